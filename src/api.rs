@@ -1,102 +1,701 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
 use std::sync::Arc;
+use std::time::Duration;
 
-use log::debug;
-use tokio::sync::{mpsc, RwLock};
+use bytes::Bytes;
+use futures::{future, stream, SinkExt, Stream, StreamExt};
+use itertools::Itertools;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio::task;
+use warp::ws::{Message, WebSocket};
 use warp::{self, Filter};
 
-use crate::store::{KvStore, Update};
+use crate::blob;
+use crate::clock::Version;
+use crate::kv::Payload;
+use crate::store::{InsertOptions, KvStore, Precondition, StoreEvent, Update};
+use crate::KvsError;
 
 type Store = Arc<RwLock<KvStore>>;
 type UpdateChannel = mpsc::UnboundedSender<Update>;
 
+/// Max size of a value in an insert request body, since a pair holds up to ~768 KB of encoded data
+/// (values that don't fit once encoded are refused with `413 Payload Too Large`)
+const MAX_BODY_SIZE: u64 = 768 * 1024;
+/// Max size of a blob in a `/blob` request body
+const MAX_BLOB_SIZE: u64 = 16 * 1024 * 1024;
+/// Content type for values inserted without one
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+/// Content type for values inserted as a path segment
+const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+/// Max length of the content type of a value
+const MAX_CONTENT_TYPE_LENGTH: usize = 255;
+
+/// Response header listing the categories of a pair
+const CATEGORIES_HEADER: &str = "x-kvs-categories";
+/// Response header with when a pair expires (milliseconds since the UNIX epoch)
+const EXPIRES_HEADER: &str = "x-kvs-expires";
+/// Response header with the version of a pair, for conditional writes
+const ETAG_HEADER: &str = "etag";
+
+/// Default number of keys returned by a `/keys` call
+const DEFAULT_KEYS_LIMIT: usize = 100;
+/// Max number of keys returned by a `/keys` call
+const MAX_KEYS_LIMIT: usize = 1000;
+
+/// Query parameters for listing keys
+#[derive(Debug, Deserialize)]
+pub struct KeysQuery {
+    /// Only list keys starting with this prefix
+    prefix: Option<String>,
+    /// Max number of keys to return
+    limit: Option<usize>,
+    /// Cursor returned by the previous call, to get the next page of keys
+    cursor: Option<String>,
+}
+
+/// Query parameters for watching key changes
+#[derive(Debug, Deserialize)]
+pub struct WatchQuery {
+    /// Only watch keys starting with this prefix
+    prefix: Option<String>,
+}
+
+/// Query parameters for inserting a key/value pair
+#[derive(Debug, Deserialize)]
+pub struct InsertQuery {
+    /// Comma-delimited categories to tag the pair with (E.g. `categories=10,20`)
+    categories: Option<String>,
+    /// Seconds until the pair expires
+    ttl: Option<u64>,
+    /// Bind the pair to this node's BGP sessions (E.g. `ephemeral=true`)
+    ephemeral: Option<bool>,
+}
+
+impl InsertQuery {
+    /// Options to insert the pair with
+    fn options(&self) -> Result<InsertOptions, warp::Rejection> {
+        Ok(InsertOptions {
+            categories: self.categories()?,
+            ttl: self.ttl.map(Duration::from_secs),
+            ephemeral: self.ephemeral.unwrap_or(false),
+            precondition: None,
+        })
+    }
+
+    /// Parse the given categories (if any), rejecting anything that isn't a list of u16s
+    fn categories(&self) -> Result<Option<Vec<u16>>, warp::Rejection> {
+        self.categories
+            .as_ref()
+            .map(|categories| {
+                categories
+                    .split(',')
+                    .filter(|category| !category.is_empty())
+                    .map(|category| category.trim().parse::<u16>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| {
+                        warp::reject::custom(KvsError::InvalidCategories(categories.clone()))
+                    })
+            })
+            .transpose()
+    }
+}
+
+/// A page of keys, with a cursor for the next page (if there are more keys)
+#[derive(Debug, Serialize)]
+struct KeysPage<'a> {
+    keys: Vec<&'a str>,
+    cursor: Option<String>,
+}
+
 /// API call to get a key (if it exists)
+///
+/// Replies with the raw value, using the content type it was inserted with.
+/// The categories of the pair are listed (comma-delimited) in the `X-Kvs-Categories` header,
+/// when it expires (if ever) in the `X-Kvs-Expires` header, and its version in the `ETag` header
 pub async fn get_key(key: String, store: Store) -> Result<impl warp::Reply, warp::Rejection> {
     debug!("GET: {}", key);
     store
         .read()
         .await
-        .get(&key)
-        .map(|value| warp::reply::with_status(format!("{}\n", value), warp::http::StatusCode::OK))
+        .get_pair(&key)
+        .map(|kv| {
+            let payload = kv.as_ref().clone();
+            let categories = kv.categories().iter().map(u16::to_string).join(",");
+            let expires = kv.expires().map(|e| e.to_string()).unwrap_or_default();
+            let content_type = reply_content_type(payload.content_type);
+            let reply = warp::reply::with_header(payload.data, "content-type", content_type);
+            let reply = warp::reply::with_header(reply, CATEGORIES_HEADER, categories);
+            let reply = warp::reply::with_header(reply, EXPIRES_HEADER, expires);
+            warp::reply::with_header(reply, ETAG_HEADER, etag(kv.version()))
+        })
         .ok_or_else(warp::reject::not_found)
 }
 
-/// API call to insert/update a key/value pair
+/// API call to list keys (optionally by prefix) in sorted order
+///
+/// Returns a JSON page of keys, along with a cursor to use for the next page
+pub async fn list_keys(
+    query: KeysQuery,
+    store: Store,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    debug!("KEYS: {:?}", query);
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_KEYS_LIMIT)
+        .clamp(1, MAX_KEYS_LIMIT);
+    let store = store.read().await;
+    let (keys, cursor) = store.scan(
+        query.prefix.as_deref().unwrap_or(""),
+        query.cursor.as_deref(),
+        limit,
+    );
+    Ok(warp::reply::json(&KeysPage { keys, cursor }))
+}
+
+/// API call to insert/update a key/value pair, with the value in the request body
+///
+/// The value is stored as raw bytes (E.g. text, JSON, binary data) along with
+/// the request's `Content-Type` (if given, and a valid MIME type), which is used when getting the key.
+/// Categories can be given as a query (E.g. `?categories=10,20`), otherwise an updated
+/// key keeps its existing categories. A TTL in seconds can also be given (E.g. `?ttl=30`),
+/// and `?ephemeral=true` removes the pair from peers once this node is no longer reachable from them.
+///
+/// Writes can be made conditional with the `If-None-Match: *` (only insert a new key) or
+/// `If-Match: <etag>` (only update the key at this version) headers, and reply with the `ETag` of the new version.
 ///
 /// This will trigger a BGP update to peers to:
 /// - Announce the new/updated key
 /// - Withdraw the existing value (if this is a value update)
+pub async fn insert_body(
+    key: String,
+    query: InsertQuery,
+    precondition: Option<Precondition>,
+    content_type: Option<String>,
+    body: Bytes,
+    store: Store,
+    channel: UpdateChannel,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let payload = Payload::new(body.to_vec(), content_type);
+    let options = InsertOptions {
+        precondition,
+        ..query.options()?
+    };
+    insert_payload(key, payload, options, store, channel).await
+}
+
+/// API call to insert/update a key/value pair, with the value as a path segment
+///
+/// The value is stored as UTF-8 text. This will trigger a BGP update to peers to:
+/// - Announce the new/updated key
+/// - Withdraw the existing value (if this is a value update)
 pub async fn insert_pair(
     key: String,
     value: String,
+    query: InsertQuery,
+    precondition: Option<Precondition>,
     store: Store,
     channel: UpdateChannel,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    debug!("INSERT: {} | {}", key, value);
-    store
-        .write()
-        .await
-        .insert(key, value)
-        .map_err(warp::reject::custom)
-        .and_then(|update| {
-            channel.send(update).unwrap();
-            Ok(warp::reply())
+    let payload = Payload::new(value.into_bytes(), Some(TEXT_CONTENT_TYPE.to_owned()));
+    let options = InsertOptions {
+        precondition,
+        ..query.options()?
+    };
+    insert_payload(key, payload, options, store, channel).await
+}
+
+async fn insert_payload(
+    key: String,
+    payload: Payload,
+    options: InsertOptions,
+    store: Store,
+    channel: UpdateChannel,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    debug!("INSERT: {} | {} {:?}", key, payload, options);
+    blob::check_key(&key).map_err(warp::reject::custom)?;
+    let mut store = store.write().await;
+    let update = task::block_in_place(|| store.insert_with(key.clone(), payload, options))
+        .map_err(warp::reject::custom)?;
+    channel.send(update).unwrap();
+    let etag = store
+        .get_pair(&key)
+        .map(|kv| etag(kv.version()))
+        .unwrap_or_default();
+    Ok(warp::reply::with_header(warp::reply(), ETAG_HEADER, etag))
+}
+
+/// API call to insert/update many key/value pairs as a batch, from a JSON object of keys to (text) values
+///
+/// Peers only apply the pairs once every pair of the batch has been received, so they never see
+/// part of a batch. The same query options & precondition headers as an insert apply to every pair,
+/// and nothing is written if any pair fails its precondition. Replies with the `ETag` shared by every pair.
+///
+/// This will trigger a BGP update to peers to:
+/// - Announce every new/updated key
+/// - Withdraw the existing values (if any)
+pub async fn insert_batch(
+    query: InsertQuery,
+    precondition: Option<Precondition>,
+    pairs: BTreeMap<String, String>,
+    store: Store,
+    channel: UpdateChannel,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    debug!("BATCH: {} pairs {:?}", pairs.len(), query);
+    let options = InsertOptions {
+        precondition,
+        ..query.options()?
+    };
+    let pairs: Vec<(String, Payload)> = pairs
+        .into_iter()
+        .map(|(key, value)| {
+            blob::check_key(&key)?;
+            let payload = Payload::new(value.into_bytes(), Some(TEXT_CONTENT_TYPE.to_owned()));
+            Ok((key, payload))
         })
+        .collect::<Result<_, KvsError>>()
+        .map_err(warp::reject::custom)?;
+    let first = pairs.first().map(|(key, _)| key.clone());
+    let mut store = store.write().await;
+    let update = task::block_in_place(|| store.insert_batch(pairs, options))
+        .map_err(warp::reject::custom)?;
+    channel.send(update).unwrap();
+    let etag = first
+        .and_then(|key| store.get_pair(&key).map(|kv| etag(kv.version())))
+        .unwrap_or_default();
+    Ok(warp::reply::with_header(warp::reply(), ETAG_HEADER, etag))
 }
 
 /// API call to remove a key/value pair by key
 ///
+/// With an `If-Match: <etag>` header, the pair is only removed if it's still at that version.
+///
 /// This will trigger a BGP update to peers to:
 /// - Withdraw the key/value pair
 pub async fn remove_pair(
     key: String,
+    precondition: Option<Precondition>,
     store: Store,
     channel: UpdateChannel,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    debug!("REMOVE: {}", key);
-    store
-        .write()
-        .await
-        .remove(&key)
-        .map_err(warp::reject::custom)
-        .and_then(|result| {
-            if let Some(update) = result {
-                channel.send(update).unwrap();
-                Ok(warp::reply::with_status("", warp::http::StatusCode::OK))
-            } else {
-                Err(warp::reject::not_found())
+    debug!("REMOVE: {} {:?}", key, precondition);
+    blob::check_key(&key).map_err(warp::reject::custom)?;
+    let mut store = store.write().await;
+    task::block_in_place(|| match precondition {
+        Some(Precondition::Version(version)) => store.remove_if(&key, version),
+        Some(Precondition::Absent) => Err(KvsError::PreconditionFailed(format!(
+            "{} can't be removed if absent",
+            key
+        ))),
+        _ => store.remove(&key),
+    })
+    .map_err(warp::reject::custom)
+    .and_then(|result| {
+        if let Some(update) = result {
+            channel.send(update).unwrap();
+            Ok(warp::reply::with_status("", warp::http::StatusCode::OK))
+        } else {
+            Err(warp::reject::not_found())
+        }
+    })
+}
+
+/// API call to get a blob (if it exists), reassembled from its chunks
+///
+/// Replies with the blob's data, using the content type it was inserted with, or
+/// `503 Service Unavailable` if the blob doesn't match its manifest (E.g. chunks are still being received from a peer)
+pub async fn get_blob(name: String, store: Store) -> Result<impl warp::Reply, warp::Rejection> {
+    debug!("GET BLOB: {}", name);
+    let payload = blob::get(&*store.read().await, &name)
+        .map_err(warp::reject::custom)?
+        .ok_or_else(warp::reject::not_found)?;
+    let content_type = reply_content_type(payload.content_type);
+    Ok(warp::reply::with_header(
+        payload.data,
+        "content-type",
+        content_type,
+    ))
+}
+
+/// API call to insert/update a blob, with the data in the request body
+///
+/// The blob is split into chunk pairs with a manifest pair, which are written as a batch.
+/// The same query options as an insert apply to the manifest & every chunk.
+///
+/// This will trigger a BGP update to peers to:
+/// - Announce the manifest & chunks
+/// - Withdraw the existing manifest & chunks (if this is a blob update)
+pub async fn insert_blob(
+    name: String,
+    query: InsertQuery,
+    content_type: Option<String>,
+    body: Bytes,
+    store: Store,
+    channel: UpdateChannel,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    debug!("INSERT BLOB: {} | {} bytes {:?}", name, body.len(), query);
+    let payload = Payload::new(body.to_vec(), content_type);
+    let mut store = store.write().await;
+    let options = query.options()?;
+    let update = task::block_in_place(|| blob::insert(&mut store, &name, payload, options))
+        .map_err(warp::reject::custom)?;
+    channel.send(update).unwrap();
+    Ok(warp::reply())
+}
+
+/// API call to remove a blob by name
+///
+/// This will trigger a BGP update to peers to:
+/// - Withdraw the manifest & chunks
+pub async fn remove_blob(
+    name: String,
+    store: Store,
+    channel: UpdateChannel,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    debug!("REMOVE BLOB: {}", name);
+    let mut store = store.write().await;
+    let updates = task::block_in_place(|| blob::remove(&mut store, &name))
+        .map_err(warp::reject::custom)?
+        .ok_or_else(warp::reject::not_found)?;
+    for update in updates {
+        channel.send(update).unwrap();
+    }
+    Ok(warp::reply::with_status("", warp::http::StatusCode::OK))
+}
+
+/// `ETag` of a pair's version (E.g. `"1600000000000@bf51"`)
+fn etag(version: Version) -> String {
+    format!("\"{}\"", version)
+}
+
+/// Parse the [Precondition](../store/enum.Precondition.html) of a conditional write from the request headers
+///
+/// - `If-None-Match: *` only writes a new key
+/// - `If-Match: *` only writes an existing key
+/// - `If-Match: <etag>` only writes the key at that version
+///
+/// Unknown ETags can never match, so fail the precondition
+fn parse_precondition(
+    if_match: Option<String>,
+    if_none_match: Option<String>,
+) -> Result<Option<Precondition>, KvsError> {
+    match (if_match, if_none_match) {
+        (None, None) => Ok(None),
+        (None, Some(tag)) if tag.trim() == "*" => Ok(Some(Precondition::Absent)),
+        (None, Some(tag)) => Err(KvsError::PreconditionFailed(format!(
+            "Only If-None-Match: * is supported, not {}",
+            tag
+        ))),
+        (Some(tag), None) if tag.trim() == "*" => Ok(Some(Precondition::Present)),
+        (Some(tag), None) => {
+            let tag = tag.trim();
+            tag.trim_start_matches("W/")
+                .trim_matches('"')
+                .parse()
+                .map(|version| Some(Precondition::Version(version)))
+                .map_err(|_| KvsError::PreconditionFailed(format!("Unknown ETag {}", tag)))
+        }
+        (Some(_), Some(_)) => Err(KvsError::PreconditionFailed(
+            "If-Match & If-None-Match can't both be given".to_owned(),
+        )),
+    }
+}
+
+/// Parse a content type as a MIME type (E.g. `text/plain; charset=utf-8`)
+///
+/// Content types are sent back in the `Content-Type` header when getting a value, so anything
+/// else (E.g. with control characters) is rejected
+fn parse_content_type(content_type: &str) -> Result<String, KvsError> {
+    if content_type.len() > MAX_CONTENT_TYPE_LENGTH || content_type.chars().any(char::is_control) {
+        return Err(KvsError::InvalidContentType(format!("{:?}", content_type)));
+    }
+    content_type
+        .parse::<mime::Mime>()
+        .map(|mime| mime.to_string())
+        .map_err(|e| KvsError::InvalidContentType(format!("{:?}: {}", content_type, e)))
+}
+
+/// Content type to reply with for a value, falling back to the default for values
+/// without one (or with an invalid one from a peer)
+fn reply_content_type(content_type: Option<String>) -> String {
+    content_type
+        .and_then(|content_type| parse_content_type(&content_type).ok())
+        .unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_owned())
+}
+
+/// Filter for the `Content-Type` of a value, rejecting anything but a valid MIME type
+fn content_type() -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("content-type").and_then(
+        |content_type: Option<String>| async move {
+            content_type
+                .map(|content_type| parse_content_type(&content_type))
+                .transpose()
+                .map_err(warp::reject::custom)
+        },
+    )
+}
+
+/// Filter for the [Precondition](../store/enum.Precondition.html) of a conditional write
+fn precondition() -> impl Filter<Extract = (Option<Precondition>,), Error = warp::Rejection> + Clone
+{
+    warp::header::optional::<String>("if-match")
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and_then(
+            |if_match: Option<String>, if_none_match: Option<String>| async move {
+                parse_precondition(if_match, if_none_match).map_err(warp::reject::custom)
+            },
+        )
+}
+
+/// Reply to failed preconditions with `412 Precondition Failed`, incomplete blobs with
+/// `503 Service Unavailable`, invalid content types with `400 Bad Request` and values too
+/// large to encode with `413 Payload Too Large`, passing on any other rejection
+async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
+    match err.find::<KvsError>() {
+        Some(KvsError::InvalidContentType(reason)) => Ok(warp::reply::with_status(
+            format!("Invalid content type: {}\n", reason),
+            warp::http::StatusCode::BAD_REQUEST,
+        )),
+        Some(KvsError::InvalidTtl(reason)) => Ok(warp::reply::with_status(
+            format!("Invalid TTL: {}\n", reason),
+            warp::http::StatusCode::BAD_REQUEST,
+        )),
+        Some(KvsError::ReservedKey(reason)) => Ok(warp::reply::with_status(
+            format!("Reserved key: {}\n", reason),
+            warp::http::StatusCode::BAD_REQUEST,
+        )),
+        Some(KvsError::EncodeError(reason)) => Ok(warp::reply::with_status(
+            format!("{}\n", reason),
+            warp::http::StatusCode::PAYLOAD_TOO_LARGE,
+        )),
+        Some(KvsError::PreconditionFailed(reason)) => Ok(warp::reply::with_status(
+            format!("{}\n", reason),
+            warp::http::StatusCode::PRECONDITION_FAILED,
+        )),
+        Some(KvsError::InvalidBlob(reason)) => Ok(warp::reply::with_status(
+            format!("{}\n", reason),
+            warp::http::StatusCode::SERVICE_UNAVAILABLE,
+        )),
+        _ => Err(err),
+    }
+}
+
+/// API call to watch for changes to keys, as Server-Sent Events
+///
+/// Each [StoreEvent](../store/enum.StoreEvent.html) (from this node, a peer or expiry) for a key
+/// starting with `prefix` (if given) is sent as an SSE event named for the kind of change, with the event as JSON data:
+/// `{"event":"updated","key":"favorite::food","old":{..},"new":{"timestamp":1600000000000,"origin":1},"source":"peer"}`
+pub async fn watch_events(
+    query: WatchQuery,
+    store: Store,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    debug!("WATCH: {:?}", query.prefix);
+    let events = store.read().await.subscribe();
+    let events = watched_events(events, query.prefix)
+        .map(|event| Ok::<_, Infallible>((warp::sse::event(event.name()), warp::sse::json(event))));
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+}
+
+/// API call to watch for changes to keys over a WebSocket
+///
+/// Sends the same events as [watch_events](fn.watch_events.html), as JSON text messages
+pub async fn watch_socket(
+    query: WatchQuery,
+    ws: warp::ws::Ws,
+    store: Store,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    debug!("WATCH (WebSocket): {:?}", query.prefix);
+    let events = store.read().await.subscribe();
+    Ok(ws.on_upgrade(move |socket| send_events(socket, events, query.prefix)))
+}
+
+/// Send watched events to a WebSocket client until it disconnects
+async fn send_events(
+    socket: WebSocket,
+    events: broadcast::Receiver<StoreEvent>,
+    prefix: Option<String>,
+) {
+    let (mut tx, mut rx) = socket.split();
+    let send = async move {
+        let mut events = Box::pin(watched_events(events, prefix));
+        while let Some(event) = events.next().await {
+            let text = serde_json::to_string(&event).expect("StoreEvent is serializable");
+            if tx.send(Message::text(text)).await.is_err() {
+                break;
             }
-        })
+        }
+    };
+    // Messages from the client are ignored, but stop sending once it closes the socket
+    let closed = async move {
+        while let Some(Ok(message)) = rx.next().await {
+            if message.is_close() {
+                break;
+            }
+        }
+    };
+    future::select(Box::pin(send), Box::pin(closed)).await;
+}
+
+/// Stream the events for keys starting with `prefix` (if given)
+fn watched_events(
+    events: broadcast::Receiver<StoreEvent>,
+    prefix: Option<String>,
+) -> impl Stream<Item = StoreEvent> {
+    let prefix = prefix.unwrap_or_default();
+    stream::unfold(events, |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => return Some((event, events)),
+                Err(broadcast::RecvError::Lagged(missed)) => {
+                    warn!("Watcher fell behind, missed {} events", missed)
+                }
+                Err(broadcast::RecvError::Closed) => return None,
+            }
+        }
+    })
+    .filter(move |event| future::ready(event.key().starts_with(&prefix)))
 }
 
 /// Defined API routes for Key/Value CRUD
+///
+/// Calls for the default store are at the root (E.g. `/get/<key>`), and calls for each named
+/// namespace are under its path prefix (E.g. `/ns/<name>/get/<key>`)
 pub fn get_routes(
     store: Store,
+    namespaces: BTreeMap<String, Store>,
     channel: UpdateChannel,
 ) -> warp::filters::BoxedFilter<(impl warp::Reply,)> {
+    let status =
+        warp::path!("status").map(|| Box::new("Alive!\n".to_owned()) as Box<dyn warp::Reply>);
+
+    let mut routes = status
+        .or(store_routes(store, channel.clone()))
+        .unify()
+        .boxed();
+    for (name, store) in namespaces {
+        let namespace = warp::path("ns")
+            .and(warp::path(name))
+            .and(store_routes(store, channel.clone()));
+        routes = routes.or(namespace).unify().boxed();
+    }
+    routes.recover(handle_rejection).boxed()
+}
+
+/// API routes for Key/Value CRUD on a single store
+fn store_routes(
+    store: Store,
+    channel: UpdateChannel,
+) -> warp::filters::BoxedFilter<(Box<dyn warp::Reply>,)> {
     let store = warp::any().map(move || store.clone());
     let channel = warp::any().map(move || channel.clone());
 
-    let status = warp::path!("status").map(|| "Alive!\n".to_owned());
-
     let get_key = warp::get()
         .and(warp::path!("get" / String))
         .and(warp::path::end())
         .and(store.clone())
         .and_then(get_key);
 
+    let list_keys = warp::get()
+        .and(warp::path!("keys"))
+        .and(warp::path::end())
+        .and(warp::query::<KeysQuery>())
+        .and(store.clone())
+        .and_then(list_keys);
+
+    let insert_body = warp::put()
+        .and(warp::path!("insert" / String))
+        .and(warp::path::end())
+        .and(warp::query::<InsertQuery>())
+        .and(precondition())
+        .and(content_type())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::bytes())
+        .and(store.clone())
+        .and(channel.clone())
+        .and_then(insert_body);
+
     let insert_key = warp::put()
         .and(warp::path!("insert" / String / String))
         .and(warp::path::end())
+        .and(warp::query::<InsertQuery>())
+        .and(precondition())
         .and(store.clone())
         .and(channel.clone())
         .and_then(insert_pair);
 
+    let batch = warp::post()
+        .and(warp::path!("batch"))
+        .and(warp::path::end())
+        .and(warp::query::<InsertQuery>())
+        .and(precondition())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+        .and(store.clone())
+        .and(channel.clone())
+        .and_then(insert_batch);
+
     let remove = warp::delete()
         .and(warp::path!("remove" / String))
         .and(warp::path::end())
+        .and(precondition())
         .and(store.clone())
         .and(channel.clone())
         .and_then(remove_pair);
 
-    status.or(get_key).or(insert_key).or(remove).boxed()
+    let get_blob = warp::get()
+        .and(warp::path!("blob" / String))
+        .and(warp::path::end())
+        .and(store.clone())
+        .and_then(get_blob);
+
+    let insert_blob = warp::put()
+        .and(warp::path!("blob" / String))
+        .and(warp::path::end())
+        .and(warp::query::<InsertQuery>())
+        .and(content_type())
+        .and(warp::body::content_length_limit(MAX_BLOB_SIZE))
+        .and(warp::body::bytes())
+        .and(store.clone())
+        .and(channel.clone())
+        .and_then(insert_blob);
+
+    let remove_blob = warp::delete()
+        .and(warp::path!("blob" / String))
+        .and(warp::path::end())
+        .and(store.clone())
+        .and(channel.clone())
+        .and_then(remove_blob);
+
+    // WebSocket upgrades are tried first, otherwise changes are sent as Server-Sent Events
+    let watch_socket = warp::get()
+        .and(warp::path!("watch"))
+        .and(warp::path::end())
+        .and(warp::query::<WatchQuery>())
+        .and(warp::ws())
+        .and(store.clone())
+        .and_then(watch_socket);
+
+    let watch_events = warp::get()
+        .and(warp::path!("watch"))
+        .and(warp::path::end())
+        .and(warp::query::<WatchQuery>())
+        .and(store.clone())
+        .and_then(watch_events);
+
+    get_key
+        .or(list_keys)
+        .or(insert_body)
+        .or(insert_key)
+        .or(batch)
+        .or(remove)
+        .or(get_blob)
+        .or(insert_blob)
+        .or(remove_blob)
+        .or(watch_socket)
+        .or(watch_events)
+        .map(|reply| Box::new(reply) as Box<dyn warp::Reply>)
+        .boxed()
 }
@@ -0,0 +1,277 @@
+//! Blobs too large for a single [KeyValue](struct.KeyValue.html) pair
+//!
+//! A blob is split into chunks of up to 512 KB, each stored as its own pair, along with a
+//! [Manifest](struct.Manifest.html) pair listing the size, number of chunks and SHA-256 hash of the blob.
+//! The manifest & chunks are written as a batch, so peers apply them together, and the blob is
+//! verified against the manifest whenever it's read.
+//!
+//! Keys used for a blob named `<name>`:
+//! - `blob::<name>`: The JSON serialized [Manifest](struct.Manifest.html)
+//! - `blob-chunk::<name>::<index>`: Each chunk of the blob's data
+//!
+//! These prefixes are reserved for blobs, and can't be written or removed as plain pairs.
+//!
+//! Chunks are far too large for IPv4 routes, so they're only announced as IPv6 routes, and peers
+//! that only carry IPv4 Unicast can't read blobs.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::kv::Payload;
+use crate::store::{InsertOptions, KvStore, Update};
+use crate::KvsError;
+
+/// Max bytes of blob data per chunk pair, leaving room for the encoding within the ~768 KB per pair
+pub const CHUNK_SIZE: usize = 512 * 1024;
+/// Key prefix of blob manifests
+const MANIFEST_PREFIX: &str = "blob::";
+/// Key prefix of blob chunks
+const CHUNK_PREFIX: &str = "blob-chunk::";
+/// Content type of blob manifests
+const MANIFEST_CONTENT_TYPE: &str = "application/json";
+
+/// Description of a stored blob, used to reassemble & verify its chunks
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Total bytes of the blob
+    pub size: u64,
+    /// Number of chunk pairs
+    pub chunks: u32,
+    /// Hex encoded SHA-256 hash of the blob
+    pub sha256: String,
+    /// MIME type of the blob (E.g. `application/x-tar`), if known
+    pub content_type: Option<String>,
+}
+
+/// Key of the manifest for a blob
+pub fn manifest_key(name: &str) -> String {
+    format!("{}{}", MANIFEST_PREFIX, name)
+}
+
+/// Key of a chunk of a blob
+pub fn chunk_key(name: &str, index: u32) -> String {
+    format!("{}{}::{}", CHUNK_PREFIX, name, index)
+}
+
+/// Check a key isn't reserved for blob manifests & chunks, before writing or removing it as a plain pair
+///
+/// Fails with [ReservedKey](../enum.KvsError.html) for keys starting with `blob::` or `blob-chunk::`
+pub fn check_key(key: &str) -> Result<(), KvsError> {
+    if key.starts_with(MANIFEST_PREFIX) || key.starts_with(CHUNK_PREFIX) {
+        return Err(KvsError::ReservedKey(format!(
+            "{} is reserved for blobs (use /blob instead)",
+            key
+        )));
+    }
+    Ok(())
+}
+
+/// Insert (or replace) a blob, with the same [InsertOptions](struct.InsertOptions.html) for the manifest & every chunk
+///
+/// Returns the [Update](struct.Update.html) to send to BGP peers: the batch of the manifest & chunks,
+/// along with withdraws for any chunks left over from a larger, replaced blob
+pub fn insert(
+    store: &mut KvStore,
+    name: &str,
+    payload: Payload,
+    options: InsertOptions,
+) -> Result<Update, KvsError> {
+    let previous = manifest(store, name)?;
+    let manifest = Manifest {
+        size: payload.data.len() as u64,
+        chunks: payload.data.chunks(CHUNK_SIZE).count() as u32,
+        sha256: format!("{:x}", Sha256::digest(&payload.data)),
+        content_type: payload.content_type,
+    };
+    let manifest_json = serde_json::to_vec(&manifest)
+        .map_err(|e| KvsError::EncodeError(format!("Could not encode manifest: {}", e)))?;
+    let mut pairs = vec![(
+        manifest_key(name),
+        Payload::new(manifest_json, Some(MANIFEST_CONTENT_TYPE.to_owned())),
+    )];
+    for (index, chunk) in payload.data.chunks(CHUNK_SIZE).enumerate() {
+        pairs.push((chunk_key(name, index as u32), chunk.to_vec().into()));
+    }
+    // Chunks left over from a larger blob are trimmed along with the batch
+    let stale = previous.map_or_else(Vec::new, |previous| {
+        (manifest.chunks..previous.chunks)
+            .map(|index| chunk_key(name, index))
+            .collect()
+    });
+    store.write_batch(pairs, &stale, options)
+}
+
+/// Get a blob, reassembled from its chunks
+///
+/// Fails with [InvalidBlob](../enum.KvsError.html) if chunks are missing or don't match the manifest
+/// (E.g. the blob is still being synchronized from a peer)
+pub fn get(store: &KvStore, name: &str) -> Result<Option<Payload>, KvsError> {
+    let manifest = match manifest(store, name)? {
+        Some(manifest) => manifest,
+        None => return Ok(None),
+    };
+    let mut data = Vec::with_capacity(manifest.size as usize);
+    for index in 0..manifest.chunks {
+        let chunk = store
+            .get(&chunk_key(name, index))
+            .ok_or_else(|| KvsError::InvalidBlob(format!("{} is missing chunk {}", name, index)))?;
+        data.extend(chunk.data);
+    }
+    if data.len() as u64 != manifest.size {
+        return Err(KvsError::InvalidBlob(format!(
+            "{} is {} bytes, expected {}",
+            name,
+            data.len(),
+            manifest.size
+        )));
+    }
+    if format!("{:x}", Sha256::digest(&data)) != manifest.sha256 {
+        return Err(KvsError::InvalidBlob(format!(
+            "{} doesn't match its SHA-256 hash",
+            name
+        )));
+    }
+    Ok(Some(Payload::new(data, manifest.content_type)))
+}
+
+/// Remove a blob's manifest & chunks
+///
+/// Returns the [Update](struct.Update.html)s to send to BGP peers, or `None` if the blob doesn't exist
+pub fn remove(store: &mut KvStore, name: &str) -> Result<Option<Vec<Update>>, KvsError> {
+    let manifest = match manifest(store, name)? {
+        Some(manifest) => manifest,
+        None => return Ok(None),
+    };
+    // The manifest is removed first, so a partially removed blob isn't readable
+    let mut updates: Vec<Update> = store.remove(&manifest_key(name))?.into_iter().collect();
+    for index in 0..manifest.chunks {
+        if let Some(update) = store.remove(&chunk_key(name, index))? {
+            updates.push(update);
+        }
+    }
+    Ok(Some(updates))
+}
+
+/// The stored manifest of a blob (if it exists)
+pub fn manifest(store: &KvStore, name: &str) -> Result<Option<Manifest>, KvsError> {
+    store
+        .get(&manifest_key(name))
+        .map(|payload| {
+            serde_json::from_slice(&payload.data).map_err(|e| {
+                KvsError::InvalidBlob(format!("Could not decode manifest of {}: {}", name, e))
+            })
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::{AddrPrefix, Route, RouteCollection};
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn blob_round_trip() {
+        let mut store = KvStore::new();
+        let payload = Payload::new(
+            data(CHUNK_SIZE * 2 + 1),
+            Some("application/x-tar".to_owned()),
+        );
+        let update = insert(
+            &mut store,
+            "bundle",
+            payload.clone(),
+            InsertOptions::default(),
+        )
+        .unwrap();
+        assert!(update.withdraw.is_none());
+        assert_eq!(store.len(), 4);
+
+        let manifest = manifest(&store, "bundle").unwrap().unwrap();
+        assert_eq!(manifest.chunks, 3);
+        assert_eq!(manifest.size, payload.data.len() as u64);
+        // Every pair of the blob is written in the same batch
+        let version = store.get_pair(&manifest_key("bundle")).unwrap().version();
+        assert_eq!(
+            store.get_pair(&chunk_key("bundle", 2)).unwrap().version(),
+            version
+        );
+        assert_eq!(get(&store, "bundle").unwrap(), Some(payload));
+        assert_eq!(get(&store, "missing").unwrap(), None);
+
+        // A smaller blob removes the chunks it no longer needs, in the same update
+        let previous: Vec<Route> = (0..3)
+            .map(|index| store.get_pair(&chunk_key("bundle", index)).unwrap())
+            .chain(store.get_pair(&manifest_key("bundle")))
+            .flat_map(|kv| {
+                let routes = RouteCollection::encode(kv, AddrPrefix::default(), None, None);
+                routes.unwrap().iter().cloned().collect::<Vec<_>>()
+            })
+            .collect();
+        let payload = Payload::new(data(10), None);
+        let update = insert(
+            &mut store,
+            "bundle",
+            payload.clone(),
+            InsertOptions::default(),
+        )
+        .unwrap();
+        // Every previous route is withdrawn, except those replaced by a route announced with the same prefix
+        let announce = update.announce.unwrap();
+        let replaced = previous
+            .iter()
+            .filter(|route| {
+                announce
+                    .iter()
+                    .any(|new| new.prefix.as_ref() == route.prefix.as_ref())
+            })
+            .count();
+        assert_eq!(
+            update.withdraw.unwrap().iter().count() + replaced,
+            previous.len()
+        );
+        assert_eq!(store.len(), 2);
+        assert_eq!(get(&store, "bundle").unwrap(), Some(payload));
+
+        assert_eq!(remove(&mut store, "bundle").unwrap().unwrap().len(), 2);
+        assert!(store.is_empty());
+        assert!(remove(&mut store, "bundle").unwrap().is_none());
+    }
+
+    #[test]
+    fn blob_verify() {
+        let mut store = KvStore::new();
+        let payload = Payload::new(data(CHUNK_SIZE + 1), None);
+        insert(&mut store, "bundle", payload, InsertOptions::default()).unwrap();
+
+        store
+            .insert(chunk_key("bundle", 1), vec![1].into())
+            .unwrap();
+        assert!(matches!(
+            get(&store, "bundle"),
+            Err(KvsError::InvalidBlob(_))
+        ));
+        store.remove(&chunk_key("bundle", 1)).unwrap();
+        assert!(matches!(
+            get(&store, "bundle"),
+            Err(KvsError::InvalidBlob(_))
+        ));
+    }
+
+    #[test]
+    fn blob_reserved_keys() {
+        assert!(check_key("bundle").is_ok());
+        assert!(check_key("blobs::bundle").is_ok());
+        assert!(matches!(
+            check_key(&manifest_key("bundle")),
+            Err(KvsError::ReservedKey(_))
+        ));
+        assert!(matches!(
+            check_key(&chunk_key("bundle", 0)),
+            Err(KvsError::ReservedKey(_))
+        ));
+    }
+}
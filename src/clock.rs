@@ -0,0 +1,215 @@
+//! Hybrid logical clock versions for [KeyValue](struct.KeyValue.html) pairs
+//!
+//! Every write is stamped with a [Version](struct.Version.html): a hybrid logical clock (HLC)
+//! timestamp and the id of the node that made the write. The HLC timestamp is milliseconds since
+//! the UNIX epoch, but always ticks forward past the newest timestamp written or received, so
+//! versions follow causality even when node clocks disagree.
+//!
+//! Versions are totally ordered, so every node converges on the same winner for a key:
+//! 1. The higher timestamp wins
+//! 2. For equal timestamps (concurrent writes), the higher origin id wins
+//!
+//! Versions from peers more than 5 minutes ahead of the local wall clock are refused, so a node
+//! with a bad clock (or a forged version) can't push every other node's clock into the future.
+//!
+//! The low 32 bits of the timestamp are also sent as the version tag in each
+//! [NextHop](struct.NextHop.html) (along with the low 16 bits of the origin id), and compared with
+//! serial number arithmetic ([RFC 1982](https://tools.ietf.org/html/rfc1982)) so the comparison is
+//! safe across wraparound, for versions less than ~24 days apart.
+
+use std::cmp::{max, Ordering};
+use std::fmt;
+use std::hash::Hasher;
+use std::process;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use siphasher::sip::SipHasher13;
+
+/// Max milliseconds a version observed from elsewhere may be ahead of the wall clock
+pub const MAX_DRIFT: u64 = 5 * 60 * 1000;
+
+/// Version of a [KeyValue](struct.KeyValue.html) write
+///
+/// Ordered by timestamp, then origin id (the tie-break for concurrent writes)
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Version {
+    /// Hybrid logical clock timestamp (milliseconds since the UNIX epoch)
+    pub timestamp: u64,
+    /// Id of the node that made this write
+    pub origin: u32,
+}
+
+impl Version {
+    /// Create a `Version` from a timestamp & origin id
+    pub fn new(timestamp: u64, origin: u32) -> Self {
+        Self { timestamp, origin }
+    }
+
+    /// 32-bit version tag encoded in each [NextHop](struct.NextHop.html) (low 32 bits of the timestamp)
+    pub fn tag(&self) -> u32 {
+        self.timestamp as u32
+    }
+
+    /// 16-bit origin tag encoded in each [NextHop](struct.NextHop.html) (low 16 bits of the origin id),
+    /// so concurrent writes from different nodes aren't mistaken for the same version
+    pub fn origin_tag(&self) -> u16 {
+        self.origin as u16
+    }
+
+    /// The next version from the same origin (one tick later)
+    pub fn next(&self) -> Self {
+        Self::new(self.timestamp.saturating_add(1), self.origin)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{:x}", self.timestamp, self.origin)
+    }
+}
+
+/// Parse a `Version` from its display format (`<timestamp>@<origin in hex>`)
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid version: {}", s);
+        let (timestamp, origin) = s.split_at(s.find('@').ok_or_else(invalid)?);
+        let timestamp = timestamp.parse().map_err(|_| invalid())?;
+        let origin = u32::from_str_radix(&origin[1..], 16).map_err(|_| invalid())?;
+        Ok(Self::new(timestamp, origin))
+    }
+}
+
+/// Compare two 32-bit version tags using serial number arithmetic
+///
+/// A tag is newer if it is less than 2^31 ahead (wrapping) of the other tag
+pub fn compare_tags(a: u32, b: u32) -> Ordering {
+    match a.wrapping_sub(b) {
+        0 => Ordering::Equal,
+        diff if diff < 0x8000_0000 => Ordering::Greater,
+        _ => Ordering::Less,
+    }
+}
+
+/// Hybrid logical clock for stamping local writes with a [Version](struct.Version.html)
+#[derive(Debug)]
+pub struct HybridClock {
+    origin: u32,
+    /// Newest timestamp written or received
+    last: u64,
+}
+
+impl HybridClock {
+    /// Create a new clock for writes from the given origin id
+    pub fn new(origin: u32) -> Self {
+        Self { origin, last: 0 }
+    }
+
+    /// Create a new clock with a random origin id
+    pub fn with_random_origin() -> Self {
+        let mut hasher = SipHasher13::new();
+        hasher.write_u64(wall_clock());
+        hasher.write_u32(process::id());
+        if let Ok(elapsed) = SystemTime::now().duration_since(UNIX_EPOCH) {
+            hasher.write_u32(elapsed.subsec_nanos());
+        }
+        Self::new(hasher.finish() as u32)
+    }
+
+    /// Id of the node stamping writes with this clock
+    pub fn origin(&self) -> u32 {
+        self.origin
+    }
+
+    /// Change the id of the node stamping writes with this clock
+    pub fn set_origin(&mut self, origin: u32) {
+        self.origin = origin;
+    }
+
+    /// Version for a new local write, newer than any version seen so far
+    pub fn now(&mut self) -> Version {
+        self.last = max(wall_clock(), self.last.saturating_add(1));
+        Version::new(self.last, self.origin)
+    }
+
+    /// Advance the clock past a version written elsewhere (E.g. received from a peer)
+    ///
+    /// Returns `false` (leaving the clock as is) if the version is more than [MAX_DRIFT](constant.MAX_DRIFT.html)
+    /// ahead of the wall clock
+    pub fn observe(&mut self, version: Version) -> bool {
+        if version.timestamp > wall_clock().saturating_add(MAX_DRIFT) {
+            return false;
+        }
+        self.last = max(self.last, version.timestamp);
+        true
+    }
+}
+
+/// Milliseconds since the UNIX epoch
+pub fn wall_clock() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_ordering() {
+        let version = Version::new(1000, 1);
+        assert!(version.next() > version);
+        // Concurrent writes are resolved by origin id
+        assert!(Version::new(1000, 2) > version);
+        assert!(Version::new(999, 2) < version);
+    }
+
+    #[test]
+    fn version_from_str() {
+        let version = Version::new(1_600_000_000_000, 0xbf51);
+        assert_eq!(version.to_string().parse(), Ok(version));
+        assert!("1600000000000".parse::<Version>().is_err());
+        assert!("1600000000000@xyz".parse::<Version>().is_err());
+    }
+
+    #[test]
+    fn clock_ticks_forward() {
+        let mut clock = HybridClock::new(7);
+        let first = clock.now();
+        assert!(first.timestamp > 0);
+        assert!(clock.now() > first);
+
+        // A version from a node with a clock far ahead is followed
+        let remote = Version::new(first.timestamp + 60_000, 1);
+        assert!(clock.observe(remote));
+        let next = clock.now();
+        assert!(next > remote);
+        assert_eq!(next.origin, 7);
+
+        // But not past the max drift
+        let future = Version::new(wall_clock() + MAX_DRIFT + 60_000, 1);
+        assert!(!clock.observe(future));
+        assert!(clock.now() < future);
+        assert_eq!(Version::new(u64::MAX, 1).next().timestamp, u64::MAX);
+    }
+
+    #[test]
+    fn tags_wraparound() {
+        assert_eq!(compare_tags(5, 5), Ordering::Equal);
+        assert_eq!(compare_tags(6, 5), Ordering::Greater);
+        assert_eq!(compare_tags(5, 6), Ordering::Less);
+        assert_eq!(compare_tags(2, u32::MAX), Ordering::Greater);
+        assert_eq!(compare_tags(u32::MAX, 2), Ordering::Less);
+        // Versions minutes apart are still ordered
+        let version = Version::new(1_600_000_000_000, 1);
+        let later = Version::new(version.timestamp + 10 * 60 * 1000, 1);
+        assert_eq!(compare_tags(later.tag(), version.tag()), Ordering::Greater);
+    }
+}
@@ -0,0 +1,269 @@
+//! Encryption & signing of [KeyValue](struct.KeyValue.html) pairs on the wire
+//!
+//! ## Encryption
+//!
+//! Nodes sharing a cluster secret encrypt the serialized value of every pair they announce with
+//! ChaCha20-Poly1305, so routers (and BGP speakers without the secret) in the path can't read values.
+//! The header & key of the pair are authenticated along with the value, so an encrypted value
+//! can't be replayed under another key or version.
+//!
+//! Each version of a pair is encrypted with a random 96-bit nonce, carried ahead of the ciphertext,
+//! so a nonce is never reused for different values (E.g. after a node's clock goes backwards).
+//! The nonce is kept with the pair, so re-encoding it (E.g. to withdraw it) produces the same routes.
+//!
+//! ## Signing
+//! Each node can have an Ed25519 [NodeKey](struct.NodeKey.html) to sign the pairs it writes, so peers
+//! with a trust list of [PublicKey](struct.PublicKey.html)s can refuse pairs injected by any other BGP speaker.
+//! Ed25519 signatures are deterministic, so re-encoding a signed pair also produces the same routes.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{Keypair, SecretKey, Signature, Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::KvsError;
+
+/// Bytes of the Poly1305 authentication tag appended to encrypted values
+pub const TAG_SIZE: usize = 16;
+/// Bytes of the random ChaCha20 nonce prepended to encrypted values
+pub const NONCE_SIZE: usize = 12;
+/// Bytes of an Ed25519 public key
+pub const PUBLIC_KEY_SIZE: usize = 32;
+/// Bytes of an Ed25519 signature
+pub const SIGNATURE_SIZE: usize = 64;
+
+/// Symmetric key shared by every node of a cluster, for encrypting values
+#[derive(Clone)]
+pub struct ClusterKey {
+    cipher: ChaCha20Poly1305,
+}
+
+impl ClusterKey {
+    /// Derive the key from a cluster secret (the SHA-256 hash of the secret)
+    pub fn from_secret(secret: &[u8]) -> Self {
+        let key: Key = Sha256::digest(secret);
+        Self {
+            cipher: ChaCha20Poly1305::new(&key),
+        }
+    }
+
+    /// Encrypt a value with the given nonce (see [random_nonce](fn.random_nonce.html)), authenticating it
+    /// along with the associated data (the header & key of the pair)
+    ///
+    /// Returns the nonce, followed by the ciphertext & the authentication tag
+    pub fn encrypt(&self, associated: &[u8], nonce: &[u8; NONCE_SIZE], value: &[u8]) -> Vec<u8> {
+        let encrypted = self
+            .cipher
+            .encrypt(
+                &Nonce::from(*nonce),
+                Payload {
+                    msg: value,
+                    aad: associated,
+                },
+            )
+            .expect("Can encrypt");
+        [&nonce[..], &encrypted[..]].concat()
+    }
+
+    /// Decrypt a value, failing with [DecryptionError](../enum.KvsError.html) if it wasn't encrypted
+    /// with this key or the value or associated data were modified
+    pub fn decrypt(&self, associated: &[u8], encrypted: &[u8]) -> Result<Vec<u8>, KvsError> {
+        if encrypted.len() < NONCE_SIZE + TAG_SIZE {
+            return Err(KvsError::DecryptionError(format!(
+                "Encrypted value is only {} bytes",
+                encrypted.len()
+            )));
+        }
+        let (nonce, encrypted) = encrypted.split_at(NONCE_SIZE);
+        let nonce = <[u8; NONCE_SIZE]>::try_from(nonce).expect("Split at the nonce size");
+        self.cipher
+            .decrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: encrypted,
+                    aad: associated,
+                },
+            )
+            .map_err(|_| {
+                KvsError::DecryptionError(
+                    "Value doesn't authenticate with the cluster key".to_owned(),
+                )
+            })
+    }
+}
+
+impl fmt::Debug for ClusterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never log the key
+        write!(f, "ClusterKey(..)")
+    }
+}
+
+/// Ed25519 identity of a node, for signing the pairs it writes
+pub struct NodeKey {
+    keypair: Keypair,
+}
+
+impl NodeKey {
+    /// Load the key from its hex encoded 32-byte secret (E.g. the contents of a key file)
+    pub fn from_hex(secret: &str) -> Result<Self, KvsError> {
+        let secret = hex::decode(secret.trim())
+            .map_err(|e| KvsError::SignatureError(format!("Invalid node key: {}", e)))?;
+        let secret = SecretKey::from_bytes(&secret)
+            .map_err(|e| KvsError::SignatureError(format!("Invalid node key: {}", e)))?;
+        let public = (&secret).into();
+        Ok(Self {
+            keypair: Keypair { secret, public },
+        })
+    }
+
+    /// The public key peers verify this node's signatures with
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.keypair.public.to_bytes())
+    }
+
+    /// Sign a message (the encoded pair)
+    pub fn sign(&self, message: &[u8]) -> [u8; SIGNATURE_SIZE] {
+        self.keypair.sign(message).to_bytes()
+    }
+}
+
+impl fmt::Debug for NodeKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never log the secret
+        write!(f, "NodeKey({})", self.public_key())
+    }
+}
+
+/// Ed25519 public key of a node, displayed (and parsed) as hex
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PublicKey([u8; PUBLIC_KEY_SIZE]);
+
+impl PublicKey {
+    /// Read a public key from (at least) 32 bytes
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, KvsError> {
+        let mut key = [0u8; PUBLIC_KEY_SIZE];
+        if bytes.len() < PUBLIC_KEY_SIZE {
+            return Err(KvsError::SignatureError(format!(
+                "Public key is {} bytes, expected {}",
+                bytes.len(),
+                PUBLIC_KEY_SIZE
+            )));
+        }
+        key.copy_from_slice(&bytes[..PUBLIC_KEY_SIZE]);
+        Ok(Self(key))
+    }
+
+    /// Bytes of the public key
+    pub fn as_bytes(&self) -> &[u8; PUBLIC_KEY_SIZE] {
+        &self.0
+    }
+
+    /// Verify the signature of a message was made by the [NodeKey](struct.NodeKey.html) of this public key
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), KvsError> {
+        let public = ed25519_dalek::PublicKey::from_bytes(&self.0)
+            .map_err(|e| KvsError::SignatureError(format!("Invalid public key {}: {}", self, e)))?;
+        let signature = Signature::try_from(signature)
+            .map_err(|e| KvsError::SignatureError(format!("Invalid signature: {}", e)))?;
+        public.verify(message, &signature).map_err(|_| {
+            KvsError::SignatureError(format!("Signature doesn't match public key {}", self))
+        })
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s.trim())
+            .map_err(|e| KvsError::SignatureError(format!("Invalid public key {}: {}", s, e)))?;
+        if bytes.len() != PUBLIC_KEY_SIZE {
+            return Err(KvsError::SignatureError(format!(
+                "Invalid public key {}: expected {} bytes",
+                s, PUBLIC_KEY_SIZE
+            )));
+        }
+        Self::from_slice(&bytes)
+    }
+}
+
+/// A random nonce, for encrypting a new version of a pair
+pub fn random_nonce() -> [u8; NONCE_SIZE] {
+    rand::random()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let key = ClusterKey::from_secret(b"Cluster secret");
+        let nonce = random_nonce();
+        let encrypted = key.encrypt(b"Header & key", &nonce, b"Some Value");
+        assert_eq!(encrypted.len(), NONCE_SIZE + "Some Value".len() + TAG_SIZE);
+        assert_eq!(encrypted[..NONCE_SIZE], nonce);
+        // Deterministic for the same nonce, but every version gets a new nonce
+        assert_eq!(
+            key.encrypt(b"Header & key", &nonce, b"Some Value"),
+            encrypted
+        );
+        assert_ne!(random_nonce(), nonce);
+        assert_ne!(
+            key.encrypt(b"Header & key", &random_nonce(), b"Some Value"),
+            encrypted
+        );
+        assert_eq!(
+            key.decrypt(b"Header & key", &encrypted).unwrap(),
+            b"Some Value"
+        );
+
+        assert!(matches!(
+            key.decrypt(b"Other key", &encrypted),
+            Err(KvsError::DecryptionError(_))
+        ));
+        assert!(matches!(
+            key.decrypt(b"Header & key", &encrypted[..NONCE_SIZE]),
+            Err(KvsError::DecryptionError(_))
+        ));
+        let other = ClusterKey::from_secret(b"Other secret");
+        assert!(matches!(
+            other.decrypt(b"Header & key", &encrypted),
+            Err(KvsError::DecryptionError(_))
+        ));
+    }
+
+    #[test]
+    fn sign_verify() {
+        let node_key = NodeKey::from_hex(&"42".repeat(32)).unwrap();
+        let public_key = node_key.public_key();
+        assert_eq!(
+            public_key.to_string().parse::<PublicKey>().unwrap(),
+            public_key
+        );
+
+        let signature = node_key.sign(b"Encoded pair");
+        assert_eq!(node_key.sign(b"Encoded pair")[..], signature[..]);
+        public_key.verify(b"Encoded pair", &signature).unwrap();
+        assert!(matches!(
+            public_key.verify(b"Forged pair", &signature),
+            Err(KvsError::SignatureError(_))
+        ));
+        let other = NodeKey::from_hex(&"07".repeat(32)).unwrap().public_key();
+        assert!(other.verify(b"Encoded pair", &signature).is_err());
+
+        assert!(NodeKey::from_hex("not hex").is_err());
+        assert!("abcd".parse::<PublicKey>().is_err());
+    }
+}
@@ -1,18 +1,63 @@
-use std::collections::hash_map::DefaultHasher;
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::convert::{AsRef, From, TryFrom};
 use std::fmt::{self, Debug, Display};
 use std::hash::{Hash, Hasher};
-use std::net::{IpAddr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
 
 use bgp_rs::{Identifier, NLRIEncoding, PathAttribute, Update};
-use bytes::{BufMut, BytesMut};
-use itertools::{chain, enumerate, Itertools};
-use serde::{de::DeserializeOwned, Serialize};
-
+use bytes::{Buf, BufMut, BytesMut};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use siphasher::sip::SipHasher13;
+
+use crate::clock::Version;
+use crate::crypto::{
+    self, ClusterKey, NodeKey, PublicKey, NONCE_SIZE, PUBLIC_KEY_SIZE, SIGNATURE_SIZE, TAG_SIZE,
+};
 use crate::KvsError;
 
-const ADDR_PREFIX: [u8; 2] = [0xbf, 0x51]; // BF51 IPv6 Prefix
+/// Default [AddrPrefix](struct.AddrPrefix.html) of `KeyValue` routes
+const DEFAULT_ADDR_PREFIX: u16 = 0xbf51; // BF51 IPv6 Prefix
 const CHUNK_SIZE: usize = 96 / 8;
+/// Bytes of data in each IPv4 [NextHop](struct.NextHop.html)
+const IPV4_CHUNK_SIZE: usize = 24 / 8;
+/// Max number of IPv4 [Route](struct.Route.html)s per `KeyValue`, as the route count is the first octet of IPv4
+/// [NextHop](struct.NextHop.html)s (kept within `1` - `126`, so next hops are unicast addresses outside of loopback)
+const MAX_IPV4_ROUTES: usize = 126;
+/// Bytes of the encoded [Version](struct.Version.html) (timestamp & origin), expiry, flags and batch size,
+/// ahead of the key & value
+const HEADER_SIZE: usize = 8 + 4 + 8 + 1 + 2;
+/// Header flag for pairs bound to the BGP sessions of their origin
+const FLAG_EPHEMERAL: u8 = 0b0000_0001;
+/// Key length marking extended (32-bit) key & value lengths, for pairs that don't fit 16-bit lengths
+const EXTENDED_LENGTH: u16 = u16::MAX;
+/// Bytes of the 16-bit key & value lengths in the first [Prefix](struct.Prefix.html)
+const LENGTHS_SIZE: usize = 2 + 2;
+/// Bytes of the extended lengths: the marker & encoding flags, followed by the 32-bit key & value lengths
+const EXTENDED_LENGTHS_SIZE: usize = 2 + 2 + 4 + 4;
+/// Encoding flag (in the extended lengths) for values that are LZ4 compressed
+const ENCODING_COMPRESSED: u16 = 0b0000_0001;
+/// Encoding flag (in the extended lengths) for values encrypted with the [ClusterKey](crypto/struct.ClusterKey.html)
+const ENCODING_ENCRYPTED: u16 = 0b0000_0010;
+/// Encoding flag (in the extended lengths) for pairs signed by a [NodeKey](crypto/struct.NodeKey.html)
+const ENCODING_SIGNED: u16 = 0b0000_0100;
+/// Bytes of the CRC32 checksum following the value
+const CHECKSUM_SIZE: usize = 4;
+/// Max number of [Route](struct.Route.html)s per `KeyValue`, as sequence numbers & route counts are 16-bit
+const MAX_ROUTES: usize = u16::MAX as usize;
+/// Max decompressed size of a value, so forged routes can't claim a value larger than any pair that can be encoded
+const MAX_DECOMPRESSED_SIZE: usize = MAX_ROUTES * CHUNK_SIZE;
+/// Sequence # of the liveness [Route](struct.Route.html) of each node (past the last sequence # of any `KeyValue`)
+const LIVENESS_SEQUENCE: u16 = u16::MAX;
+/// Sequence # of the IPv4 liveness [Route](struct.Route.html) of each node (past the last IPv4 sequence # of any `KeyValue`)
+const IPV4_LIVENESS_SEQUENCE: u8 = u8::MAX;
+/// First octet of IPv4 liveness [NextHop](struct.NextHop.html)s, ahead of the high 16 bits of the origin id
+const IPV4_LIVENESS_NEXT_HOP: u8 = 1;
+/// SipHash keys for the `Key` hash, fixed as part of the wire format
+const KEY_HASH_KEYS: (u64, u64) = (0x4b56_5342_4750_0001, 0x4b56_5342_4750_0002);
+/// Bits of the SipHash kept as the `Key` hash (the low 48 bits), as carried by each [NextHop](struct.NextHop.html)
+const KEY_HASH_MASK: u64 = 0xffff_ffff_ffff;
 
 /// `Key` ID for the Key/Value Store
 ///
@@ -43,10 +88,15 @@ where
         bincode::serialize(&self.inner).expect("Can encode")
     }
 
-    fn get_hash(&self) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        self.inner.hash(&mut hasher);
-        hasher.finish()
+    /// Stable hash of the encoded key, sent on the wire in each [NextHop](struct.NextHop.html)
+    ///
+    /// SipHash-1-3 (with fixed keys) of the bincode serialized key, truncated to 48 bits, so all nodes
+    /// agree on the hash regardless of platform or Rust version
+    pub(crate) fn get_hash(&self) -> u64 {
+        let (k0, k1) = KEY_HASH_KEYS;
+        let mut hasher = SipHasher13::new_with_keys(k0, k1);
+        hasher.write(&self.as_bytes());
+        hasher.finish() & KEY_HASH_MASK
     }
 }
 
@@ -108,10 +158,56 @@ where
     }
 }
 
+/// Binary-safe data for a [Value](struct.Value.html), along with its content type
+///
+/// Used as the value type for the [KvStore](struct.KvStore.html), so the content type a value
+/// was inserted with is synchronized to peers along with the data
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Payload {
+    /// MIME type of the data (E.g. `application/json`), if known
+    pub content_type: Option<String>,
+    /// Raw bytes of the value
+    pub data: Vec<u8>,
+}
+
+impl Payload {
+    /// Create a new `Payload` from data with the given content type
+    pub fn new(data: Vec<u8>, content_type: Option<String>) -> Self {
+        Self { content_type, data }
+    }
+}
+
+impl From<Vec<u8>> for Payload {
+    fn from(data: Vec<u8>) -> Self {
+        Self::new(data, None)
+    }
+}
+
+impl From<String> for Payload {
+    fn from(data: String) -> Self {
+        Self::new(data.into_bytes(), None)
+    }
+}
+
+impl From<&str> for Payload {
+    fn from(data: &str) -> Self {
+        Self::new(data.as_bytes().to_vec(), None)
+    }
+}
+
+impl Display for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match std::str::from_utf8(&self.data) {
+            Ok(data) => write!(f, "{}", data),
+            Err(_) => write!(f, "<{} bytes>", self.data.len()),
+        }
+    }
+}
+
 /// A [KeyValue](struct.KeyValue.html) pair, stored internally as a value in the [KvStore](struct.KvStore.html) HashMap
 ///
-/// Keeps track of the key hash for checksum & comparison, along with a version
-/// that increments each time the value is updated
+/// Keeps track of the key hash for checksum & comparison, along with a [Version](struct.Version.html)
+/// that advances each time the value is updated
 ///    (for evicting aged out versions locally and syncing remote peers)
 #[derive(Debug)]
 pub struct KeyValue<K, V>
@@ -122,7 +218,25 @@ where
     key: Key<K>,
     value: Value<V>,
     hash: u64,
-    version: u16,
+    version: Version,
+    /// Categories for Pub/Sub filtering, encoded as BGP communities (sorted & unique)
+    categories: Vec<u16>,
+    /// When this pair expires (milliseconds since the UNIX epoch), if ever
+    expires: Option<u64>,
+    /// Is this pair removed by peers once all their sessions to its origin are down?
+    ephemeral: bool,
+    /// Number of pairs written in the same batch (sharing this pair's version), `0` if not batched
+    batch_size: u16,
+    /// Key of the node that signed this pair, if it was decoded with a valid signature
+    signer: Option<PublicKey>,
+    /// Random nonce the value is encrypted with (if a cluster key is set), new for every version
+    /// and kept so re-encoding the pair produces the same routes
+    nonce: [u8; NONCE_SIZE],
+    /// Serialized & LZ4 compressed lengths of the value, cached as compressing is costly for large values
+    value_lengths: (usize, usize),
+    /// Slot of the IPv4 routes of this version (following the origin tag in their prefixes), if it's
+    /// encoded as IPv4 routes
+    ipv4_slot: Option<u8>,
 }
 
 impl<K, V> KeyValue<K, V>
@@ -132,7 +246,7 @@ where
 {
     /// Create a new [KeyValue](struct.KeyValue.html) pair by values for K, V
     pub fn new(key: K, value: V) -> Self {
-        Self::with_version(key, value, 0)
+        Self::with_version(key, value, Version::default())
     }
 
     /// Get a ref to the `KeyValue` `Key`
@@ -140,41 +254,466 @@ where
         &self.key.inner
     }
 
-    fn with_version(key: K, value: V, version: u16) -> Self {
+    pub(crate) fn with_version(key: K, value: V, version: Version) -> Self {
         let _key = Key::new(key);
         let hash = _key.get_hash();
+        let value = Value::new(value);
         Self {
             key: _key,
-            value: Value::new(value),
+            value_lengths: Self::value_lengths(&value),
+            value,
             hash,
             version,
+            categories: vec![],
+            expires: None,
+            ephemeral: false,
+            batch_size: 0,
+            signer: None,
+            nonce: crypto::random_nonce(),
+            ipv4_slot: None,
         }
     }
 
-    /// Replace the current `Value` and increment the [KeyValue](struct.KeyValue.html) version
+    /// The categories this `KeyValue` is tagged with
+    pub fn categories(&self) -> &[u16] {
+        &self.categories
+    }
+
+    /// Replace the categories this `KeyValue` is tagged with
+    pub fn set_categories(&mut self, mut categories: Vec<u16>) {
+        categories.sort_unstable();
+        categories.dedup();
+        self.categories = categories;
+    }
+
+    /// When this `KeyValue` expires (milliseconds since the UNIX epoch), if ever
+    pub fn expires(&self) -> Option<u64> {
+        self.expires
+    }
+
+    /// Set when this `KeyValue` expires (milliseconds since the UNIX epoch)
+    pub fn set_expires(&mut self, expires: Option<u64>) {
+        self.expires = expires;
+    }
+
+    /// Has this `KeyValue` expired by the given time (milliseconds since the UNIX epoch)?
+    pub fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires, Some(expires) if expires <= now)
+    }
+
+    /// Is this `KeyValue` bound to the BGP sessions of the node that wrote it?
+    ///
+    /// Peers remove ephemeral pairs once all of their sessions to the origin of the pair are down
+    pub fn is_ephemeral(&self) -> bool {
+        self.ephemeral
+    }
+
+    /// Set whether this `KeyValue` is bound to the BGP sessions of the node that wrote it
+    pub fn set_ephemeral(&mut self, ephemeral: bool) {
+        self.ephemeral = ephemeral;
+    }
+
+    /// Number of pairs written in the same batch as this `KeyValue`, `0` if it wasn't written in a batch
+    ///
+    /// Every pair of a batch shares the same [Version](struct.Version.html), so peers can
+    /// hold back the pairs of a batch until all of them have been received
+    pub fn batch_size(&self) -> u16 {
+        self.batch_size
+    }
+
+    /// Set the number of pairs written in the same batch as this `KeyValue`
+    pub fn set_batch_size(&mut self, batch_size: u16) {
+        self.batch_size = batch_size;
+    }
+
+    /// The [PublicKey](crypto/struct.PublicKey.html) of the node that signed this `KeyValue`,
+    /// if it was received from a peer with a valid signature
+    pub fn signer(&self) -> Option<&PublicKey> {
+        self.signer.as_ref()
+    }
+
+    /// Set the [PublicKey](crypto/struct.PublicKey.html) of the node that signed this `KeyValue`
+    /// (E.g. when restoring a stored pair)
+    pub(crate) fn set_signer(&mut self, signer: Option<PublicKey>) {
+        self.signer = signer;
+    }
+
+    /// Slot of this `KeyValue`'s IPv4 [Route](struct.Route.html)s, following the origin tag in their prefixes,
+    /// if it's encoded as IPv4 routes (see [RouteCollection::encode](struct.RouteCollection.html#method.encode))
+    pub fn ipv4_slot(&self) -> Option<u8> {
+        self.ipv4_slot
+    }
+
+    /// Set the slot of this `KeyValue`'s IPv4 routes (E.g. allocated by the [KvStore](struct.KvStore.html)
+    /// for a local write, or when restoring a stored pair)
+    pub(crate) fn set_ipv4_slot(&mut self, slot: Option<u8>) {
+        self.ipv4_slot = slot;
+    }
+
+    /// The origin tag (low 8 bits of the origin id) & slot carried by the IPv4 prefixes of this `KeyValue`,
+    /// as returned by [Route::hash](struct.Route.html#method.hash), if it's encoded as IPv4 routes
+    pub fn ipv4_id(&self) -> Option<u16> {
+        self.ipv4_slot
+            .map(|slot| u16::from_be_bytes([self.version.origin as u8, slot]))
+    }
+
+    /// Nonce the value of this version is encrypted with, if a cluster key is set
+    pub(crate) fn nonce(&self) -> &[u8; NONCE_SIZE] {
+        &self.nonce
+    }
+
+    /// Replace the nonce the value is encrypted with (E.g. when restoring a stored pair)
+    pub(crate) fn set_nonce(&mut self, nonce: [u8; NONCE_SIZE]) {
+        self.nonce = nonce;
+    }
+
+    /// Replace the current `Value` and advance the [KeyValue](struct.KeyValue.html) version
+    /// to the next tick of the current version
     pub fn update(&mut self, value: V) {
+        let version = self.version.next();
+        self.update_with_version(value, version);
+    }
+
+    /// Replace the current `Value` with a write stamped with the given version
+    pub(crate) fn update_with_version(&mut self, value: V, version: Version) {
         self.value = Value::new(value);
-        self.version += 1;
+        self.value_lengths = Self::value_lengths(&self.value);
+        self.nonce = crypto::random_nonce();
+        self.ipv4_slot = None;
+        self.version = version;
     }
 
     fn as_bytes(&self) -> Vec<u8> {
-        [self.key.as_bytes(), self.value.as_bytes()].concat()
+        [
+            &self.header()[..],
+            &self.key.as_bytes(),
+            &self.value.as_bytes(),
+        ]
+        .concat()
+    }
+
+    /// The version, expiry, flags & batch size encoded ahead of the key & value
+    fn header(&self) -> Vec<u8> {
+        [
+            &self.version.timestamp.to_be_bytes()[..],
+            &self.version.origin.to_be_bytes()[..],
+            // No expiry is encoded as 0
+            &self.expires.unwrap_or(0).to_be_bytes()[..],
+            &[self.flags()][..],
+            &self.batch_size.to_be_bytes()[..],
+        ]
+        .concat()
+    }
+
+    /// Encode the key & value lengths followed by the header, key, value, signature & checksum,
+    /// to be split into [Prefix](struct.Prefix.html)es
+    ///
+    /// The value is LZ4 compressed (flagged in the extended lengths) only if that needs fewer routes,
+    /// and then encrypted if a [ClusterKey](crypto/struct.ClusterKey.html) is given. The pair is signed
+    /// if a [NodeKey](crypto/struct.NodeKey.html) is given.
+    fn encode(&self, cluster_key: Option<&ClusterKey>, node_key: Option<&NodeKey>) -> Vec<u8> {
+        let header = self.header();
+        let key = self.key.as_bytes();
+        let value = self.value.as_bytes();
+        let (compress, len) = self.encoded_len(key.len(), overhead(cluster_key, node_key));
+        let (value, encoding) = if compress {
+            (lz4_flex::compress_prepend_size(&value), ENCODING_COMPRESSED)
+        } else {
+            (value, 0)
+        };
+        // Encrypted after compressing, as ciphertext doesn't compress
+        let (value, encoding) = match cluster_key {
+            Some(cluster_key) => (
+                cluster_key.encrypt(&[&header[..], &key[..]].concat(), &self.nonce, &value),
+                encoding | ENCODING_ENCRYPTED,
+            ),
+            None => (value, encoding),
+        };
+        let encoding = match node_key {
+            Some(_) => encoding | ENCODING_SIGNED,
+            None => encoding,
+        };
+
+        let mut bytes = BytesMut::with_capacity(len);
+        if encoding == 0 && lengths_size(key.len(), value.len()) == LENGTHS_SIZE {
+            bytes.put_u16(key.len() as u16);
+            bytes.put_u16(value.len() as u16);
+        } else {
+            // Fits in 32 bits for any pair within the limit on routes
+            bytes.put_u16(EXTENDED_LENGTH);
+            bytes.put_u16(encoding);
+            bytes.put_u32(key.len() as u32);
+            bytes.put_u32(value.len() as u32);
+        }
+        bytes.put(&header[..]);
+        bytes.put(&key[..]);
+        bytes.put(&value[..]);
+        if let Some(node_key) = node_key {
+            // Signs every byte before it, including the lengths & encoding flags, and the categories
+            let signature = node_key.sign(&signed_message(&bytes, &self.categories));
+            bytes.put(&node_key.public_key().as_bytes()[..]);
+            bytes.put(&signature[..]);
+        }
+        let checksum = crc32fast::hash(&bytes);
+        bytes.put_u32(checksum);
+        bytes.to_vec()
+    }
+
+    /// Flags encoded ahead of the key & value
+    fn flags(&self) -> u8 {
+        if self.ephemeral {
+            FLAG_EPHEMERAL
+        } else {
+            0
+        }
     }
 
     /// Calculate the number of [Route](struct.Route.html)s needed to encode
-    /// this `KeyValue` pair
-    pub fn number_of_routes(&self) -> usize {
-        // Sum the length fields and the length of key & value,
-        // divided by 96 bits per `Prefix`
-        ((self.key.len() + self.value.len() + 4) as f32 / CHUNK_SIZE as f32).ceil() as usize
+    /// this `KeyValue` pair, encrypted if a [ClusterKey](crypto/struct.ClusterKey.html) is given and
+    /// signed if a [NodeKey](crypto/struct.NodeKey.html) is given
+    ///
+    /// Pairs needing more than 65,535 routes can't be encoded
+    pub fn number_of_routes(
+        &self,
+        cluster_key: Option<&ClusterKey>,
+        node_key: Option<&NodeKey>,
+    ) -> usize {
+        // The length fields, header (version, expiry, flags & batch size), key, (possibly compressed) value,
+        // encryption & signature overhead & checksum, divided by 96 bits per `Prefix`
+        let overhead = overhead(cluster_key, node_key);
+        let (_, len) = self.encoded_len(self.key.as_bytes().len(), overhead);
+        (len + CHUNK_SIZE - 1) / CHUNK_SIZE
     }
 
-    fn key_hash(&self) -> u64 {
+    /// Does this `KeyValue` pair fit in IPv4 [Route](struct.Route.html)s (126 routes of 24 bits), encrypted
+    /// & signed like [number_of_routes](#method.number_of_routes)?
+    pub fn fits_ipv4_routes(
+        &self,
+        cluster_key: Option<&ClusterKey>,
+        node_key: Option<&NodeKey>,
+    ) -> bool {
+        let overhead = overhead(cluster_key, node_key);
+        let (_, len) = self.encoded_len(self.key.as_bytes().len(), overhead);
+        (len + IPV4_CHUNK_SIZE - 1) / IPV4_CHUNK_SIZE <= MAX_IPV4_ROUTES
+    }
+
+    /// Serialized & LZ4 compressed lengths of a value
+    fn value_lengths(value: &Value<V>) -> (usize, usize) {
+        let value = value.as_bytes();
+        (value.len(), lz4_flex::compress_prepend_size(&value).len())
+    }
+
+    /// Whether the value should be LZ4 compressed (only if that needs fewer routes), and the length of the
+    /// encoded pair with the given `overhead` (bytes of nonce, authentication tag and/or signature)
+    ///
+    /// Encrypted & signed pairs (with any overhead) always use extended lengths, for the encoding flags
+    fn encoded_len(&self, key_len: usize, overhead: usize) -> (bool, usize) {
+        let (value_len, compressed_len) = self.value_lengths;
+        let len = |lengths_size: usize, val_len: usize| {
+            lengths_size + HEADER_SIZE + key_len + val_len + overhead + CHECKSUM_SIZE
+        };
+        let plain_lengths_size = if overhead > 0 {
+            EXTENDED_LENGTHS_SIZE
+        } else {
+            lengths_size(key_len, value_len)
+        };
+        let plain = len(plain_lengths_size, value_len);
+        let compressed = len(EXTENDED_LENGTHS_SIZE, compressed_len);
+        if (compressed + CHUNK_SIZE - 1) / CHUNK_SIZE < (plain + CHUNK_SIZE - 1) / CHUNK_SIZE {
+            (true, compressed)
+        } else {
+            (false, plain)
+        }
+    }
+
+    /// Decode a `KeyValue` from its (complete) [RouteCollection](struct.RouteCollection.html) under the given
+    /// [AddrPrefix](struct.AddrPrefix.html), decrypting the value with the [ClusterKey](crypto/struct.ClusterKey.html)
+    /// if it's encrypted
+    ///
+    /// Fails with [DecryptionError](../enum.KvsError.html) if the value is encrypted & there's no
+    /// cluster key, or it was encrypted with another key. Signed pairs are verified (failing with
+    /// [SignatureError](../enum.KvsError.html)), but it's up to the caller whether to trust the [signer](#method.signer)
+    pub fn decode(
+        routes: &RouteCollection,
+        addr_prefix: AddrPrefix,
+        cluster_key: Option<&ClusterKey>,
+    ) -> Result<Self, KvsError> {
+        let first = routes
+            .0
+            .first()
+            .ok_or_else(|| KvsError::DecodeError("At least one route should exist".to_owned()))?;
+
+        let mut bytes: Vec<u8> = Vec::with_capacity(routes.0.len() * CHUNK_SIZE);
+
+        let mut tag: Option<(u32, u16)> = None;
+        let mut hash: Option<u64> = None;
+        // IPv4 routes carry the origin tag & slot of the pair instead of the version & origin tags
+        let (tag_mask, origin_mask) = if first.is_ipv4() {
+            (0, 0)
+        } else {
+            (u32::MAX, u16::MAX)
+        };
+
+        for (i, route) in routes.0.iter().enumerate() {
+            if !route.has_valid_prefix(addr_prefix) {
+                return Err(KvsError::DecodeError(format!(
+                    "Not a KVS-BGP Prefix (expected {})",
+                    addr_prefix
+                )));
+            }
+            if route.is_ipv4() != first.is_ipv4() {
+                return Err(KvsError::DecodeError("Mixed IPv4 & IPv6 routes".to_owned()));
+            }
+            if route.sequence() != i as u16 {
+                return Err(KvsError::DecodeError(format!(
+                    "Missing route sequence # {}",
+                    i
+                )));
+            }
+            if i == 0 {
+                tag.replace((route.version(), route.origin()));
+                hash.replace(route.hash());
+            }
+            bytes.extend_from_slice(&route.data());
+        }
+
+        // IPv4 routes carry too little data to assume the first has room for the extended lengths
+        if bytes.len() < EXTENDED_LENGTHS_SIZE {
+            return Err(KvsError::DecodeError(format!(
+                "Only {} bytes in {} routes",
+                bytes.len(),
+                routes.0.len()
+            )));
+        }
+        let mut data = &bytes[..];
+        let (key_length, val_length, encoding) = match data.get_u16() {
+            EXTENDED_LENGTH => {
+                let encoding = data.get_u16();
+                (data.get_u32() as usize, data.get_u32() as usize, encoding)
+            }
+            key_length => (key_length as usize, data.get_u16() as usize, 0),
+        };
+        if encoding & !(ENCODING_COMPRESSED | ENCODING_ENCRYPTED | ENCODING_SIGNED) != 0 {
+            return Err(KvsError::DecodeError(format!(
+                "Unknown encoding flags {:#06x}",
+                encoding
+            )));
+        }
+        let signature_size = if encoding & ENCODING_SIGNED != 0 {
+            PUBLIC_KEY_SIZE + SIGNATURE_SIZE
+        } else {
+            0
+        };
+        let length = HEADER_SIZE + key_length + val_length + signature_size + CHECKSUM_SIZE;
+        if data.len() < length {
+            return Err(KvsError::DecodeError(format!(
+                "Expected {} bytes, only {} routes",
+                length,
+                routes.0.len()
+            )));
+        }
+        // The checksum (and signature) cover every byte before them, including the lengths
+        let lengths_size = bytes.len() - data.len();
+        let checksummed = lengths_size + length - CHECKSUM_SIZE;
+        let (covered, mut checksum) = bytes[..checksummed + CHECKSUM_SIZE].split_at(checksummed);
+        let checksum = checksum.get_u32();
+        if crc32fast::hash(covered) != checksum {
+            return Err(KvsError::ChecksumMismatch(format!(
+                "Expected {:08x} for {} routes with hash {:x}",
+                checksum,
+                routes.0.len(),
+                first.hash()
+            )));
+        }
+        let (mut header, data) = data.split_at(HEADER_SIZE);
+        let (key, data) = data.split_at(key_length);
+        let (value, data) = data.split_at(val_length);
+        let signer = if signature_size > 0 {
+            let signed = lengths_size + HEADER_SIZE + key_length + val_length;
+            let (public_key, signature) = data.split_at(PUBLIC_KEY_SIZE);
+            let public_key = PublicKey::from_slice(public_key)?;
+            let mut categories = first.categories.clone();
+            categories.sort_unstable();
+            categories.dedup();
+            public_key.verify(
+                &signed_message(&bytes[..signed], &categories),
+                &signature[..SIGNATURE_SIZE],
+            )?;
+            Some(public_key)
+        } else {
+            None
+        };
+        let mut nonce = None;
+        let value: Cow<[u8]> = if encoding & ENCODING_ENCRYPTED != 0 {
+            let cluster_key = cluster_key.ok_or_else(|| {
+                KvsError::DecryptionError(
+                    "Value is encrypted, but no cluster key is set".to_owned(),
+                )
+            })?;
+            let decrypted = cluster_key.decrypt(&[header, key].concat(), value)?;
+            // Kept, so the decoded pair is re-encoded as the same routes
+            nonce = <[u8; NONCE_SIZE]>::try_from(&value[..NONCE_SIZE]).ok();
+            decrypted.into()
+        } else {
+            value.into()
+        };
+        let value: Cow<[u8]> = if encoding & ENCODING_COMPRESSED != 0 {
+            decompress(&value)?.into()
+        } else {
+            value
+        };
+        let version = Version::new(header.get_u64(), header.get_u32());
+        let expires = Some(header.get_u64()).filter(|expires| *expires != 0);
+        let flags = header.get_u8();
+        let batch_size = header.get_u16();
+        if tag != Some((version.tag() & tag_mask, version.origin_tag() & origin_mask)) {
+            return Err(KvsError::DecodeError(format!(
+                "Version tag mismatch for {}: {:?}",
+                version, tag
+            )));
+        }
+        let key = bincode::deserialize(&key)
+            .map_err(|_e| KvsError::DecodeError("Couldn't decode key".to_owned()))?;
+        let value = bincode::deserialize(&value)
+            .map_err(|_e| KvsError::DecodeError("Couldn't decode value".to_owned()))?;
+        let mut kv = Self::with_version(key, value, version);
+        // Routes of another key (or a corrupted key) won't match the hash it was sent with
+        let hash = hash.ok_or_else(|| KvsError::DecodeError("Missing key hash".to_owned()))?;
+        // IPv4 routes carry the slot of the pair along with its origin tag instead
+        let (expected, ipv4_slot) = if first.is_ipv4() {
+            let slot = hash as u8;
+            (
+                u16::from_be_bytes([version.origin as u8, slot]) as u64,
+                Some(slot),
+            )
+        } else {
+            (kv.key_hash(), None)
+        };
+        if expected != hash {
+            return Err(KvsError::DecodeError(format!(
+                "Key hash mismatch for {}: {:x} != {:x}",
+                kv.key, expected, hash
+            )));
+        }
+        kv.set_categories(first.categories.clone());
+        kv.set_expires(expires);
+        kv.set_ephemeral(flags & FLAG_EPHEMERAL != 0);
+        kv.set_batch_size(batch_size);
+        kv.set_ipv4_slot(ipv4_slot);
+        kv.signer = signer;
+        if let Some(nonce) = nonce {
+            kv.nonce = nonce;
+        }
+        Ok(kv)
+    }
+
+    /// The stable hash of this `KeyValue`'s [Key](struct.Key.html), as encoded in its [NextHop](struct.NextHop.html)s
+    pub fn key_hash(&self) -> u64 {
         self.hash
     }
 
-    /// The version of this `KeyValue` (incremented for every update)
-    pub fn version(&self) -> u16 {
+    /// The version of this `KeyValue` (advanced for every update)
+    pub fn version(&self) -> Version {
         self.version
     }
 
@@ -205,14 +744,155 @@ where
     }
 }
 
-/// An IPv6 Unicast Prefix to encode a portion of a [KeyValue](struct.KeyValue.html) pair
+/// The leading 16 bits of every [Prefix](struct.Prefix.html) & [NextHop](struct.NextHop.html)
+/// of a cluster's IPv6 `KeyValue` routes (`BF51` by default), and the first octet of its
+/// IPv4 routes (only encoded if set)
+///
+/// Only 16-bit prefixes are supported, as the prefix is also the global admin of the standard communities
+/// carrying categories (`<prefix>:<category>`).
+///
+/// Clusters sharing a fabric each need their own prefix, so their routes don't collide. Prefixes in
+/// global unicast (`2000::/3`), link-local (`FE80::/10`) & multicast (`FF00::/8`) space, or starting
+/// with `00` (loopback, IPv4-mapped, etc.) are refused, so `KeyValue` routes don't clobber real routes.
+/// For the same reason, IPv4 prefixes must be in reserved class E space (`240` - `254`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AddrPrefix {
+    ipv6: u16,
+    ipv4: Option<u8>,
+}
+
+impl AddrPrefix {
+    /// Create an `AddrPrefix`, failing with [InvalidPrefix](../enum.KvsError.html) if it's reserved for real routes
+    pub fn new(prefix: u16) -> Result<Self, KvsError> {
+        let reserved = match prefix {
+            0x0000..=0x00ff => Some("::/8"),
+            0x2000..=0x3fff => Some("global unicast space (2000::/3)"),
+            0xfe80..=0xfebf => Some("link-local space (FE80::/10)"),
+            0xff00..=0xffff => Some("multicast space (FF00::/8)"),
+            _ => None,
+        };
+        match reserved {
+            Some(reserved) => Err(KvsError::InvalidPrefix(format!(
+                "{:X} is in {}",
+                prefix, reserved
+            ))),
+            None => Ok(Self {
+                ipv6: prefix,
+                ipv4: None,
+            }),
+        }
+    }
+
+    /// Also encode pairs as IPv4 routes, starting with the given octet
+    ///
+    /// Fails with [InvalidPrefix](../enum.KvsError.html) if the octet isn't in class E space
+    pub fn with_ipv4(self, octet: u8) -> Result<Self, KvsError> {
+        if !is_ipv4_prefix(octet) {
+            return Err(KvsError::InvalidPrefix(format!(
+                "{} isn't in class E space (240 - 254)",
+                octet
+            )));
+        }
+        Ok(Self {
+            ipv4: Some(octet),
+            ..self
+        })
+    }
+
+    /// First octet of the IPv4 routes, if pairs are encoded as IPv4 routes
+    pub fn ipv4(&self) -> Option<u8> {
+        self.ipv4
+    }
+
+    /// Does this address (IPv6, or IPv4-mapped) start with this prefix?
+    #[inline]
+    pub fn matches(&self, addr: &Ipv6Addr) -> bool {
+        match ipv4_mapped(addr) {
+            Some(v4) => Some(v4.octets()[0]) == self.ipv4,
+            None => addr.segments()[0] == self.ipv6,
+        }
+    }
+
+    /// Would routes of this prefix collide with routes of the other prefix (in either family)?
+    pub fn overlaps(&self, other: &AddrPrefix) -> bool {
+        self.ipv6 == other.ipv6 || (self.ipv4.is_some() && self.ipv4 == other.ipv4)
+    }
+}
+
+impl Default for AddrPrefix {
+    fn default() -> Self {
+        Self {
+            ipv6: DEFAULT_ADDR_PREFIX,
+            ipv4: None,
+        }
+    }
+}
+
+impl Display for AddrPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ipv4 {
+            Some(ipv4) => write!(f, "{:X},{}", self.ipv6, ipv4),
+            None => write!(f, "{:X}", self.ipv6),
+        }
+    }
+}
+
+impl FromStr for AddrPrefix {
+    type Err = KvsError;
+
+    /// Parse a prefix from hex (E.g. `BF51`, optionally written as `BF51::/16`), followed by
+    /// the first octet of IPv4 routes if they're enabled (E.g. `BF51,240`)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ipv6, ipv4) = match s.split_once(',') {
+            Some((ipv6, ipv4)) => (ipv6, Some(ipv4)),
+            None => (s, None),
+        };
+        let hex = ipv6.trim().trim_end_matches("::/16").trim_end_matches("::");
+        let prefix = u16::from_str_radix(hex, 16)
+            .map_err(|e| KvsError::InvalidPrefix(format!("{}: {}", s, e)))?;
+        let prefix = Self::new(prefix)?;
+        match ipv4 {
+            Some(ipv4) => {
+                let octet = ipv4
+                    .trim()
+                    .trim_end_matches(".0.0.0/8")
+                    .parse::<u8>()
+                    .map_err(|e| KvsError::InvalidPrefix(format!("{}: {}", s, e)))?;
+                prefix.with_ipv4(octet)
+            }
+            None => Ok(prefix),
+        }
+    }
+}
+
+/// An IPv6 (or IPv4-mapped) Unicast Prefix to encode a portion of a [KeyValue](struct.KeyValue.html) pair
 #[derive(Clone, Debug)]
 pub struct Prefix(Ipv6Addr);
 
 impl Prefix {
     /// [Route](struct.Route.html) sequence for this [KeyValue](struct.KeyValue.html)
     fn sequence(&self) -> u16 {
-        self.0.segments()[1]
+        match ipv4_mapped(&self.0) {
+            Some(v4) => v4.octets()[3] as u16,
+            None => self.0.segments()[1],
+        }
+    }
+
+    /// Origin id of the node announcing this prefix, if it's a [liveness](struct.Route.html#method.liveness) route
+    pub fn liveness_origin(&self) -> Option<u32> {
+        let segments = self.0.segments();
+        if ipv4_mapped(&self.0).is_some() || segments[1] != LIVENESS_SEQUENCE {
+            return None;
+        }
+        Some((segments[2] as u32) << 16 | segments[3] as u32)
+    }
+
+    /// Does this start with the [AddrPrefix](struct.AddrPrefix.html) of any cluster?
+    fn is_kvs_prefix(&self) -> bool {
+        match ipv4_mapped(&self.0) {
+            Some(v4) => is_ipv4_prefix(v4.octets()[0]),
+            None => AddrPrefix::new(self.0.segments()[0]).is_ok(),
+        }
     }
 
     // fn data(&self) -> &[u8] {
@@ -235,34 +915,48 @@ impl From<&BytesMut> for Prefix {
 
 impl From<&Prefix> for IpAddr {
     fn from(prefix: &Prefix) -> Self {
-        IpAddr::V6(prefix.0)
+        to_ip(prefix.0)
     }
 }
 
-/// An IPv6 Unicast Next Hop to encode details about a [KeyValue](struct.KeyValue.html) pair
+/// An IPv6 (or IPv4-mapped) Unicast Next Hop to encode details about a [KeyValue](struct.KeyValue.html) pair
 #[derive(Clone, Debug)]
 pub struct NextHop(Ipv6Addr);
 
 impl NextHop {
-    /// The version of this `KeyValue` (incremented for every update)
-    pub fn version(&self) -> u16 {
-        self.0.segments()[1]
+    /// The version tag of this `KeyValue` (low 32 bits of the [Version](struct.Version.html) timestamp)
+    ///
+    /// IPv4 next hops don't carry the version tag (always `0`), as every version of a pair has its
+    /// own slot in the IPv4 [Prefix](struct.Prefix.html)
+    pub fn version(&self) -> u32 {
+        match ipv4_mapped(&self.0) {
+            Some(_) => 0,
+            None => (self.0.segments()[1] as u32) << 16 | self.0.segments()[2] as u32,
+        }
     }
 
-    /// [Route](struct.Route.html) sequence for this [KeyValue](struct.KeyValue.html)
-    pub fn sequence(&self) -> u16 {
-        self.0.segments()[2]
+    /// The origin tag of this `KeyValue` (low 16 bits of the [Version](struct.Version.html) origin id)
+    ///
+    /// IPv4 next hops don't carry the origin tag either (always `0`)
+    pub fn origin(&self) -> u16 {
+        match ipv4_mapped(&self.0) {
+            Some(_) => 0,
+            None => self.0.segments()[3],
+        }
     }
 
     /// The encoded number of routes for the encoded `KeyValue`
     fn collection_length(&self) -> u16 {
-        self.0.segments()[3]
+        match ipv4_mapped(&self.0) {
+            Some(v4) => v4.octets()[0] as u16,
+            None => self.0.segments()[4],
+        }
     }
 
     /// The `Key` hash for this [KeyValue](struct.KeyValue.html)
     fn hash(&self) -> u64 {
         let mut data = [0u8; 8];
-        data.copy_from_slice(&self.0.octets()[8..]);
+        data[2..].copy_from_slice(&self.0.octets()[10..]);
         u64::from_be_bytes(data)
     }
 }
@@ -282,7 +976,7 @@ impl From<&BytesMut> for NextHop {
 
 impl From<&NextHop> for IpAddr {
     fn from(next_hop: &NextHop) -> Self {
-        IpAddr::V6(next_hop.0)
+        to_ip(next_hop.0)
     }
 }
 
@@ -291,10 +985,12 @@ impl From<&NextHop> for IpAddr {
 /// Collected in sequential order as a `RouteCollection` for encoding & decoding
 #[derive(Clone, Debug)]
 pub struct Route {
-    /// BGP Update IPv6 Prefix to advertise (assumed /128 mask)
+    /// BGP Update IPv6 Prefix to advertise (assumed /128 mask), or IPv4-mapped Prefix (assumed /32 mask)
     pub prefix: Prefix,
-    /// BGP Update IPv6 NextHop to advertise
+    /// BGP Update IPv6 (or IPv4-mapped) NextHop to advertise
     pub next_hop: NextHop,
+    /// Categories of the [KeyValue](struct.KeyValue.html), advertised as BGP communities
+    pub categories: Vec<u16>,
 }
 
 impl Route {
@@ -303,86 +999,235 @@ impl Route {
         Self {
             prefix: Prefix(prefix),
             next_hop: NextHop(next_hop),
+            categories: vec![],
+        }
+    }
+
+    /// Route announced by a node for as long as it runs, so peers can tell whether the node
+    /// (E.g. the origin of ephemeral pairs) is still reachable through any of their sessions
+    ///
+    /// ```ignore
+    /// addr: |BF51: FFFF : origin id : 0 | /128
+    /// ```
+    pub fn liveness(addr_prefix: AddrPrefix, origin: u32) -> Self {
+        let addr = Ipv6Addr::new(
+            addr_prefix.ipv6,
+            LIVENESS_SEQUENCE,
+            (origin >> 16) as u16,
+            origin as u16,
+            0,
+            0,
+            0,
+            0,
+        );
+        Self::from_addrs(addr, addr)
+    }
+
+    /// IPv4 liveness route of a node, if the [AddrPrefix](struct.AddrPrefix.html) has an IPv4 octet,
+    /// for peers that only carry IPv4 Unicast (see [liveness](#method.liveness))
+    ///
+    /// ```ignore
+    /// prefix:   | 240 : origin id (low 16 bits)  : 255 | /32
+    /// next hop: |  1  : origin id (high 16 bits) :  0  |
+    /// ```
+    pub fn ipv4_liveness(addr_prefix: AddrPrefix, origin: u32) -> Option<Self> {
+        let octet = addr_prefix.ipv4()?;
+        let [a, b, c, d] = origin.to_be_bytes();
+        let prefix = Ipv4Addr::new(octet, c, d, IPV4_LIVENESS_SEQUENCE);
+        let next_hop = Ipv4Addr::new(IPV4_LIVENESS_NEXT_HOP, a, b, 0);
+        Some(Self::from_addrs(
+            prefix.to_ipv6_mapped(),
+            next_hop.to_ipv6_mapped(),
+        ))
+    }
+
+    /// Origin id of the node announcing this route, if it's a [liveness](#method.liveness) route
+    /// (of either family)
+    pub fn liveness_origin(&self) -> Option<u32> {
+        match (ipv4_mapped(&self.prefix.0), ipv4_mapped(&self.next_hop.0)) {
+            (Some(prefix), Some(next_hop)) => {
+                let (prefix, next_hop) = (prefix.octets(), next_hop.octets());
+                if prefix[3] != IPV4_LIVENESS_SEQUENCE || next_hop[0] != IPV4_LIVENESS_NEXT_HOP {
+                    return None;
+                }
+                Some(u32::from_be_bytes([
+                    next_hop[1],
+                    next_hop[2],
+                    prefix[1],
+                    prefix[2],
+                ]))
+            }
+            (Some(_), None) => None,
+            (None, _) => self.prefix.liveness_origin(),
         }
     }
 
-    /// Determine if this has a BF51 prefix
-    fn has_valid_prefix(&self) -> bool {
-        ADDR_PREFIX[..] == self.prefix.0.octets()[..2]
-            && ADDR_PREFIX[..] == self.next_hop.0.octets()[..2]
+    /// BGP communities to advertise this route's categories with, under the given [AddrPrefix](struct.AddrPrefix.html)
+    /// (`<addr prefix>:<category>`, E.g. `BF51:7`)
+    pub fn communities(&self, addr_prefix: AddrPrefix) -> Vec<u32> {
+        self.categories
+            .iter()
+            .map(|category| (addr_prefix.ipv6 as u32) << 16 | *category as u32)
+            .collect()
+    }
+
+    /// Set this route's categories from the BGP communities it was announced with, under the given
+    /// [AddrPrefix](struct.AddrPrefix.html) (ignoring communities of other prefixes)
+    pub fn set_communities(&mut self, communities: &[u32], addr_prefix: AddrPrefix) {
+        self.categories = communities
+            .iter()
+            .filter(|community| (*community >> 16) as u16 == addr_prefix.ipv6)
+            .map(|community| *community as u16)
+            .collect();
+    }
+
+    /// Determine if both the prefix & next hop start with the given [AddrPrefix](struct.AddrPrefix.html)
+    ///
+    /// IPv4 next hops carry data instead, so only the prefix of IPv4 routes is checked
+    pub fn has_valid_prefix(&self, prefix: AddrPrefix) -> bool {
+        prefix.matches(&self.prefix.0) && (self.is_ipv4() || prefix.matches(&self.next_hop.0))
     }
 
+    /// Is this an IPv4 route (with IPv4-mapped prefix & next hop)?
+    pub fn is_ipv4(&self) -> bool {
+        ipv4_mapped(&self.prefix.0).is_some()
+    }
+
+    /// The `Key` hash of the [KeyValue](struct.KeyValue.html) this route encodes
+    ///
+    /// IPv4 routes carry the origin tag & slot of the pair instead (see [KeyValue::ipv4_id](struct.KeyValue.html#method.ipv4_id))
     pub fn hash(&self) -> u64 {
-        self.next_hop.hash()
+        match ipv4_mapped(&self.prefix.0) {
+            Some(v4) => u16::from_be_bytes([v4.octets()[1], v4.octets()[2]]) as u64,
+            None => self.next_hop.hash(),
+        }
     }
 
     pub fn collection_length(&self) -> usize {
         self.next_hop.collection_length() as usize
     }
 
-    fn sequence(&self) -> u16 {
+    /// Version tag of the [KeyValue](struct.KeyValue.html) this route encodes
+    pub fn version(&self) -> u32 {
+        self.next_hop.version()
+    }
+
+    /// Origin tag of the [KeyValue](struct.KeyValue.html) this route encodes
+    pub fn origin(&self) -> u16 {
+        self.next_hop.origin()
+    }
+
+    /// Sequence of this route in the [RouteCollection](struct.RouteCollection.html)
+    pub fn sequence(&self) -> u16 {
         self.prefix.sequence()
     }
+
+    /// Bytes of the encoded `KeyValue` carried by this route
+    fn data(&self) -> Vec<u8> {
+        match ipv4_mapped(&self.next_hop.0) {
+            Some(v4) if self.is_ipv4() => v4.octets()[4 - IPV4_CHUNK_SIZE..].to_vec(),
+            _ => self.prefix.0.octets()[16 - CHUNK_SIZE..].to_vec(),
+        }
+    }
+
+    /// Is this shaped like a `KeyValue` route of any cluster?
+    fn is_kvs_route(&self) -> bool {
+        self.prefix.is_kvs_prefix()
+            && match ipv4_mapped(&self.prefix.0) {
+                Some(_) => ipv4_mapped(&self.next_hop.0).is_some(),
+                None => self.next_hop.0.segments()[0] == self.prefix.0.segments()[0],
+            }
+    }
+}
+
+/// A [Route](struct.Route.html) learned from a BGP Peer, either announced or withdrawn
+#[derive(Clone, Debug)]
+pub enum PeerRoute {
+    /// Route for a new/updated [KeyValue](struct.KeyValue.html) pair, along with the BGP communities it
+    /// was announced with (see [set_communities](struct.Route.html#method.set_communities) for its categories,
+    /// once the [AddrPrefix](struct.AddrPrefix.html) of the route is known)
+    Announced(Route, Vec<u32>),
+    /// Prefix of a removed (or replaced) [KeyValue](struct.KeyValue.html) pair
+    ///
+    /// Withdrawals don't necessarily carry a [NextHop](struct.NextHop.html), so it's only
+    /// present if the peer included one with the withdrawn prefix
+    Withdrawn(Prefix, Option<NextHop>),
 }
 
-// This needs some major cleanup, it's a pain to do all the matching for BGP Update PathAttributes
-impl TryFrom<&Update> for Route {
+impl TryFrom<&Update> for PeerRoute {
     type Error = KvsError;
 
+    /// Accepts routes of either encoding: IPv6 routes, and IPv4 routes (with or
+    /// without multiprotocol extensions)
     fn try_from(update: &Update) -> Result<Self, Self::Error> {
         if let Some(PathAttribute::MP_REACH_NLRI(mp_reach)) = update.get(Identifier::MP_REACH_NLRI)
         {
-            if let Some(nlri) = mp_reach.announced_routes.first() {
-                if let NLRIEncoding::IP(prefix) = nlri {
-                    let addr: IpAddr = prefix.into();
-                    if let IpAddr::V6(v6) = addr {
-                        let next_hop = octets_to_ip(&mp_reach.next_hop);
-                        let route = Route::from_addrs(v6, next_hop);
-                        if route.has_valid_prefix() {
-                            return Ok(route);
-                        }
-                    }
+            if let Some(prefix) = mp_reach.announced_routes.first().and_then(nlri_to_ip) {
+                if let Some(next_hop) = next_hop_to_ip(&mp_reach.next_hop) {
+                    return announced_route(update, prefix, next_hop);
                 }
             }
-            return Err(KvsError::NotAKvsRoute);
         } else if let Some(PathAttribute::MP_UNREACH_NLRI(mp_unreach)) =
             update.get(Identifier::MP_UNREACH_NLRI)
         {
             // These are KeyValue pairs removed from remote servers
             // Collect and remove from local store
-            let next_hop: Option<Ipv6Addr> =
-                if let Some(PathAttribute::NEXT_HOP(next_hop)) = update.get(Identifier::NEXT_HOP) {
-                    if let IpAddr::V6(v6) = next_hop {
-                        Some(*v6)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                };
-            let next_hop = if let Some(next_hop) = next_hop {
-                next_hop
-            } else {
-                return Err(KvsError::NotAKvsRoute);
-            };
-            if let Some(nlri) = mp_unreach.withdrawn_routes.first() {
-                if let NLRIEncoding::IP(prefix) = nlri {
-                    let addr: IpAddr = prefix.into();
-                    if let IpAddr::V6(v6) = addr {
-                        let route = Route::from_addrs(v6, next_hop);
-                        if route.has_valid_prefix() {
-                            return Ok(route);
-                        }
-                    }
-                }
+            if let Some(prefix) = mp_unreach.withdrawn_routes.first().and_then(nlri_to_ip) {
+                return withdrawn_route(update, prefix);
+            }
+        } else if let Some(prefix) = update.announced_routes.first().and_then(nlri_to_ip) {
+            // IPv4 Unicast routes are usually sent without multiprotocol extensions
+            if let Some(PathAttribute::NEXT_HOP(IpAddr::V4(next_hop))) =
+                update.get(Identifier::NEXT_HOP)
+            {
+                return announced_route(update, prefix, next_hop.to_ipv6_mapped());
             }
-            return Err(KvsError::NotAKvsRoute);
+        } else if let Some(prefix) = update.withdrawn_routes.first().and_then(nlri_to_ip) {
+            return withdrawn_route(update, prefix);
         }
         Err(KvsError::NotAKvsRoute)
     }
 }
 
-/// Represents one [KeyValue](struct.KeyValue.html) as a collection of IPv6 Unicast Routes
+/// An announced [Route](struct.Route.html) of any cluster, for the caller to pick the store by prefix
+fn announced_route(
+    update: &Update,
+    prefix: Ipv6Addr,
+    next_hop: Ipv6Addr,
+) -> Result<PeerRoute, KvsError> {
+    let route = Route::from_addrs(prefix, next_hop);
+    if !route.is_kvs_route() {
+        return Err(KvsError::NotAKvsRoute);
+    }
+    let communities = match update.get(Identifier::COMMUNITY) {
+        Some(PathAttribute::COMMUNITY(communities)) => communities.clone(),
+        _ => vec![],
+    };
+    Ok(PeerRoute::Announced(route, communities))
+}
+
+/// A withdrawn [Prefix](struct.Prefix.html) of any cluster, along with its [NextHop](struct.NextHop.html)
+/// if the peer sent one
+fn withdrawn_route(update: &Update, prefix: Ipv6Addr) -> Result<PeerRoute, KvsError> {
+    let prefix = Prefix(prefix);
+    if !prefix.is_kvs_prefix() {
+        return Err(KvsError::NotAKvsRoute);
+    }
+    let next_hop = match update.get(Identifier::NEXT_HOP) {
+        Some(PathAttribute::NEXT_HOP(next_hop)) => {
+            let route = Route::from_addrs(*prefix.as_ref(), to_ipv6(*next_hop));
+            if route.is_kvs_route() {
+                Some(route.next_hop)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    };
+    Ok(PeerRoute::Withdrawn(prefix, next_hop))
+}
+
+/// Represents one [KeyValue](struct.KeyValue.html) as a collection of IPv6 (and possibly IPv4) Unicast Routes
 #[derive(Debug)]
 pub struct RouteCollection(Vec<Route>);
 
@@ -393,36 +1238,44 @@ impl RouteCollection {
         Self(routes)
     }
 
-    /// Iterate through contained routes in sorted order (by sequence number)
-    pub fn iter(&self) -> impl Iterator<Item = &Route> {
-        self.0.iter()
+    /// Join the routes of many `RouteCollection`s (E.g. for a batch of [KeyValue](struct.KeyValue.html)s),
+    /// keeping the routes of each collection in order
+    pub fn concat<I: IntoIterator<Item = RouteCollection>>(collections: I) -> Self {
+        Self(collections.into_iter().flat_map(|c| c.0).collect())
     }
-}
 
-impl<K, V> TryFrom<&KeyValue<K, V>> for RouteCollection
-where
-    K: Debug + Display + Hash + Serialize + DeserializeOwned,
-    V: Debug + Display + Serialize + DeserializeOwned,
-{
-    type Error = KvsError;
-
-    fn try_from(kv: &KeyValue<K, V>) -> Result<Self, Self::Error> {
-        let num_routes = kv.number_of_routes();
+    /// Encode a [KeyValue](struct.KeyValue.html) as routes under the given [AddrPrefix](struct.AddrPrefix.html),
+    /// encrypting the value if a [ClusterKey](crypto/struct.ClusterKey.html) is given, and signing the pair if a
+    /// [NodeKey](crypto/struct.NodeKey.html) is given
+    ///
+    /// If the prefix has an IPv4 octet and the pair has an [IPv4 slot](struct.KeyValue.html#method.ipv4_slot), the pair
+    /// is also encoded as IPv4 routes (after the IPv6 routes), for peers that only carry IPv4 Unicast.
+    /// Pairs too large for IPv4 routes are only encoded as IPv6 routes
+    pub fn encode<K, V>(
+        kv: &KeyValue<K, V>,
+        addr_prefix: AddrPrefix,
+        cluster_key: Option<&ClusterKey>,
+        node_key: Option<&NodeKey>,
+    ) -> Result<Self, KvsError>
+    where
+        K: Debug + Display + Hash + Serialize + DeserializeOwned,
+        V: Debug + Display + Serialize + DeserializeOwned,
+    {
+        let bytes = kv.encode(cluster_key, node_key);
+        let num_routes = (bytes.len() + CHUNK_SIZE - 1) / CHUNK_SIZE;
+        if num_routes > MAX_ROUTES {
+            return Err(KvsError::EncodeError(format!(
+                "{} is too large, needs {} routes (max {})",
+                kv.key, num_routes, MAX_ROUTES
+            )));
+        }
         let mut routes: Vec<Route> = Vec::with_capacity(num_routes);
 
-        // Encode the K/V lengths for the first prefix
-        let lengths = [
-            (kv.key.len() as u16).to_be_bytes(),
-            (kv.value.len() as u16).to_be_bytes(),
-        ]
-        .concat();
-
         let mut prefix_buf = BytesMut::with_capacity(128);
         let mut next_hop_buf = BytesMut::with_capacity(128);
 
-        for (i, chunk) in enumerate(&chain(lengths.iter(), kv.as_bytes().iter()).chunks(CHUNK_SIZE))
-        {
-            prefix_buf.put(&ADDR_PREFIX[..]);
+        for (i, chunk) in bytes.chunks(CHUNK_SIZE).enumerate() {
+            prefix_buf.put_u16(addr_prefix.ipv6);
             prefix_buf.put_u16(i as u16);
             let mut remaining = CHUNK_SIZE;
             for byte in chunk {
@@ -436,17 +1289,70 @@ where
             let prefix: Prefix = (&prefix_buf).into();
             prefix_buf.clear();
 
-            next_hop_buf.put(&ADDR_PREFIX[..]);
-            next_hop_buf.put_u16(kv.version);
-            next_hop_buf.put_u16(i as u16);
+            next_hop_buf.put_u16(addr_prefix.ipv6);
+            next_hop_buf.put_u32(kv.version.tag());
+            next_hop_buf.put_u16(kv.version.origin_tag());
             next_hop_buf.put_u16(num_routes as u16);
-            next_hop_buf.put_u64(kv.key_hash());
+            next_hop_buf.put_uint(kv.key_hash(), 6);
             let next_hop: NextHop = (&next_hop_buf).into();
             next_hop_buf.clear();
 
-            routes.push(Route { prefix, next_hop });
+            routes.push(Route {
+                prefix,
+                next_hop,
+                categories: kv.categories.clone(),
+            });
+        }
+        let num_routes = (bytes.len() + IPV4_CHUNK_SIZE - 1) / IPV4_CHUNK_SIZE;
+        let ipv4 = addr_prefix.ipv4().zip(kv.ipv4_slot);
+        if let Some((octet, slot)) = ipv4.filter(|_| num_routes <= MAX_IPV4_ROUTES) {
+            let origin_tag = kv.version.origin as u8;
+            for (i, chunk) in bytes.chunks(IPV4_CHUNK_SIZE).enumerate() {
+                let mut data = [0u8; IPV4_CHUNK_SIZE];
+                data[..chunk.len()].copy_from_slice(chunk);
+                let prefix = Ipv4Addr::new(octet, origin_tag, slot, i as u8);
+                let next_hop = Ipv4Addr::new(num_routes as u8, data[0], data[1], data[2]);
+                routes.push(Route {
+                    prefix: Prefix(prefix.to_ipv6_mapped()),
+                    next_hop: NextHop(next_hop.to_ipv6_mapped()),
+                    categories: kv.categories.clone(),
+                });
+            }
         }
-        Ok(RouteCollection::from_routes(routes))
+        // Already in order (and sorting would interleave the IPv4 & IPv6 routes)
+        Ok(Self(routes))
+    }
+
+    /// Iterate through contained routes in sorted order (by sequence number)
+    pub fn iter(&self) -> impl Iterator<Item = &Route> {
+        self.0.iter()
+    }
+
+    /// Are there no routes in this collection?
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Drop the routes with a prefix also in `other`
+    ///
+    /// Routes of different versions can share a prefix (E.g. when a chunk of the header is unchanged), and
+    /// announcing a prefix already replaces its route, so withdrawing it too would withdraw the new route
+    pub fn without_prefixes_of(mut self, other: &RouteCollection) -> Self {
+        let prefixes: HashSet<Ipv6Addr> = other.iter().map(|route| route.prefix.0).collect();
+        self.0.retain(|route| !prefixes.contains(&route.prefix.0));
+        self
+    }
+}
+
+impl<K, V> TryFrom<&KeyValue<K, V>> for RouteCollection
+where
+    K: Debug + Display + Hash + Serialize + DeserializeOwned,
+    V: Debug + Display + Serialize + DeserializeOwned,
+{
+    type Error = KvsError;
+
+    fn try_from(kv: &KeyValue<K, V>) -> Result<Self, Self::Error> {
+        Self::encode(kv, AddrPrefix::default(), None, None)
     }
 }
 
@@ -458,47 +1364,64 @@ where
     type Error = KvsError;
 
     fn try_from(routes: &RouteCollection) -> Result<Self, Self::Error> {
-        let first = routes
-            .0
-            .first()
-            .ok_or_else(|| KvsError::DecodeError("At least one route should exist".to_owned()))?;
+        Self::decode(routes, AddrPrefix::default(), None)
+    }
+}
 
-        let key_length = first.prefix.0.segments()[2];
-        let val_length = first.prefix.0.segments()[3];
-        let mut bytes: Vec<u8> = Vec::with_capacity((key_length + val_length) as usize);
+/// Bytes needed to encode the key & value lengths, extended if either doesn't fit in 16 bits
+///
+/// The max 16-bit key length marks extended lengths, so it's only used by extended lengths
+fn lengths_size(key_len: usize, val_len: usize) -> usize {
+    if key_len < EXTENDED_LENGTH as usize && val_len <= u16::MAX as usize {
+        LENGTHS_SIZE
+    } else {
+        EXTENDED_LENGTHS_SIZE
+    }
+}
 
-        let mut version: Option<u16> = None;
-        let mut hash: Option<u64> = None;
+/// Message signed for a pair: the encoded bytes ahead of the signature, followed by the (sorted)
+/// categories as 16-bit big endian values, as categories are carried by communities rather than the data
+fn signed_message(bytes: &[u8], categories: &[u16]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(bytes.len() + categories.len() * 2);
+    message.extend_from_slice(bytes);
+    for category in categories {
+        message.extend_from_slice(&category.to_be_bytes());
+    }
+    message
+}
 
-        for (i, route) in routes.0.iter().enumerate() {
-            if !route.has_valid_prefix() {
-                return Err(KvsError::DecodeError("Not a KVS-BGP Prefix".to_owned()));
-            }
-            if route.sequence() != i as u16 {
-                return Err(KvsError::DecodeError(format!(
-                    "Missing route sequence # {}",
-                    i
-                )));
-            }
-            if i == 0 {
-                version.replace(route.next_hop.version());
-                hash.replace(route.next_hop.hash());
-                bytes.extend_from_slice(&route.prefix.0.octets()[8..]);
-            } else {
-                bytes.extend_from_slice(&route.prefix.0.octets()[4..]);
-            }
-        }
+/// Bytes added to an encoded pair by encrypting (the nonce & authentication tag) and signing it
+fn overhead(cluster_key: Option<&ClusterKey>, node_key: Option<&NodeKey>) -> usize {
+    cluster_key.map_or(0, |_| NONCE_SIZE + TAG_SIZE)
+        + node_key.map_or(0, |_| PUBLIC_KEY_SIZE + SIGNATURE_SIZE)
+}
 
-        let (key, bytes) = bytes.split_at(key_length as usize);
-        let (value, _) = bytes.split_at(val_length as usize);
-        let version = version.ok_or_else(|| KvsError::DecodeError("Missing version".to_owned()))?;
-        let key = bincode::deserialize(&key)
-            .map_err(|_e| KvsError::DecodeError("Couldn't decode key".to_owned()))?;
-        let value = bincode::deserialize(&value)
-            .map_err(|_e| KvsError::DecodeError("Couldn't decode value".to_owned()))?;
-        let kv = Self::with_version(key, value, version);
-        Ok(kv)
+/// Decompress an LZ4 compressed value, prefixed with its (little endian) decompressed size
+fn decompress(value: &[u8]) -> Result<Vec<u8>, KvsError> {
+    let mut size = [0u8; 4];
+    if value.len() < size.len() {
+        return Err(KvsError::DecodeError(
+            "Compressed value is missing its size".to_owned(),
+        ));
     }
+    size.copy_from_slice(&value[..4]);
+    let size = u32::from_le_bytes(size) as usize;
+    if size > MAX_DECOMPRESSED_SIZE {
+        return Err(KvsError::DecodeError(format!(
+            "Compressed value is too large: {} bytes (max {})",
+            size, MAX_DECOMPRESSED_SIZE
+        )));
+    }
+    lz4_flex::decompress_size_prepended(value)
+        .map_err(|e| KvsError::DecodeError(format!("Couldn't decompress value: {}", e)))
+}
+
+/// Extract the address from an NLRI (if it is one), IPv4-mapped for IPv4 prefixes
+fn nlri_to_ip(nlri: &NLRIEncoding) -> Option<Ipv6Addr> {
+    if let NLRIEncoding::IP(prefix) = nlri {
+        return Some(to_ipv6(prefix.into()));
+    }
+    None
 }
 
 /// Convert a [u8] slice (with at least 16 x u8) into an Ipv6 addr
@@ -509,6 +1432,49 @@ fn octets_to_ip(bytes: &[u8]) -> Ipv6Addr {
     Ipv6Addr::from(octets)
 }
 
+/// Convert a multiprotocol next hop (IPv6, or IPv4) into an Ipv6 (or IPv4-mapped) addr
+#[inline]
+fn next_hop_to_ip(bytes: &[u8]) -> Option<Ipv6Addr> {
+    match bytes.len() {
+        4 => Some(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]).to_ipv6_mapped()),
+        len if len >= 16 => Some(octets_to_ip(bytes)),
+        _ => None,
+    }
+}
+
+/// Represent an address as IPv6, IPv4-mapped for IPv4 addresses
+#[inline]
+fn to_ipv6(addr: IpAddr) -> Ipv6Addr {
+    match addr {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+/// The IPv4 address an IPv4-mapped address (`::ffff:a.b.c.d`) represents
+#[inline]
+fn ipv4_mapped(addr: &Ipv6Addr) -> Option<Ipv4Addr> {
+    match addr.octets() {
+        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => Some(Ipv4Addr::new(a, b, c, d)),
+        _ => None,
+    }
+}
+
+/// Represent an address as an `IpAddr`, IPv4 for IPv4-mapped addresses
+#[inline]
+fn to_ip(addr: Ipv6Addr) -> IpAddr {
+    match ipv4_mapped(&addr) {
+        Some(v4) => IpAddr::V4(v4),
+        None => IpAddr::V6(addr),
+    }
+}
+
+/// Is this the first octet of IPv4 `KeyValue` routes (in class E space, excluding broadcast)?
+#[inline]
+fn is_ipv4_prefix(octet: u8) -> bool {
+    (240..=254).contains(&octet)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -540,29 +1506,32 @@ mod tests {
 
     #[test]
     fn test_key_value() {
-        let kv1 = KeyValue::new("myKey".to_owned(), 42);
+        let kv1 = KeyValue::with_version("myKey".to_owned(), 42, Version::new(0x0102, 0x03));
         assert_eq!(
             kv1.as_bytes(),
-            vec![5, 0, 0, 0, 0, 0, 0, 0, 109, 121, 75, 101, 121, 42, 0, 0, 0]
+            vec![
+                0, 0, 0, 0, 0, 0, 1, 2, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0,
+                0, 0, 0, 109, 121, 75, 101, 121, 42, 0, 0, 0
+            ]
         );
         assert_eq!(&kv1.to_string(), "myKey | 42");
-        assert_eq!(kv1.number_of_routes(), 2);
+        assert_eq!(kv1.number_of_routes(None, None), 4);
 
         let kv2 = KeyValue::new(
             "myKey".to_owned(),
             "This is a really long value that should use a few more routes than the last"
                 .to_owned(),
         );
-        assert_eq!(kv2.number_of_routes(), 9);
+        assert_eq!(kv2.number_of_routes(None, None), 11);
     }
 
     #[test]
     fn test_key_value_update() {
         let mut kv = KeyValue::new("myKey".to_owned(), 42);
-        assert_eq!(kv.version(), 0);
+        assert_eq!(kv.version(), Version::default());
 
         kv.update(24);
-        assert_eq!(kv.version(), 1);
+        assert_eq!(kv.version(), Version::new(1, 0));
         assert_eq!(kv.value.as_ref(), &24);
     }
 
@@ -576,18 +1545,577 @@ mod tests {
         assert_eq!(kv.value.to_string(), kv2.value.to_string());
     }
 
+    #[test]
+    fn stable_key_hash() {
+        // The key hash is part of the wire format, and must never change
+        let kv = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        assert_eq!(kv.key_hash(), 0x6dca_25e9_e065);
+    }
+
+    #[test]
+    fn key_hash_mismatch() {
+        let kv = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        let routes: RouteCollection = (&kv).try_into().unwrap();
+        let other = KeyValue::new("Other".to_owned(), "Some Value".to_owned());
+        let other_routes: RouteCollection = (&other).try_into().unwrap();
+        // Prefixes of one key with the NextHops (and hash) of another
+        let mixed = RouteCollection(
+            routes
+                .iter()
+                .zip(other_routes.iter())
+                .map(|(route, other)| Route {
+                    prefix: route.prefix.clone(),
+                    next_hop: other.next_hop.clone(),
+                    categories: vec![],
+                })
+                .collect(),
+        );
+        let decoded: Result<KeyValue<String, String>, _> = (&mixed).try_into();
+        assert!(matches!(decoded, Err(KvsError::DecodeError(_))));
+    }
+
+    #[test]
+    fn round_trip_expires() {
+        let mut kv = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        assert!(!kv.is_expired(u64::MAX));
+        kv.set_expires(Some(1_600_000_060_000));
+        let routes: RouteCollection = (&kv).try_into().unwrap();
+        let kv2: KeyValue<String, String> = (&routes).try_into().unwrap();
+        assert_eq!(kv2.expires(), Some(1_600_000_060_000));
+        assert!(!kv2.is_expired(1_600_000_059_999));
+        assert!(kv2.is_expired(1_600_000_060_000));
+    }
+
+    #[test]
+    fn round_trip_ephemeral() {
+        let mut kv = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        kv.set_ephemeral(true);
+        let routes: RouteCollection = (&kv).try_into().unwrap();
+        let kv2: KeyValue<String, String> = (&routes).try_into().unwrap();
+        assert!(kv2.is_ephemeral());
+        assert_eq!(kv2.expires(), None);
+        assert_eq!(kv2.batch_size(), 0);
+    }
+
+    #[test]
+    fn round_trip_batch_size() {
+        let mut kv = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        kv.set_batch_size(3);
+        let routes: RouteCollection = (&kv).try_into().unwrap();
+        let kv2: KeyValue<String, String> = (&routes).try_into().unwrap();
+        assert_eq!(kv2.batch_size(), 3);
+        assert!(!kv2.is_ephemeral());
+    }
+
+    #[test]
+    fn round_trip_payload() {
+        let payload = Payload::new(vec![0, 159, 146, 150, 255], Some("image/png".to_owned()));
+        assert_eq!(payload.to_string(), "<5 bytes>");
+        let kv = KeyValue::new("MyKey".to_owned(), payload.clone());
+        let routes: RouteCollection = (&kv).try_into().unwrap();
+        let kv2: KeyValue<String, Payload> = (&routes).try_into().unwrap();
+        assert_eq!(kv2.into_value(), payload);
+    }
+
+    /// A `KeyValue` with an (incompressible) value that encodes to exactly `len` bytes
+    fn sized_pair(len: usize) -> KeyValue<String, Payload> {
+        // Xorshift, so the data doesn't compress
+        let mut state = 0x2545_f491_u32;
+        let data = (0..len - 9)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        // Serialized as an empty content type, the data length (u64) & the data
+        KeyValue::new("MyKey".to_owned(), Payload::new(data, None))
+    }
+
+    #[test]
+    fn round_trip_extended_lengths() {
+        for len in &[u16::MAX as usize, u16::MAX as usize + 1, 100_000] {
+            let kv = sized_pair(*len);
+            assert_eq!(kv.value.len(), *len);
+            let routes: RouteCollection = (&kv).try_into().unwrap();
+            let extended = routes.0[0].prefix.0.segments()[2] == EXTENDED_LENGTH;
+            assert_eq!(extended, *len > u16::MAX as usize);
+            assert_eq!(routes.0.len(), kv.number_of_routes(None, None));
+            let kv2: KeyValue<String, Payload> = (&routes).try_into().unwrap();
+            assert_eq!(kv2.as_ref(), kv.as_ref());
+        }
+    }
+
+    #[test]
+    fn round_trip_compressed() {
+        let value = "Repetitive value ".repeat(100);
+        let kv = KeyValue::new("MyKey".to_owned(), value.clone());
+        let routes: RouteCollection = (&kv).try_into().unwrap();
+        assert_eq!(routes.0.len(), kv.number_of_routes(None, None));
+        assert!(routes.0.len() < (value.len() / CHUNK_SIZE));
+        let segments = routes.0[0].prefix.0.segments();
+        assert_eq!(segments[2], EXTENDED_LENGTH);
+        assert_eq!(segments[3], ENCODING_COMPRESSED);
+        let kv2: KeyValue<String, String> = (&routes).try_into().unwrap();
+        assert_eq!(kv2.as_ref(), &value);
+
+        // The cached lengths follow updates of the value
+        let mut kv = kv;
+        kv.update("Less repetitive".to_owned());
+        let routes: RouteCollection = (&kv).try_into().unwrap();
+        assert_eq!(routes.0.len(), kv.number_of_routes(None, None));
+        assert_ne!(routes.0[0].prefix.0.segments()[2], EXTENDED_LENGTH);
+
+        // Not compressed when it doesn't save any routes
+        let kv = sized_pair(1000);
+        let routes: RouteCollection = (&kv).try_into().unwrap();
+        assert_eq!(routes.0[0].prefix.0.segments()[3], 1000);
+
+        // Forged sizes larger than any encodable value aren't decompressed
+        let mut forged = lz4_flex::compress_prepend_size(b"value");
+        forged[..4].copy_from_slice(&((MAX_DECOMPRESSED_SIZE + 1) as u32).to_le_bytes());
+        assert!(matches!(decompress(&forged), Err(KvsError::DecodeError(_))));
+    }
+
+    #[test]
+    fn round_trip_encrypted() {
+        let cluster_key = ClusterKey::from_secret(b"Cluster secret");
+        let kv = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        let routes =
+            RouteCollection::encode(&kv, AddrPrefix::default(), Some(&cluster_key), None).unwrap();
+        assert_eq!(routes.0[0].prefix.0.segments()[3], ENCODING_ENCRYPTED);
+        // The same routes are encoded every time, so they can be withdrawn
+        let again =
+            RouteCollection::encode(&kv, AddrPrefix::default(), Some(&cluster_key), None).unwrap();
+        assert!(routes
+            .iter()
+            .zip(again.iter())
+            .all(|(a, b)| a.prefix.as_ref() == b.prefix.as_ref()));
+        assert_eq!(
+            routes.0.len(),
+            kv.number_of_routes(Some(&cluster_key), None)
+        );
+        let kv2: KeyValue<String, String> =
+            KeyValue::decode(&routes, AddrPrefix::default(), Some(&cluster_key)).unwrap();
+        assert_eq!(kv2.as_ref(), "Some Value");
+        // Including when re-encoding the decoded pair
+        let decoded =
+            RouteCollection::encode(&kv2, AddrPrefix::default(), Some(&cluster_key), None).unwrap();
+        assert!(routes
+            .iter()
+            .zip(decoded.iter())
+            .all(|(a, b)| a.prefix.as_ref() == b.prefix.as_ref()));
+
+        // Another value with the same header & key is encrypted with a new nonce
+        let mut other = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        assert_ne!(other.nonce(), kv.nonce());
+        let nonce = *other.nonce();
+        other.update_with_version("Other Value".to_owned(), Version::default());
+        assert_ne!(other.nonce(), &nonce);
+
+        let no_key: Result<KeyValue<String, String>, _> = (&routes).try_into();
+        assert!(matches!(no_key, Err(KvsError::DecryptionError(_))));
+        let other_key = ClusterKey::from_secret(b"Other secret");
+        let wrong_key: Result<KeyValue<String, String>, _> =
+            KeyValue::decode(&routes, AddrPrefix::default(), Some(&other_key));
+        assert!(matches!(wrong_key, Err(KvsError::DecryptionError(_))));
+
+        // Compressed before encrypting
+        let value = "Repetitive value ".repeat(100);
+        let kv = KeyValue::new("MyKey".to_owned(), value.clone());
+        let routes =
+            RouteCollection::encode(&kv, AddrPrefix::default(), Some(&cluster_key), None).unwrap();
+        assert_eq!(
+            routes.0[0].prefix.0.segments()[3],
+            ENCODING_COMPRESSED | ENCODING_ENCRYPTED
+        );
+        let kv2: KeyValue<String, String> =
+            KeyValue::decode(&routes, AddrPrefix::default(), Some(&cluster_key)).unwrap();
+        assert_eq!(kv2.as_ref(), &value);
+    }
+
+    #[test]
+    fn round_trip_signed() {
+        let node_key = NodeKey::from_hex(&"42".repeat(32)).unwrap();
+        let kv = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        let routes =
+            RouteCollection::encode(&kv, AddrPrefix::default(), None, Some(&node_key)).unwrap();
+        assert_eq!(routes.0[0].prefix.0.segments()[3], ENCODING_SIGNED);
+        assert_eq!(routes.0.len(), kv.number_of_routes(None, Some(&node_key)));
+        let kv2: KeyValue<String, String> = (&routes).try_into().unwrap();
+        assert_eq!(kv2.signer(), Some(&node_key.public_key()));
+        assert_eq!(kv2.as_ref(), "Some Value");
+        let unsigned: KeyValue<String, String> = (&RouteCollection::try_from(&kv).unwrap())
+            .try_into()
+            .unwrap();
+        assert!(unsigned.signer().is_none());
+
+        // Signed & encrypted
+        let cluster_key = ClusterKey::from_secret(b"Cluster secret");
+        let routes = RouteCollection::encode(
+            &kv,
+            AddrPrefix::default(),
+            Some(&cluster_key),
+            Some(&node_key),
+        )
+        .unwrap();
+        let kv2: KeyValue<String, String> =
+            KeyValue::decode(&routes, AddrPrefix::default(), Some(&cluster_key)).unwrap();
+        assert_eq!(kv2.signer(), Some(&node_key.public_key()));
+        assert_eq!(kv2.as_ref(), "Some Value");
+    }
+
+    #[test]
+    fn forged_signature() {
+        let node_key = NodeKey::from_hex(&"42".repeat(32)).unwrap();
+        let kv = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        let mut routes =
+            RouteCollection::encode(&kv, AddrPrefix::default(), None, Some(&node_key)).unwrap();
+        // Flip a bit in the value, then fix up the checksum so only the signature catches it
+        let mut bytes = kv.encode(None, Some(&node_key));
+        let checksummed = bytes.len() - CHECKSUM_SIZE;
+        bytes[checksummed - PUBLIC_KEY_SIZE - SIGNATURE_SIZE - 1] ^= 0x01;
+        let checksum = crc32fast::hash(&bytes[..checksummed]);
+        bytes[checksummed..].copy_from_slice(&checksum.to_be_bytes());
+        for (route, chunk) in routes.0.iter_mut().zip(bytes.chunks(CHUNK_SIZE)) {
+            let mut octets = route.prefix.0.octets();
+            octets[4..4 + chunk.len()].copy_from_slice(chunk);
+            route.prefix = Prefix(Ipv6Addr::from(octets));
+        }
+        let kv2: Result<KeyValue<String, String>, _> = (&routes).try_into();
+        assert!(matches!(kv2, Err(KvsError::SignatureError(_))));
+
+        // Categories are signed too, so they can't be changed in the path
+        let mut kv = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        kv.set_categories(vec![2, 1]);
+        let mut routes =
+            RouteCollection::encode(&kv, AddrPrefix::default(), None, Some(&node_key)).unwrap();
+        let kv2: KeyValue<String, String> = (&routes).try_into().unwrap();
+        assert_eq!(kv2.categories(), &[1, 2]);
+        routes.0[0].categories = vec![1];
+        let kv2: Result<KeyValue<String, String>, _> = (&routes).try_into();
+        assert!(matches!(kv2, Err(KvsError::SignatureError(_))));
+    }
+
+    #[test]
+    fn encode_max_routes() {
+        // Largest value that fits in 65,535 routes
+        let key_len = 8 + "MyKey".len();
+        let max =
+            MAX_ROUTES * CHUNK_SIZE - EXTENDED_LENGTHS_SIZE - HEADER_SIZE - key_len - CHECKSUM_SIZE;
+        let kv = sized_pair(max);
+        assert_eq!(kv.number_of_routes(None, None), MAX_ROUTES);
+        let routes: RouteCollection = (&kv).try_into().unwrap();
+        let kv2: KeyValue<String, Payload> = (&routes).try_into().unwrap();
+        assert_eq!(kv2.as_ref().data.len(), max - 9);
+
+        let kv = sized_pair(max + 1);
+        assert_eq!(kv.number_of_routes(None, None), MAX_ROUTES + 1);
+        let routes: Result<RouteCollection, _> = (&kv).try_into();
+        assert!(matches!(routes, Err(KvsError::EncodeError(_))));
+    }
+
+    #[test]
+    fn round_trip_categories() {
+        let mut kv = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        kv.set_categories(vec![42, 7, 42]);
+        assert_eq!(kv.categories(), &[7, 42]);
+        let routes: RouteCollection = (&kv).try_into().unwrap();
+        for route in routes.iter() {
+            assert_eq!(
+                route.communities(AddrPrefix::default()),
+                vec![0xbf51_0007, 0xbf51_002a]
+            );
+        }
+        let kv2: KeyValue<String, String> = (&routes).try_into().unwrap();
+        assert_eq!(kv2.categories(), &[7, 42]);
+
+        // Communities are under the address prefix of the store
+        let addr_prefix: AddrPrefix = "fd42".parse().unwrap();
+        let mut route = routes.0[0].clone();
+        assert_eq!(
+            route.communities(addr_prefix),
+            vec![0xfd42_0007, 0xfd42_002a]
+        );
+        route.set_communities(&[0xfd42_0007, 0xbf51_002a], addr_prefix);
+        assert_eq!(route.categories, vec![7]);
+    }
+
     #[test]
     fn has_valid_prefix() {
-        let route = Route {
-            prefix: Prefix("BF51:10::2".parse().unwrap()),
-            next_hop: NextHop("bf51:A::2".parse().unwrap()),
+        let prefix = AddrPrefix::default();
+        let route = Route::from_addrs("BF51:10::2".parse().unwrap(), "bf51:A::2".parse().unwrap());
+        assert!(route.has_valid_prefix(prefix));
+        let route = Route::from_addrs("2001:10::2".parse().unwrap(), "bf51:A::2".parse().unwrap());
+        assert!(!route.has_valid_prefix(prefix));
+        let route = Route::from_addrs("BF52:10::2".parse().unwrap(), "bf52:A::2".parse().unwrap());
+        assert!(!route.has_valid_prefix(prefix));
+        assert!(route.has_valid_prefix(AddrPrefix::new(0xbf52).unwrap()));
+    }
+
+    #[test]
+    fn liveness_route() {
+        let prefix = AddrPrefix::default();
+        let route = Route::liveness(prefix, 0xdead_beef);
+        assert!(route.has_valid_prefix(prefix));
+        assert_eq!(
+            route.prefix.as_ref(),
+            &"bf51:ffff:dead:beef::".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(route.prefix.liveness_origin(), Some(0xdead_beef));
+        // Routes of a `KeyValue` never use the liveness sequence #
+        let kv = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        let routes: RouteCollection = (&kv).try_into().unwrap();
+        assert!(routes
+            .iter()
+            .all(|route| route.prefix.liveness_origin().is_none()));
+        assert_eq!(route.liveness_origin(), Some(0xdead_beef));
+        assert!(Route::ipv4_liveness(prefix, 0xdead_beef).is_none());
+
+        // With an IPv4 octet, the origin id is split between the IPv4 prefix & next hop
+        let prefix: AddrPrefix = "BF51,240".parse().unwrap();
+        let route = Route::ipv4_liveness(prefix, 0xdead_beef).unwrap();
+        assert!(route.is_ipv4());
+        assert!(route.has_valid_prefix(prefix));
+        assert_eq!(
+            IpAddr::from(&route.prefix),
+            "240.190.239.255".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            IpAddr::from(&route.next_hop),
+            "1.222.173.0".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(route.liveness_origin(), Some(0xdead_beef));
+        assert!(route.prefix.liveness_origin().is_none());
+    }
+
+    #[test]
+    fn addr_prefix() {
+        assert_eq!(AddrPrefix::default().to_string(), "BF51");
+        assert_eq!("bf51".parse::<AddrPrefix>().unwrap(), AddrPrefix::default());
+        assert_eq!(
+            "BF51::/16".parse::<AddrPrefix>().unwrap(),
+            AddrPrefix::default()
+        );
+        assert_eq!(
+            "fd42".parse::<AddrPrefix>().unwrap(),
+            AddrPrefix::new(0xfd42).unwrap()
+        );
+        let with_ipv4 = "BF51,240".parse::<AddrPrefix>().unwrap();
+        assert_eq!(with_ipv4.ipv4(), Some(240));
+        assert_eq!(with_ipv4.to_string(), "BF51,240");
+        assert!(with_ipv4.overlaps(&AddrPrefix::default()));
+        assert!(with_ipv4.overlaps(&"fd42,240".parse().unwrap()));
+        assert!(!with_ipv4.overlaps(&"fd42,241".parse().unwrap()));
+        for reserved in &[
+            "0", "2001", "3fff", "fe80", "ff02", "bf51:1", "nope", "bf51,10", "bf51,255",
+        ] {
+            assert!(matches!(
+                reserved.parse::<AddrPrefix>(),
+                Err(KvsError::InvalidPrefix(_))
+            ));
+        }
+
+        // Routes of another cluster aren't decoded
+        let prefix = AddrPrefix::new(0xfd42).unwrap();
+        let kv = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        let routes = RouteCollection::encode(&kv, prefix, None, None).unwrap();
+        assert!(routes.iter().all(|route| route.has_valid_prefix(prefix)));
+        assert!(prefix.matches(routes.0[0].prefix.as_ref()));
+        let kv2: KeyValue<String, String> = KeyValue::decode(&routes, prefix, None).unwrap();
+        assert_eq!(kv2.as_ref(), "Some Value");
+        let other: Result<KeyValue<String, String>, _> = (&routes).try_into();
+        assert!(matches!(other, Err(KvsError::DecodeError(_))));
+    }
+
+    #[test]
+    fn round_trip_ipv4() {
+        let prefix: AddrPrefix = "BF51,240".parse().unwrap();
+        let mut kv = KeyValue::with_version(
+            "MyKey".to_owned(),
+            "Some Value".to_owned(),
+            Version::new(1000, 0x0102),
+        );
+        kv.set_categories(vec![7]);
+        // Pairs without an IPv4 slot are only encoded as IPv6 routes
+        let routes = RouteCollection::encode(&kv, prefix, None, None).unwrap();
+        assert!(routes.iter().all(|route| !route.is_ipv4()));
+
+        kv.set_ipv4_slot(Some(9));
+        assert_eq!(kv.ipv4_id(), Some(0x0209));
+        let routes = RouteCollection::encode(&kv, prefix, None, None).unwrap();
+        let (ipv4, ipv6): (Vec<Route>, Vec<Route>) =
+            routes.iter().cloned().partition(|route| route.is_ipv4());
+        assert_eq!(ipv6.len(), kv.number_of_routes(None, None));
+        assert_eq!(
+            ipv4.len(),
+            (kv.encode(None, None).len() + IPV4_CHUNK_SIZE - 1) / IPV4_CHUNK_SIZE
+        );
+        for (i, route) in ipv4.iter().enumerate() {
+            assert!(route.has_valid_prefix(prefix));
+            assert!(!route.has_valid_prefix(AddrPrefix::default()));
+            assert_eq!(route.sequence(), i as u16);
+            assert_eq!(route.collection_length(), ipv4.len());
+            assert_eq!(route.hash(), 0x0209);
+            assert_eq!(route.version(), 0);
+            assert!(route.liveness_origin().is_none());
+            let prefix: IpAddr = (&route.prefix).into();
+            assert_eq!(prefix, IpAddr::V4(Ipv4Addr::new(240, 2, 9, i as u8)));
+        }
+
+        // Each family decodes on its own, in any order
+        let kv2: KeyValue<String, String> =
+            KeyValue::decode(&RouteCollection::from_routes(ipv4.clone()), prefix, None).unwrap();
+        assert_eq!(kv2.as_ref(), "Some Value");
+        assert_eq!(kv2.version(), kv.version());
+        assert_eq!(kv2.categories(), &[7]);
+        assert_eq!(kv2.ipv4_slot(), Some(9));
+        let kv2: KeyValue<String, String> =
+            KeyValue::decode(&RouteCollection::from_routes(ipv6.clone()), prefix, None).unwrap();
+        assert_eq!(kv2.as_ref(), "Some Value");
+        // But not mixed together
+        let mixed = RouteCollection::from_routes(vec![ipv6[0].clone(), ipv4[1].clone()]);
+        let mixed: Result<KeyValue<String, String>, _> = KeyValue::decode(&mixed, prefix, None);
+        assert!(matches!(mixed, Err(KvsError::DecodeError(_))));
+        // Or too short to hold the lengths
+        let short = RouteCollection::from_routes(ipv4[..1].to_vec());
+        let short: Result<KeyValue<String, String>, _> = KeyValue::decode(&short, prefix, None);
+        assert!(matches!(short, Err(KvsError::DecodeError(_))));
+        // Or with the origin tag of another node
+        let mut forged = ipv4.clone();
+        for route in forged.iter_mut() {
+            let mut octets = route.prefix.0.octets();
+            octets[13] = 3;
+            route.prefix = Prefix(Ipv6Addr::from(octets));
+        }
+        let forged: Result<KeyValue<String, String>, _> =
+            KeyValue::decode(&RouteCollection::from_routes(forged), prefix, None);
+        assert!(matches!(forged, Err(KvsError::DecodeError(_))));
+
+        // A new version needs a new slot
+        kv.update("Other Value".to_owned());
+        assert_eq!(kv.ipv4_slot(), None);
+
+        // Pairs too large for 126 IPv4 routes are only encoded as IPv6 routes,
+        // and next hops stay in unicast space
+        for (len, fits) in &[(300, true), (378, false), (1024, false)] {
+            let mut kv = sized_pair(*len);
+            kv.set_ipv4_slot(Some(1));
+            assert_eq!(kv.fits_ipv4_routes(None, None), *fits);
+            let routes = RouteCollection::encode(&kv, prefix, None, None).unwrap();
+            assert_eq!(routes.iter().any(|route| route.is_ipv4()), *fits);
+            for route in routes.iter().filter(|route| route.is_ipv4()) {
+                match IpAddr::from(&route.next_hop) {
+                    IpAddr::V4(next_hop) => assert!((1..=126).contains(&next_hop.octets()[0])),
+                    IpAddr::V6(_) => panic!("Expected an IPv4 next hop"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn peer_route_from_update() {
+        use bgp_rs::{MPReachNLRI, MPUnreachNLRI, AFI, SAFI};
+
+        let prefix: Ipv6Addr = "bf51:0:d:12:500::".parse().unwrap();
+        let next_hop: Ipv6Addr = "bf51::3:6dca:25e9:e065".parse().unwrap();
+        let nlri = NLRIEncoding::IP((IpAddr::V6(prefix), 128).into());
+
+        let announce = Update {
+            withdrawn_routes: vec![],
+            attributes: vec![
+                PathAttribute::MP_REACH_NLRI(MPReachNLRI {
+                    afi: AFI::IPV6,
+                    safi: SAFI::Unicast,
+                    next_hop: next_hop.octets().to_vec(),
+                    announced_routes: vec![nlri.clone()],
+                }),
+                PathAttribute::COMMUNITY(vec![0xbf51_0007, 0xfde8_0001]),
+            ],
+            announced_routes: vec![],
         };
-        assert!(route.has_valid_prefix());
-        let route = Route {
-            prefix: Prefix("2001:10::2".parse().unwrap()),
-            next_hop: NextHop("bf51:A::2".parse().unwrap()),
+        match (&announce).try_into().unwrap() {
+            PeerRoute::Announced(mut route, communities) => {
+                assert_eq!(route.prefix.as_ref(), &prefix);
+                assert_eq!(route.next_hop.as_ref(), &next_hop);
+                route.set_communities(&communities, AddrPrefix::default());
+                assert_eq!(route.categories, vec![7]);
+            }
+            _ => panic!("Expected an announced route"),
+        }
+
+        let withdraw = Update {
+            withdrawn_routes: vec![],
+            attributes: vec![PathAttribute::MP_UNREACH_NLRI(MPUnreachNLRI {
+                afi: AFI::IPV6,
+                safi: SAFI::Unicast,
+                withdrawn_routes: vec![nlri],
+            })],
+            announced_routes: vec![],
         };
-        assert!(!route.has_valid_prefix());
+        match (&withdraw).try_into().unwrap() {
+            PeerRoute::Withdrawn(withdrawn, next_hop) => {
+                assert_eq!(withdrawn.as_ref(), &prefix);
+                assert!(next_hop.is_none());
+            }
+            _ => panic!("Expected a withdrawn route"),
+        }
+
+        // IPv4 routes, without multiprotocol extensions
+        let prefix: Ipv4Addr = "240.12.34.5".parse().unwrap();
+        let next_hop: Ipv4Addr = "18.23.0.1".parse().unwrap();
+        let nlri = NLRIEncoding::IP((IpAddr::V4(prefix), 32).into());
+        let announce = Update {
+            withdrawn_routes: vec![],
+            attributes: vec![PathAttribute::NEXT_HOP(IpAddr::V4(next_hop))],
+            announced_routes: vec![nlri.clone()],
+        };
+        match (&announce).try_into().unwrap() {
+            PeerRoute::Announced(route, _) => {
+                assert!(route.is_ipv4());
+                assert_eq!(route.prefix.as_ref(), &prefix.to_ipv6_mapped());
+                assert_eq!(route.next_hop.as_ref(), &next_hop.to_ipv6_mapped());
+                assert_eq!(route.sequence(), 5);
+                assert_eq!(route.collection_length(), 18);
+                assert_eq!(route.hash(), 0x0c22);
+            }
+            _ => panic!("Expected an announced route"),
+        }
+        let withdraw = Update {
+            withdrawn_routes: vec![nlri],
+            attributes: vec![],
+            announced_routes: vec![],
+        };
+        match (&withdraw).try_into().unwrap() {
+            PeerRoute::Withdrawn(withdrawn, _) => {
+                assert_eq!(withdrawn.as_ref(), &prefix.to_ipv6_mapped());
+            }
+            _ => panic!("Expected a withdrawn route"),
+        }
+
+        // Real IPv4 routes are ignored
+        let real = Update {
+            withdrawn_routes: vec![],
+            attributes: vec![PathAttribute::NEXT_HOP(IpAddr::V4(next_hop))],
+            announced_routes: vec![NLRIEncoding::IP(
+                (IpAddr::V4("10.0.0.1".parse().unwrap()), 32).into(),
+            )],
+        };
+        assert!(PeerRoute::try_from(&real).is_err());
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let kv = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        let mut routes: RouteCollection = (&kv).try_into().unwrap();
+        // Flip a bit in the data of the last route
+        let last = routes.0.last_mut().unwrap();
+        let mut octets = last.prefix.0.octets();
+        octets[4] ^= 0x01;
+        last.prefix = Prefix(Ipv6Addr::from(octets));
+        let kv2: Result<KeyValue<String, String>, _> = (&routes).try_into();
+        assert!(matches!(kv2, Err(KvsError::ChecksumMismatch(_))));
     }
 
     #[test]
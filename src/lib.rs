@@ -1,6 +1,6 @@
 //! # Internal representation of [KeyValue](struct.KeyValue.html) pairs
 //!
-//! Supports encoding/decoding pairs as BGP update messages using IPv6 Unicast [Prefix](struct.Prefix.html) & [NextHop](struct.NextHop.html)
+//! Supports encoding/decoding pairs as BGP update messages using IPv6 (or IPv4) Unicast [Prefix](struct.Prefix.html) & [NextHop](struct.NextHop.html)
 //!
 //! ## [KeyValue](struct.KeyValue.html) Pairs
 //! Each [KeyValue](struct.KeyValue.html) is allowed ~**768 Kbytes** (65,535 * 96 bits). Data
@@ -8,17 +8,20 @@
 //!
 //! ## [Prefix](struct.Prefix.html) encoding is as follows:
 //!
-//! First prefix for a [KeyValue](struct.KeyValue.html) pair:
+//! Every prefix of a [KeyValue](struct.KeyValue.html) pair carries the next 96 bits of its encoded data:
 //! ```ignore
-//! bits: | 16 :  16    :    16      :     16       :      64        |
-//! addr: |BF51: seq #  : key length : value length :     data       | /128
+//! bits: | 16 :  16    :                   96                       |
+//! addr: |BF51: seq #  :                  data                      | /128
 //! ```
 //!
-//! Subsequent prefixes for a [KeyValue](struct.KeyValue.html) pair:
-//!
+//! The encoded data of a [KeyValue](struct.KeyValue.html) pair (fields in `[]` are only present with extended lengths, or when signed),
+//! zero-padded to a multiple of 96 bits. With extended lengths, the first two fields are `FFFF` & the encoding flags:
 //! ```ignore
-//! bits: | 16 :  16    :                   96                       |
-//! addr: |BF51: seq #  :                  data                      | /128
+//! bits: |     16     :      16      :    [32]    :     [32]     :    64     :   32   :   64   :   8   :   16   |
+//! data: | key length : value length : key length : value length : timestamp : origin : expiry : flags : batch  | ...
+//!
+//! bits: ... |  var  :  var  :   [256]    :   [512]   :  32   |
+//! data: ... |  key  : value : public key : signature : CRC32 |
 //! ```
 //!
 //! ### Notes:
@@ -26,43 +29,96 @@
 //!   - Stands for "Bgp File 5tore v1"
 //!   - Used for easy identification and to make sure this
 //!     doesn't clobber public routes
+//!   - The default [AddrPrefix](kv/struct.AddrPrefix.html), configurable per [KvStore](store/struct.KvStore.html) so
+//!     independent clusters sharing a fabric don't collide. Prefixes in global unicast, link-local or multicast space are refused
 //! - Sequence Number
 //!   - Provides ordering for data decoding and creates unique routes
 //!     so best-path selection doesn't filter prefixes
 //!   - Allows for 65_535 prefixes per [KeyValue](struct.KeyValue.html) pair, and given 12 bytes per prefix
 //!     provides ~768 Kb per [KeyValue](struct.KeyValue.html) pair
+//! - Key & value length
+//!   - Lengths of the serialized key & value, when the key is shorter than 65,535 bytes and the value is at most 65,535 bytes
+//!   - Otherwise the key length is `FFFF` (extended lengths) and the value length holds encoding flags, followed by the 32-bit
+//!     key & value lengths as the first 64 bits of data. Pairs needing more than 65_535 prefixes can't be encoded
+//!   - Encoding flag `1` marks an LZ4 compressed value (prefixed with its 32-bit little endian decompressed size).
+//!     Values are compressed only when that needs fewer prefixes, and values claiming to decompress past ~768 Kbytes are rejected
+//!   - Encoding flag `2` marks a value encrypted with the cluster key (see [crypto](crypto/index.html)),
+//!     prefixed with its random 96-bit nonce and followed by its 128-bit authentication tag
+//!   - Encoding flag `4` marks a pair signed by the [NodeKey](crypto/struct.NodeKey.html) of the node that wrote it,
+//!     with its 256-bit Ed25519 public key & 512-bit signature (of the lengths, header, key & value, followed by the categories)
+//!     following the value
 //! - Data
-//!   - Serialized to bytes using [Serde](https://github.com/serde-rs/serde) with [bincode](https://github.com/servo/bincode) serialization
+//!   - The 64-bit [Version](clock/struct.Version.html) timestamp, 32-bit origin id, 64-bit expiry (big endian, `0` for none),
+//!     8-bit flags (E.g. ephemeral) & 16-bit batch size (`0` for none), followed by the key & value
+//!   - Key & value are serialized to bytes using [Serde](https://github.com/serde-rs/serde) with [bincode](https://github.com/servo/bincode) serialization
+//!   - Values are stored as a [Payload](kv/struct.Payload.html) of raw bytes along with the content type they were inserted with
+//!   - Followed by a CRC32 checksum of all preceding bytes (lengths, header, key, value & signature), and pairs that don't
+//!     match their checksum are rejected
 //!
 //!
 //! ## [NextHop](struct.NextHop.html) encoding is as follows:
 //!
 //! ```ignore
-//! bits: | 16 :   16    :  16   :    16    :          64            |
-//! addr: |BF51: version : seq # : # routes :       key hash         | /128
+//! bits: | 16 :      32       :     16     :    16    :      48      |
+//! addr: |BF51:    ver tag    : origin tag : # routes :   key hash   | /128
 //! ```
 //!
+//! Routes of a [KeyValue](struct.KeyValue.html) pair tagged with categories also carry a `BF51:<category>`
+//! standard community per category (under the [AddrPrefix](kv/struct.AddrPrefix.html) of the store, E.g. `FD42:<category>`),
+//! which peers can subscribe to (or filter with BGP policy).
+//!
 //! ### Notes:
 //! - Version
-//!   - Encoding of the [KeyValue](struct.KeyValue.html) version number
+//!   - Tag of the [KeyValue](struct.KeyValue.html) [Version](clock/struct.Version.html) (low 32 bits of its hybrid logical clock timestamp)
+//!   - Compared with serial number arithmetic, so tags are ordered across wraparound (for versions less than ~24 days apart)
+//!   - Followed by the low 16 bits of the version's origin id, so concurrent writes from different nodes
+//!     (E.g. with the same timestamp) don't share routes
+//!   - The full version (timestamp & origin id) is encoded in the data, and concurrent writes are resolved by the
+//!     higher timestamp, then the higher origin id
 //!   - During convergence of an updated [KeyValue](struct.KeyValue.html) pair, will provide unique Prefix/NextHop route
 //!     so bytes of different versions aren't interlaced together
-//! - Sequence Number
-//!   - Provides ordering for data decoding and creates unique routes
-//!     so best-path selection doesn't filter prefixes
 //! - Number of Routes
 //!   - Count of routes included in this version
 //!   - Used to confirm when all routes have been received before decoding
 //! - Key Hash
 //!   - Hash of the [KeyValue](struct.KeyValue.html) [Key](struct.Key.html), to differentiate this [NextHop](struct.NextHop.html) from other [KeyValue](struct.KeyValue.html) [NextHop](struct.NextHop.html)s
+//!   - SipHash-1-3 of the bincode serialized key (the low 48 bits), with keys `k0 = 0x4B56_5342_4750_0001` & `k1 = 0x4B56_5342_4750_0002`
+//!   - Fixed for version 1 of the encoding (the `1` in `BF51`), so all nodes agree regardless of platform or Rust version
+//!   - Decoded keys are checked against this hash, and the [KvStore](store/struct.KvStore.html) rejects a key whose hash collides with another stored key
+//!
+//! ## IPv4 encoding
+//! For fabrics that don't carry IPv6 Unicast, an [AddrPrefix](kv/struct.AddrPrefix.html) with an IPv4 octet
+//! (E.g. `BF51,240`) also encodes each pair as IPv4 Unicast routes, announced to peers of that family.
+//! The same bytes are split into 24-bit chunks, carried by the [NextHop](struct.NextHop.html)s of /32 prefixes:
+//! ```ignore
+//! prefix:   |  8  :     8      :  8   :   8   |
+//!           | 240 : origin tag : slot : seq # | /32
+//! next hop: |    8     :    24    |
+//!           | # routes :   data   |
+//! ```
+//!
+//! ### Notes:
+//! - The first octet is in class E space (`240` - `254`), so pairs don't clobber real routes
+//! - Route counts are kept to `1` - `126`, so next hops stay in unicast space (never `0`, loopback or multicast).
+//!   Pairs are allowed ~**378 bytes** (126 * 24 bits) of encoded data, and larger pairs (E.g. blob chunks) are
+//!   only announced as IPv6 routes
+//! - The origin tag is the low 8 bits of the writer's origin id, and is checked against the decoded pair
+//! - The slot is picked by the writing [KvStore](store/struct.KvStore.html), so no two of its stored pairs share
+//!   one. New versions take a free slot, so withdrawing the old version leaves them be. Once all 256 slots of
+//!   a node are taken, further writes are only announced as IPv6 routes
+//! - Nodes also announce an IPv4 liveness route (`240.<origin id low 16 bits>.255/32`, via next hop
+//!   `1.<origin id high 16 bits>.0`), for their ephemeral pairs
 //!
 //! ## Example
-//! The [KeyValue](struct.KeyValue.html) pair "MyKey" : "Some Value" would be represented as:
+//! The [KeyValue](struct.KeyValue.html) pair "MyKey" : "Some Value" (written at timestamp `1600000000000` by origin `10`) would be represented as:
 //! ```ignore
-//! | Seq # | Prefix                                   | NextHop                        |
-//! | 0     | BF51:0:D:12:500::                   /128 | BF51::3:7911:E0FA:7BEA:920B    |
-//! | 1     | BF51:1:4D79:4B65:790A::             /128 | BF51:0:1:3:7911:E0FA:7BEA:920B |
-//! | 2     | BF51:2:53:6F6D:6520:5661:6C75:6500  /128 | BF51:0:2:3:7911:E0FA:7BEA:920B |
+//! | Seq # | Prefix                                   | NextHop                           |
+//! | 0     | BF51:0:D:13:0:174:876E:8000         /128 | BF51:876E:8000:A:6:6DCA:25E9:E065 |
+//! | 1     | BF51:1:0:A::                        /128 | BF51:876E:8000:A:6:6DCA:25E9:E065 |
+//! | 2     | BF51:2:0:5::4D                      /128 | BF51:876E:8000:A:6:6DCA:25E9:E065 |
+//! | 3     | BF51:3:794B:6579:A::                /128 | BF51:876E:8000:A:6:6DCA:25E9:E065 |
+//! | 4     | BF51:4:53:6F6D:6520:5661:6C75:65AC  /128 | BF51:876E:8000:A:6:6DCA:25E9:E065 |
+//! | 5     | BF51:5:87C4:AF00::                  /128 | BF51:876E:8000:A:6:6DCA:25E9:E065 |
 //! ```
 //!
 //! ## KvStore
@@ -72,15 +128,33 @@
 /// HTTP API for clients of the KeyValue store service
 pub mod api;
 
+/// Blobs split across many `KeyValue` pairs, for values too large for a single pair
+pub mod blob;
+
+/// Pre-shared key encryption & per-node signing of `KeyValue` pairs on the wire
+pub mod crypto;
+
+/// Hybrid logical clock versions for ordering `KeyValue` writes across nodes
+pub mod clock;
+
 /// Internal `KeyValue` representations for Encoding/Decoding as BGP Updates
 pub mod kv;
 
 /// BGP Peering/Update logic
 pub mod peering;
 
+/// On-disk snapshot & write-ahead log for persisting a `KvStore`
+pub mod persist;
+
+/// Redis (RESP) protocol front-end for clients of the KeyValue store service
+pub mod resp;
+
+/// Reassembly of `KeyValue` routes received from BGP peers
+pub mod reassembly;
+
 /// In-memory Key/Value store that stores `KeyValue` pairs and synchronizes with BGP peers
 pub mod store;
-pub use store::KvStore;
+pub use store::{EventSource, KvStore, StoreEvent};
 
 use thiserror::Error;
 
@@ -89,10 +163,40 @@ use thiserror::Error;
 pub enum KvsError {
     #[error("Could not decode: {0}")]
     DecodeError(String),
+    #[error("Checksum mismatch: {0}")]
+    ChecksumMismatch(String),
+    #[error("Could not decrypt: {0}")]
+    DecryptionError(String),
+    #[error("Invalid signature: {0}")]
+    SignatureError(String),
+    #[error("Untrusted write: {0}")]
+    Untrusted(String),
     #[error("Could not encode: {0}")]
     EncodeError(String),
     #[error("Not a Kvs Route")]
     NotAKvsRoute,
+    #[error("Storage error: {0}")]
+    StorageError(String),
+    #[error("Invalid categories: {0}")]
+    InvalidCategories(String),
+    #[error("Key hash collision: {0}")]
+    HashCollision(String),
+    #[error("Precondition failed: {0}")]
+    PreconditionFailed(String),
+    #[error("Invalid batch: {0}")]
+    InvalidBatch(String),
+    #[error("Invalid blob: {0}")]
+    InvalidBlob(String),
+    #[error("Invalid address prefix: {0}")]
+    InvalidPrefix(String),
+    #[error("Invalid content type: {0}")]
+    InvalidContentType(String),
+    #[error("Invalid version: {0}")]
+    InvalidVersion(String),
+    #[error("Invalid TTL: {0}")]
+    InvalidTtl(String),
+    #[error("Reserved key: {0}")]
+    ReservedKey(String),
 }
 
 impl warp::reject::Reject for KvsError {}
@@ -1,13 +1,28 @@
+use std::collections::BTreeMap;
 use std::error::Error;
-use std::net::IpAddr;
+use std::fmt::Display;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
 
 use env_logger::Builder;
-use log::{info, LevelFilter};
+use log::{error, info, LevelFilter};
 use tokio::sync::{mpsc, RwLock};
 
-use kvs_bgp::{api, peering::BgpPeerings, store::KvStore};
+use kvs_bgp::{
+    api,
+    crypto::{ClusterKey, NodeKey, PublicKey},
+    kv::AddrPrefix,
+    peering::BgpPeerings,
+    persist::Persistence,
+    reassembly::ReassemblyLimits,
+    resp,
+    store::{self, KvStore},
+};
 
 #[derive(StructOpt, Debug)]
 #[structopt(
@@ -27,17 +42,249 @@ pub struct Args {
     /// Host port to use for HTTP API
     #[structopt(long, default_value = "3030")]
     api_port: u16,
+    /// Host address to use for the Redis (RESP) API
+    #[structopt(long, default_value = "127.0.0.1")]
+    resp_address: IpAddr,
+    /// Host port to use for the Redis (RESP) API, serving the default store (disabled if not given)
+    #[structopt(long)]
+    resp_port: Option<u16>,
     /// Host address to use for BGPd
     #[structopt(long, default_value = "127.0.0.1")]
     bgp_address: IpAddr,
     /// Host port to use for BGPd
     #[structopt(long, default_value = "179")]
     bgp_port: u16,
+    /// Directory to persist the KeyValue stores in, the default store in `default/` & each namespace in `ns/<name>/`
+    /// (in-memory only if not given)
+    #[structopt(long, parse(from_os_str))]
+    data_dir: Option<PathBuf>,
+    /// Leading 16 bits (hex) of the IPv6 routes KeyValues are encoded as, the same for every node of the cluster,
+    /// optionally followed by the first octet of IPv4 routes to also encode them as (E.g. "BF51,240")
+    #[structopt(long, default_value = "BF51")]
+    addr_prefix: AddrPrefix,
+    /// Named store to serve under `/ns/<name>/` in the HTTP API, synchronized with its own address prefix
+    /// over the same peers as the default store (E.g. "team-a=FD42" or "team-a=FD42,241" for IPv4 routes too;
+    /// may be given many times)
+    #[structopt(long = "namespace", number_of_values = 1)]
+    namespaces: Vec<Namespace>,
+    /// Unique id of this node, to break ties between concurrent writes (random if not given)
+    #[structopt(long)]
+    origin_id: Option<u32>,
+    /// Number of changes to log before writing a new snapshot of the persisted store
+    #[structopt(long, default_value = "1000")]
+    snapshot_interval: usize,
+    /// Seconds to keep a partially received KeyValue from a peer before dropping it
+    #[structopt(long, default_value = "60")]
+    reassembly_timeout: u64,
+    /// Max number of partially received KeyValues to buffer
+    #[structopt(long, default_value = "1024")]
+    reassembly_max_pending: usize,
+    /// Max bytes of routes to buffer for partially received KeyValues
+    #[structopt(long, default_value = "16777216")]
+    reassembly_max_bytes: usize,
+    /// Only accept KeyValues from peers tagged with these categories (E.g. "10,20"; all if not given)
+    #[structopt(long, use_delimiter = true)]
+    subscribe: Vec<u16>,
+    /// File with a secret shared by all nodes, to encrypt values sent to peers (sent in the clear if not given)
+    #[structopt(long, parse(from_os_str))]
+    cluster_secret_file: Option<PathBuf>,
+    /// File with the hex encoded Ed25519 secret key of this node, to sign pairs sent to peers (unsigned if not given)
+    #[structopt(long, parse(from_os_str))]
+    node_key_file: Option<PathBuf>,
+    /// File with the hex encoded public keys of nodes trusted to write pairs, one per line
+    /// (unsigned & untrusted pairs from peers are refused if given)
+    #[structopt(long, parse(from_os_str))]
+    trusted_keys_file: Option<PathBuf>,
+    /// Cluster secret file of a namespace, instead of the `--cluster-secret-file`
+    /// (E.g. "team-a=/etc/kvs-bgp/team-a.secret"; may be given many times)
+    #[structopt(long = "namespace-cluster-secret-file", number_of_values = 1)]
+    namespace_cluster_secret_files: Vec<NamespaceOption<PathBuf>>,
+    /// Node key file of a namespace, instead of the `--node-key-file` (E.g. "team-a=/etc/kvs-bgp/team-a.key")
+    #[structopt(long = "namespace-node-key-file", number_of_values = 1)]
+    namespace_node_key_files: Vec<NamespaceOption<PathBuf>>,
+    /// Trusted keys file of a namespace, instead of the `--trusted-keys-file` (E.g. "team-a=/etc/kvs-bgp/team-a.keys")
+    #[structopt(long = "namespace-trusted-keys-file", number_of_values = 1)]
+    namespace_trusted_keys_files: Vec<NamespaceOption<PathBuf>>,
+    /// Categories a namespace is subscribed to, instead of the `--subscribe` categories (E.g. "team-a=10,20")
+    #[structopt(long = "namespace-subscribe", number_of_values = 1)]
+    namespace_subscriptions: Vec<NamespaceOption<Categories>>,
     /// Log verbosity (additive [-vv] for debug, trace, etc.)
     #[structopt(short, parse(from_occurrences))]
     pub verbose: u8,
 }
 
+/// A named store, given as `<name>=<address prefix>`
+#[derive(Debug)]
+struct Namespace {
+    name: String,
+    addr_prefix: AddrPrefix,
+}
+
+impl FromStr for Namespace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        let name = parts.next().unwrap_or("").trim();
+        let addr_prefix = parts
+            .next()
+            .ok_or_else(|| format!("Expected <name>=<address prefix>, got {}", s))?;
+        // Used as a path segment in the HTTP API
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "Invalid namespace name {:?} (letters, digits, '-' & '_' only)",
+                name
+            ));
+        }
+        Ok(Self {
+            name: name.to_owned(),
+            addr_prefix: addr_prefix.parse().map_err(|e| format!("{}", e))?,
+        })
+    }
+}
+
+/// An option for one namespace, given as `<name>=<value>`
+#[derive(Debug)]
+struct NamespaceOption<T> {
+    name: String,
+    value: T,
+}
+
+impl<T> NamespaceOption<T> {
+    /// Make sure every option is given for a namespace that exists
+    fn check(options: &[Self], namespaces: &[Namespace]) -> Result<(), String> {
+        match options
+            .iter()
+            .find(|option| !namespaces.iter().any(|ns| ns.name == option.name))
+        {
+            Some(option) => Err(format!(
+                "Option given for unknown namespace {}",
+                option.name
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// The value given for a namespace, if any
+    fn find<'a>(options: &'a [Self], name: &str) -> Option<&'a T> {
+        options
+            .iter()
+            .find(|option| option.name == name)
+            .map(|option| &option.value)
+    }
+}
+
+impl<T> FromStr for NamespaceOption<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        let name = parts.next().unwrap_or("").trim();
+        let value = parts
+            .next()
+            .ok_or_else(|| format!("Expected <namespace>=<value>, got {}", s))?;
+        Ok(Self {
+            name: name.to_owned(),
+            value: value.parse().map_err(|e| format!("{}", e))?,
+        })
+    }
+}
+
+/// Comma separated categories (E.g. "10,20")
+#[derive(Debug)]
+struct Categories(Vec<u16>);
+
+impl FromStr for Categories {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|category| {
+                category
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("Invalid category {:?}", category))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Keys & subscriptions of a store
+struct StoreConfig {
+    cluster_key: Option<ClusterKey>,
+    /// Hex encoded secret of the node key
+    node_key: Option<String>,
+    trusted_keys: Option<Vec<PublicKey>>,
+    subscriptions: Vec<u16>,
+}
+
+/// Read a cluster secret file, for the key to encrypt values with
+fn read_cluster_key(path: &Path) -> Result<ClusterKey, Box<dyn Error>> {
+    let secret = fs::read_to_string(path)?;
+    info!("Encrypting values with the cluster secret in {:?}", path);
+    Ok(ClusterKey::from_secret(secret.trim().as_bytes()))
+}
+
+/// Read a node key file, checking it holds a valid key
+fn read_node_key(path: &Path) -> Result<String, Box<dyn Error>> {
+    let node_key = fs::read_to_string(path)?;
+    let public_key = NodeKey::from_hex(&node_key)?.public_key();
+    info!("Signing pairs with public key {}", public_key);
+    Ok(node_key)
+}
+
+/// Read a trusted keys file, with one hex encoded public key per line (and `#` for comments)
+fn read_trusted_keys(path: &Path) -> Result<Vec<PublicKey>, Box<dyn Error>> {
+    let trusted_keys = fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.parse())
+        .collect::<Result<Vec<PublicKey>, _>>()?;
+    info!(
+        "Only accepting pairs signed by {} trusted keys in {:?}",
+        trusted_keys.len(),
+        path
+    );
+    Ok(trusted_keys)
+}
+
+/// Open a store (restoring any persisted KeyValues from the data directory), configured for this node
+fn open_store(
+    data_dir: Option<PathBuf>,
+    snapshot_interval: usize,
+    origin_id: Option<u32>,
+    addr_prefix: AddrPrefix,
+    config: &StoreConfig,
+) -> Result<KvStore, Box<dyn Error>> {
+    let mut kv_store = if let Some(data_dir) = &data_dir {
+        info!("Using data directory {:?}", data_dir);
+        KvStore::open(data_dir, snapshot_interval)?
+    } else {
+        KvStore::new()
+    };
+    if let Some(origin_id) = origin_id {
+        kv_store.set_origin(origin_id);
+    }
+    kv_store.set_addr_prefix(addr_prefix);
+    kv_store.set_cluster_key(config.cluster_key.clone());
+    if let Some(node_key) = &config.node_key {
+        kv_store.set_node_key(Some(NodeKey::from_hex(node_key)?));
+    }
+    kv_store.set_trusted_keys(config.trusted_keys.clone());
+    kv_store.set_subscriptions(config.subscriptions.clone());
+    Ok(kv_store)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::from_args();
@@ -54,14 +301,149 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .init();
     info!("Logging at levels {}/{}", kvs_level, other_level);
 
-    let kv_store = Arc::new(RwLock::new(KvStore::new()));
+    let config = StoreConfig {
+        cluster_key: args
+            .cluster_secret_file
+            .as_deref()
+            .map(read_cluster_key)
+            .transpose()?,
+        node_key: args
+            .node_key_file
+            .as_deref()
+            .map(read_node_key)
+            .transpose()?,
+        trusted_keys: args
+            .trusted_keys_file
+            .as_deref()
+            .map(read_trusted_keys)
+            .transpose()?,
+        subscriptions: args.subscribe.clone(),
+    };
+    if !config.subscriptions.is_empty() {
+        info!("Subscribed to categories {:?}", config.subscriptions);
+    }
+
+    // Restore any persisted KeyValues before starting the HTTP API & BGP sessions.
+    // The default store is persisted in its own directory, next to those of the namespaces
+    let data_dir = match &args.data_dir {
+        Some(data_dir) => {
+            let default_dir = data_dir.join("default");
+            if Persistence::relocate(data_dir, &default_dir)? {
+                info!("Moved the default store into {:?}", default_dir);
+            }
+            Some(default_dir)
+        }
+        None => None,
+    };
+    let kv_store = open_store(
+        data_dir,
+        args.snapshot_interval,
+        args.origin_id,
+        args.addr_prefix,
+        &config,
+    )?;
+    // Every store writes with the same origin id
+    let origin_id = kv_store.origin();
+    info!("Using origin id {}", origin_id);
+    info!("Using address prefix {}", args.addr_prefix);
+    let kv_store = Arc::new(RwLock::new(kv_store));
+
+    // Each namespace is a separate store, persisted in its own directory & synchronized with its own address prefix,
+    // keys & subscriptions (those of the default store unless given for the namespace)
+    NamespaceOption::check(&args.namespace_cluster_secret_files, &args.namespaces)?;
+    NamespaceOption::check(&args.namespace_node_key_files, &args.namespaces)?;
+    NamespaceOption::check(&args.namespace_trusted_keys_files, &args.namespaces)?;
+    NamespaceOption::check(&args.namespace_subscriptions, &args.namespaces)?;
+    let mut addr_prefixes: Vec<AddrPrefix> = vec![args.addr_prefix];
+    let mut namespaces: BTreeMap<String, Arc<RwLock<KvStore>>> = BTreeMap::new();
+    for namespace in &args.namespaces {
+        if namespaces.contains_key(&namespace.name) {
+            return Err(format!("Namespace {} is given more than once", namespace.name).into());
+        }
+        if addr_prefixes
+            .iter()
+            .any(|addr_prefix| addr_prefix.overlaps(&namespace.addr_prefix))
+        {
+            return Err(format!(
+                "Address prefix {} of namespace {} is already used",
+                namespace.addr_prefix, namespace.name
+            )
+            .into());
+        }
+        addr_prefixes.push(namespace.addr_prefix);
+        let data_dir = args
+            .data_dir
+            .as_ref()
+            .map(|data_dir| data_dir.join("ns").join(&namespace.name));
+        let name = &namespace.name;
+        let ns_config = StoreConfig {
+            cluster_key: match NamespaceOption::find(&args.namespace_cluster_secret_files, name) {
+                Some(path) => Some(read_cluster_key(path)?),
+                None => config.cluster_key.clone(),
+            },
+            node_key: match NamespaceOption::find(&args.namespace_node_key_files, name) {
+                Some(path) => Some(read_node_key(path)?),
+                None => config.node_key.clone(),
+            },
+            trusted_keys: match NamespaceOption::find(&args.namespace_trusted_keys_files, name) {
+                Some(path) => Some(read_trusted_keys(path)?),
+                None => config.trusted_keys.clone(),
+            },
+            subscriptions: match NamespaceOption::find(&args.namespace_subscriptions, name) {
+                Some(categories) => categories.0.clone(),
+                None => config.subscriptions.clone(),
+            },
+        };
+        let ns_store = open_store(
+            data_dir,
+            args.snapshot_interval,
+            Some(origin_id),
+            namespace.addr_prefix,
+            &ns_config,
+        )?;
+        info!(
+            "Serving namespace {} with address prefix {}",
+            namespace.name, namespace.addr_prefix
+        );
+        namespaces.insert(namespace.name.clone(), Arc::new(RwLock::new(ns_store)));
+    }
     let (outbound_tx, outbound_rx) = mpsc::unbounded_channel();
 
     let mut bgp_server =
         BgpPeerings::from_config(&args.config_path, args.bgp_address, args.bgp_port).await?;
+    bgp_server.reassembly_limits = ReassemblyLimits {
+        timeout: Duration::from_secs(args.reassembly_timeout),
+        max_pending: args.reassembly_max_pending,
+        max_bytes: args.reassembly_max_bytes,
+    };
+
+    // Start the Redis API server (if enabled) in a thread, updating the default KvStore
+    if let Some(resp_port) = args.resp_port {
+        let resp_addr = SocketAddr::from((args.resp_address, resp_port));
+        let resp_store = kv_store.clone();
+        let resp_tx = outbound_tx.clone();
+        tokio::spawn(async move {
+            if let Err(err) = resp::serve(resp_addr, resp_store, resp_tx).await {
+                error!("RESP API failed: {}", err);
+            }
+        });
+    }
+
+    // Re-announce the pairs this node wrote before restarting, since peers dropped their routes
+    // with the previous sessions. Then remove expired pairs of every store in a thread,
+    // withdrawing the pairs written by this node
+    let kv_stores: Vec<_> = std::iter::once(kv_store.clone())
+        .chain(namespaces.values().cloned())
+        .collect();
+    for kv_store in &kv_stores {
+        if let Some(update) = kv_store.read().await.announce_own()? {
+            outbound_tx.send(update)?;
+        }
+        tokio::spawn(store::expire_pairs(kv_store.clone(), outbound_tx.clone()));
+    }
 
-    // Start the HTTP API server in a thread, updating the KvStore
-    let api_routes = api::get_routes(kv_store.clone(), outbound_tx);
+    // Start the HTTP API server in a thread, updating the KvStores
+    let api_routes = api::get_routes(kv_store.clone(), namespaces, outbound_tx);
     tokio::spawn(async move {
         info!(
             "Starting HTTP API on {}:{}",
@@ -73,7 +455,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
     });
 
     // Run the BGP daemon
-    // Injecting inbound updates into KvStore and outbound updates to peers
-    bgp_server.run(kv_store, outbound_rx).await?;
+    // Injecting inbound updates into the KvStores and outbound updates to peers.
+    // Every store shares the sessions of the one bgpd config, as peers aren't configured per namespace
+    bgp_server.run(kv_stores, outbound_rx).await?;
     Ok(())
 }
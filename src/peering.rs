@@ -3,11 +3,14 @@
 //! Uses [bgpd-rs](https://github.com/thepacketgeek/bgpd-rs) for session management
 //! and RIB storage of pending updates
 
-use std::collections::HashMap;
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::error::Error;
-use std::net::{IpAddr, SocketAddr};
+use std::iter::once;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use bgp_rs::{MPUnreachNLRI, NLRIEncoding, PathAttribute, AFI, SAFI};
 use bgpd::{
@@ -15,24 +18,34 @@ use bgpd::{
     rib::{Family, RIB},
     session::{SessionManager, SessionUpdate},
 };
-use log::{debug, trace};
+use log::{debug, error, info, trace, warn};
 use tokio::{
     self,
     net::TcpListener,
     sync::{mpsc, watch, RwLock},
+    task, time,
 };
 
 use crate::{
-    kv::{KeyValue, Route, RouteCollection},
+    crypto::ClusterKey,
+    kv::{AddrPrefix, KeyValue, NextHop, Payload, PeerRoute, Route, RouteCollection},
+    reassembly::{BatchAssembler, Reassembler, ReassemblyLimits},
     store::{KvStore, Update as KvUpdate},
+    KvsError,
 };
 
+/// How long after the first route is learned from peers that they have to announce the pairs of other nodes
+/// restored from disk, before those pairs are removed
+const RESTORE_GRACE: Duration = Duration::from_secs(120);
+
 /// Struct for interacting with BGP Peers
 ///
 /// Keeps sessions and an RIB for storing inbound/outbound updates for `KeyValue` pair routes
 pub struct BgpPeerings {
     pub sessions: Arc<RwLock<SessionManager>>,
     pub rib: Arc<RwLock<RIB>>,
+    /// Limits for buffering partially received `KeyValue` pairs from peers
+    pub reassembly_limits: ReassemblyLimits,
 }
 
 impl BgpPeerings {
@@ -46,6 +59,7 @@ impl BgpPeerings {
         Ok(Self {
             sessions: Arc::new(RwLock::new(manager)),
             rib: Arc::new(RwLock::new(RIB::new())),
+            reassembly_limits: ReassemblyLimits::default(),
         })
     }
 
@@ -68,74 +82,430 @@ impl BgpPeerings {
 
     /// Process BGP sessions & updates, listening for KvStore updates from the HTTP API and
     /// announcing routes out to peers
+    ///
+    /// Each `KvStore` is synchronized under its own [AddrPrefix](../kv/struct.AddrPrefix.html) over every session
+    /// (there's no per-store peer config), and routes learned for any other prefix are ignored. Values from peers are decrypted with the
+    /// cluster key of their store, and only pairs tagged with the store's subscribed categories are accepted. Routes are announced to peers in the
+    /// family they're encoded in (IPv6 or IPv4 Unicast), so peers only get the routes they negotiated.
     pub async fn run(
         &mut self,
-        kv_store: Arc<RwLock<KvStore>>,
+        kv_stores: Vec<Arc<RwLock<KvStore>>>,
         mut outbound_updates: mpsc::UnboundedReceiver<KvUpdate>,
     ) -> Result<(), Box<dyn Error>> {
-        // BGP Updates from peers may come in multiple messages
-        // Keep any routes that have come in a HashMap, keyed by file hash
-        // and only decode once all messages for a KeyValue version are received
-        let mut pending_routes: HashMap<u64, Vec<Route>> = HashMap::new();
+        let mut stores: Vec<PeeredStore> = Vec::with_capacity(kv_stores.len());
+        for kv_store in kv_stores {
+            let peered = PeeredStore::new(kv_store, self.reassembly_limits).await;
+            if stores.iter().any(|other| other.addr_prefix.overlaps(&peered.addr_prefix)) {
+                return Err(KvsError::InvalidPrefix(format!(
+                    "{} is used by more than one store",
+                    peered.addr_prefix
+                ))
+                .into());
+            }
+            stores.push(peered);
+        }
+        let addr_prefixes: Vec<AddrPrefix> = stores.iter().map(|peered| peered.addr_prefix).collect();
+        // Periodically drop partial `KeyValue`s that peers never finished sending
+        let mut expiry = time::interval(max(
+            self.reassembly_limits.timeout / 2,
+            Duration::from_secs(1),
+        ));
+        // Peers aren't required to send a NextHop with withdrawals, so remember the NextHop
+        // of each prefix learned from each peer to identify the withdrawn `KeyValue`
+        let mut learned_next_hops: HashMap<(Ipv6Addr, IpAddr), NextHop> = HashMap::new();
+        // Count of received `KeyValue`s dropped for not matching their checksum
+        let mut corrupted: u64 = 0;
+        // When to remove the pairs of other nodes restored from disk that peers haven't announced again
+        // (set once the first route is learned, and cleared once they're removed)
+        let mut restore_deadline: Option<Instant> = None;
+        let mut restore_reconciled = false;
+        // Peers learn whether this node is still reachable (for its ephemeral pairs) from its liveness route
+        // (announced in both families when the prefix has an IPv4 octet, for peers that only carry IPv4)
+        for peered in &stores {
+            let origin = peered.store.read().await.origin();
+            let routes = once(Route::liveness(peered.addr_prefix, origin))
+                .chain(Route::ipv4_liveness(peered.addr_prefix, origin));
+            for route in routes {
+                let (afi, mask) = family(&route);
+                self.rib.write().await.insert_from_api(
+                    Family::new(afi, SAFI::Unicast),
+                    vec![PathAttribute::NEXT_HOP((&route.next_hop).into())],
+                    NLRIEncoding::IP(((&route.prefix).into(), mask).into()),
+                );
+            }
+        }
 
         loop {
             let mut sessions = self.sessions.write().await;
             tokio::select! {
-                update = sessions.get_update(self.rib.clone()) => {
-                    if let Ok(Some(SessionUpdate::Learned((_, update)))) = update {
-                        if let Ok(route) = TryInto::<Route>::try_into(&update) {
-                            let hash = route.hash();
-                            let kv_length = route.collection_length();
-                            trace!("Bgp update: {} {:?}", hash, route);
-                            let routes = pending_routes.entry(hash).or_insert_with(|| vec![]);
-                            routes.push(route);
-                            trace!("Bgp update: {} [{}/{}]", hash, routes.len(), kv_length);
-
-                            if routes.len() == kv_length {
-                                let full_routes = pending_routes.remove(&hash).expect("Hash is in map");
-                                let collection = RouteCollection::from_routes(full_routes);
-                                if let Ok(kv) = TryInto::<KeyValue<String, String>>::try_into(&collection) {
-                                    kv_store.write().await.insert_from_peer(kv);
+                update = sessions.get_update(self.rib.clone()) => match update {
+                    Ok(Some(SessionUpdate::Learned((peer, update)))) => {
+                        if !restore_reconciled && restore_deadline.is_none() {
+                            restore_deadline = Some(Instant::now() + RESTORE_GRACE);
+                        }
+                        match TryInto::<PeerRoute>::try_into(&update) {
+                            Ok(PeerRoute::Announced(mut route, communities)) => {
+                                trace!("Bgp update: {} {:?}", route.hash(), route);
+                                let peered = match stores.iter_mut().find(|peered| route.has_valid_prefix(peered.addr_prefix)) {
+                                    Some(peered) => peered,
+                                    None => {
+                                        trace!("Ignoring route of another prefix: {:?}", route.prefix);
+                                        continue;
+                                    }
+                                };
+                                route.set_communities(&communities, peered.addr_prefix);
+                                learned_next_hops.insert((*route.prefix.as_ref(), peer), route.next_hop.clone());
+                                if let Some(origin) = route.liveness_origin() {
+                                    trace!("Origin {:x} reachable via {}", origin, peer);
+                                    peered.origin_sessions.entry(origin).or_default().insert(peer);
+                                    continue;
+                                }
+                                if let Some(collection) = peered.announcements.insert(route) {
+                                    if let Some(kv) = decode(&collection, peer, peered.addr_prefix, peered.cluster_key.as_ref(), &mut corrupted) {
+                                        // Subscriptions apply to assembled batches, as every pair counts towards completing its batch
+                                        for batch in peered.batches.insert(kv) {
+                                            store_from_peer(&peered.store, peered.subscribed(batch)).await;
+                                        }
+                                        self.remove_from_peer(&peered.store, peered.batches.take_withdraws(), &addr_prefixes).await;
+                                    }
                                 }
                             }
+                            Ok(PeerRoute::Withdrawn(prefix, next_hop)) => {
+                                let peered = match stores.iter_mut().find(|peered| peered.addr_prefix.matches(prefix.as_ref())) {
+                                    Some(peered) => peered,
+                                    None => {
+                                        trace!("Ignoring withdraw of another prefix: {:?}", prefix);
+                                        continue;
+                                    }
+                                };
+                                let learned = learned_next_hops.remove(&(*prefix.as_ref(), peer));
+                                let next_hop = match next_hop.or(learned) {
+                                    Some(next_hop) => next_hop,
+                                    None => {
+                                        trace!("Bgp withdraw for unknown prefix: {:?}", prefix);
+                                        continue;
+                                    }
+                                };
+                                let route = Route { prefix, next_hop, categories: vec![] };
+                                // IPv4 liveness routes carry part of the origin id in their NextHop
+                                if let Some(origin) = route.liveness_origin() {
+                                    let sessions = peered.origin_sessions.get_mut(&origin);
+                                    if sessions.map(|sessions| sessions.remove(&peer) && sessions.is_empty()) == Some(true) {
+                                        peered.origin_sessions.remove(&origin);
+                                        peered.remove_ephemeral(origin, &mut learned_next_hops).await;
+                                    }
+                                    continue;
+                                }
+                                trace!("Bgp withdraw: {} {:?}", route.hash(), route);
+                                peered.announcements.discard(&route);
+                                if let Some(collection) = peered.withdrawals.insert(route) {
+                                    // Old versions of pairs in a pending batch are only replaced once the whole batch is received
+                                    let kv = decode(&collection, peer, peered.addr_prefix, peered.cluster_key.as_ref(), &mut corrupted)
+                                        .and_then(|kv| peered.batches.hold_withdraw(kv));
+                                    if let Some(kv) = kv {
+                                        self.remove_from_peer(&peered.store, vec![kv], &addr_prefixes).await;
+                                    }
+                                }
+                            }
+                            Err(_) => (),
                         }
                     }
-                },
-                outbound_update = outbound_updates.recv() => {
-                    if let Some(update) = outbound_update {
-                        // New/updated `KeyValue` pairs need to be announced to peers
-                        if let Some(announce) = update.announce {
-                            for route in announce.iter() {
-                                self.rib.write().await.insert_from_api(
-                                    Family::new(AFI::IPV6, SAFI::Unicast),
-                                    vec![
-                                        PathAttribute::NEXT_HOP((&route.next_hop).into()),
-                                    ],
-                                    NLRIEncoding::IP(((&route.prefix).into(), 128).into()),
-                                );
+                    Ok(Some(SessionUpdate::Ended(peers))) => {
+                        // Routes of ended sessions are gone, so their NextHops are no longer needed
+                        learned_next_hops.retain(|(_, learned_from), _| !peers.contains(learned_from));
+                        // Ephemeral pairs are removed once the node that wrote them isn't reachable via any session
+                        for peered in stores.iter_mut() {
+                            for sessions in peered.origin_sessions.values_mut() {
+                                for peer in &peers {
+                                    sessions.remove(peer);
+                                }
+                            }
+                            let orphaned: Vec<u32> = peered.origin_sessions
+                                .iter()
+                                .filter(|(_, sessions)| sessions.is_empty())
+                                .map(|(origin, _)| *origin)
+                                .collect();
+                            for origin in orphaned {
+                                peered.origin_sessions.remove(&origin);
+                                peered.remove_ephemeral(origin, &mut learned_next_hops).await;
                             }
                         }
-                        if let Some(withdraw) = update.withdraw {
-                            for route in withdraw.iter() {
-                                self.rib.write().await.insert_from_api(
-                                    Family::new(AFI::IPV6, SAFI::Unicast),
-                                    vec![
-                                        PathAttribute::MP_UNREACH_NLRI(MPUnreachNLRI {
-                                            afi: AFI::IPV6,
-                                            safi: SAFI::Unicast,
-                                            withdrawn_routes: vec![
-                                                NLRIEncoding::IP(((&route.prefix).into(), 128).into()),
-                                            ],
-                                        }),
-                                        PathAttribute::NEXT_HOP((&route.next_hop).into()),
-                                    ],
-                                    NLRIEncoding::IP(((&route.prefix).into(), 128).into()),
-                                );
+                    }
+                    _ => (),
+                },
+                now = expiry.tick() => {
+                    if restore_deadline.map_or(false, |deadline| now.into_std() >= deadline) {
+                        restore_deadline = None;
+                        restore_reconciled = true;
+                        for peered in &stores {
+                            let mut store = peered.store.write().await;
+                            match task::block_in_place(|| store.remove_provisional()) {
+                                Ok(removed) if !removed.is_empty() => info!(
+                                    "Removed {} restored KeyValues of {} that peers no longer have",
+                                    removed.len(),
+                                    peered.addr_prefix
+                                ),
+                                Ok(_) => (),
+                                Err(err) => error!("Could not remove restored KeyValues: {}", err),
                             }
                         }
                     }
+                    for peered in stores.iter_mut() {
+                        let batches = peered.batches.expire(now.into_std());
+                        // Withdraws held for dropped batches still apply
+                        self.remove_from_peer(&peered.store, peered.batches.take_withdraws(), &addr_prefixes).await;
+                        let expired = peered.announcements.expire(now.into_std()) + peered.withdrawals.expire(now.into_std());
+                        if expired + batches > 0 {
+                            info!(
+                                "Expired {} partial KeyValues & {} partial batches of {} (announcements: {:?}, withdrawals: {:?})",
+                                expired,
+                                batches,
+                                peered.addr_prefix,
+                                peered.announcements.stats(),
+                                peered.withdrawals.stats(),
+                            );
+                        }
+                    }
+                },
+                outbound_update = outbound_updates.recv() => {
+                    if let Some(update) = outbound_update {
+                        self.advertise(update, &addr_prefixes).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Remove `KeyValue` pairs withdrawn by a peer from a `KvStore`
+    ///
+    /// Withdraws of this node's own pairs (removed on another node) are advertised, so their routes are withdrawn too
+    async fn remove_from_peer(
+        &self,
+        kv_store: &Arc<RwLock<KvStore>>,
+        withdraws: Vec<KeyValue<String, Payload>>,
+        addr_prefixes: &[AddrPrefix],
+    ) {
+        if withdraws.is_empty() {
+            return;
+        }
+        let mut store = kv_store.write().await;
+        for kv in withdraws {
+            match task::block_in_place(|| store.remove_from_peer(kv)) {
+                Ok(None) => (),
+                Ok(Some(update)) => self.advertise(update, addr_prefixes).await,
+                Err(KvsError::Untrusted(reason)) => warn!("Refusing withdraw from peer: {}", reason),
+                Err(err) => error!("Could not remove KeyValue from peer: {}", err),
+            }
+        }
+    }
+
+    /// Insert the routes of a `KvStore` update into the RIB, to be announced/withdrawn to peers
+    ///
+    /// Routes carrying categories are announced with communities under the [AddrPrefix](../kv/struct.AddrPrefix.html)
+    /// of their store (one of `addr_prefixes`)
+    async fn advertise(&self, update: KvUpdate, addr_prefixes: &[AddrPrefix]) {
+        // New/updated `KeyValue` pairs need to be announced to peers
+        if let Some(announce) = update.announce {
+            for route in announce.iter() {
+                let (afi, mask) = family(route);
+                let mut attributes = vec![PathAttribute::NEXT_HOP((&route.next_hop).into())];
+                // Categories are advertised as communities (under the prefix of the route's store)
+                // for peers (and BGP policy) to filter on
+                if !route.categories.is_empty() {
+                    if let Some(addr_prefix) = addr_prefixes
+                        .iter()
+                        .find(|addr_prefix| route.has_valid_prefix(**addr_prefix))
+                    {
+                        attributes.push(PathAttribute::COMMUNITY(route.communities(*addr_prefix)));
+                    }
                 }
+                self.rib.write().await.insert_from_api(
+                    Family::new(afi, SAFI::Unicast),
+                    attributes,
+                    NLRIEncoding::IP(((&route.prefix).into(), mask).into()),
+                );
             }
         }
+        if let Some(withdraw) = update.withdraw {
+            for route in withdraw.iter() {
+                let (afi, mask) = family(route);
+                self.rib.write().await.insert_from_api(
+                    Family::new(afi, SAFI::Unicast),
+                    vec![
+                        PathAttribute::MP_UNREACH_NLRI(MPUnreachNLRI {
+                            afi,
+                            safi: SAFI::Unicast,
+                            withdrawn_routes: vec![NLRIEncoding::IP(
+                                ((&route.prefix).into(), mask).into(),
+                            )],
+                        }),
+                        PathAttribute::NEXT_HOP((&route.next_hop).into()),
+                    ],
+                    NLRIEncoding::IP(((&route.prefix).into(), mask).into()),
+                );
+            }
+        }
+    }
+}
+
+/// A `KvStore` synchronized with peers under its own [AddrPrefix](../kv/struct.AddrPrefix.html),
+/// along with the routes being received for it
+struct PeeredStore {
+    store: Arc<RwLock<KvStore>>,
+    addr_prefix: AddrPrefix,
+    /// Key to decrypt values from peers with (encrypted values can't be read without it)
+    cluster_key: Option<ClusterKey>,
+    /// Categories of `KeyValue` pairs to accept from peers (all pairs are accepted if empty)
+    subscriptions: Vec<u16>,
+    /// BGP Updates from peers may come in multiple messages
+    /// Buffer any routes that have come in, per key hash & version,
+    /// and only decode once all messages for a KeyValue version are received
+    announcements: Reassembler,
+    /// Withdrawals are reassembled the same way so the withdrawn
+    /// `KeyValue` can be decoded and removed from the store
+    withdrawals: Reassembler,
+    /// Pairs written in a batch are only stored once the whole batch has been received
+    /// (and withdraws of their old versions held until then)
+    batches: BatchAssembler,
+    /// Peers the liveness route of each origin has been learned from, so its ephemeral
+    /// `KeyValue`s can be removed once it isn't reachable via any of them
+    origin_sessions: HashMap<u32, HashSet<IpAddr>>,
+}
+
+impl PeeredStore {
+    /// Start synchronizing a store, with its prefix, cluster key & subscriptions as currently set
+    async fn new(store: Arc<RwLock<KvStore>>, limits: ReassemblyLimits) -> Self {
+        let (addr_prefix, cluster_key, subscriptions) = {
+            let kv_store = store.read().await;
+            (
+                kv_store.addr_prefix(),
+                kv_store.cluster_key().cloned(),
+                kv_store.subscriptions().to_vec(),
+            )
+        };
+        Self {
+            store,
+            addr_prefix,
+            cluster_key,
+            subscriptions,
+            announcements: Reassembler::with_limits(limits),
+            withdrawals: Reassembler::with_limits(limits),
+            batches: BatchAssembler::with_limits(limits),
+            origin_sessions: HashMap::new(),
+        }
+    }
+
+    /// The pairs of an assembled batch from a peer to accept, given the categories subscribed to
+    fn subscribed(&self, pairs: Vec<KeyValue<String, Payload>>) -> Vec<KeyValue<String, Payload>> {
+        if self.subscriptions.is_empty() {
+            return pairs;
+        }
+        pairs
+            .into_iter()
+            .filter(|kv| {
+                let subscribed = kv
+                    .categories()
+                    .iter()
+                    .any(|category| self.subscriptions.contains(category));
+                if !subscribed {
+                    trace!("Ignoring unsubscribed categories: {:?}", kv.categories());
+                }
+                subscribed
+            })
+            .collect()
+    }
+
+    /// Remove the ephemeral `KeyValue`s of an unreachable origin, along with any of their routes still buffered
+    async fn remove_ephemeral(
+        &mut self,
+        origin: u32,
+        learned_next_hops: &mut HashMap<(Ipv6Addr, IpAddr), NextHop>,
+    ) {
+        let mut store = self.store.write().await;
+        let removed = match task::block_in_place(|| store.remove_ephemeral(origin)) {
+            Ok(removed) => removed,
+            Err(err) => {
+                error!("Could not remove ephemeral KeyValues: {}", err);
+                return;
+            }
+        };
+        if removed.is_empty() {
+            return;
+        }
+        info!("Removed {} ephemeral KeyValues of origin {:x}", removed.len(), origin);
+        let hashes: HashSet<u64> = removed.iter().map(|kv| kv.key_hash()).collect();
+        // IPv4 routes carry the origin tag & slot of the pair instead
+        let ipv4_ids: HashSet<u64> = removed
+            .iter()
+            .filter_map(|kv| kv.ipv4_id())
+            .map(|id| id as u64)
+            .collect();
+        for hash in hashes.iter().chain(&ipv4_ids) {
+            self.announcements.remove_key(*hash);
+            self.withdrawals.remove_key(*hash);
+        }
+        learned_next_hops.retain(|(prefix, _), next_hop| {
+            let route = Route::from_addrs(*prefix, *next_hop.as_ref());
+            if route.liveness_origin().is_some() {
+                return true;
+            }
+            let hashes = if route.is_ipv4() { &ipv4_ids } else { &hashes };
+            !hashes.contains(&route.hash())
+        });
+    }
+}
+
+/// Address family & prefix length to announce a route with
+fn family(route: &Route) -> (AFI, u8) {
+    if route.is_ipv4() {
+        (AFI::IPV4, 32)
+    } else {
+        (AFI::IPV6, 128)
+    }
+}
+
+/// Store the `KeyValue` pairs of a batch from a peer, all under one lock so they're visible together
+async fn store_from_peer(kv_store: &Arc<RwLock<KvStore>>, pairs: Vec<KeyValue<String, Payload>>) {
+    let mut store = kv_store.write().await;
+    for kv in pairs {
+        // Writes block on syncing to disk (if the store is persistent)
+        match task::block_in_place(|| store.insert_from_peer(kv)) {
+            Ok(()) => (),
+            Err(KvsError::Untrusted(reason)) | Err(KvsError::InvalidVersion(reason)) => {
+                warn!("Refusing KeyValue from peer: {}", reason)
+            }
+            Err(err) => error!("Could not store KeyValue from peer: {}", err),
+        }
+    }
+}
+
+/// Decode a fully received `KeyValue` from a peer, decrypting its value with the cluster key (if any)
+///
+/// Collections that don't match their checksum (corrupted or forged routes) are logged & counted
+fn decode(
+    collection: &RouteCollection,
+    peer: IpAddr,
+    addr_prefix: AddrPrefix,
+    cluster_key: Option<&ClusterKey>,
+    corrupted: &mut u64,
+) -> Option<KeyValue<String, Payload>> {
+    match KeyValue::decode(collection, addr_prefix, cluster_key) {
+        Ok(kv) => Some(kv),
+        Err(KvsError::ChecksumMismatch(reason)) => {
+            *corrupted += 1;
+            warn!(
+                "Dropping corrupted KeyValue from {} ({} total): {}",
+                peer, corrupted, reason
+            );
+            None
+        }
+        Err(KvsError::DecryptionError(reason)) | Err(KvsError::SignatureError(reason)) => {
+            warn!("Dropping KeyValue from {}: {}", peer, reason);
+            None
+        }
+        Err(err) => {
+            debug!("Could not decode KeyValue from {}: {}", peer, err);
+            None
+        }
     }
 }
@@ -0,0 +1,500 @@
+//! On-disk persistence for a [KvStore](struct.KvStore.html)
+//!
+//! Every change to the store is appended to a write-ahead log (and synced to disk) as it happens,
+//! and the whole store is periodically written to a snapshot (after which the log is truncated).
+//! On startup the snapshot is loaded and the log replayed on top of it to restore the store.
+//!
+//! Files in the data directory:
+//! - `snapshot.bin`: All pairs in the store, [bincode](https://github.com/servo/bincode) serialized
+//! - `wal.log`: Log entries since the last snapshot, each as a 32-bit (big endian) length & CRC32
+//!   checksum followed by the bincode serialized [LogEntry](enum.LogEntry.html)
+//! - `origin`: Origin id of the node writing to the store, so it's kept across restarts
+//!
+//! Only the last entry of the log may be incomplete (E.g. from a crash while it was written), and is
+//! dropped. A corrupt entry followed by more entries fails to open, rather than losing the entries after it
+
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Version;
+use crate::crypto::{PublicKey, NONCE_SIZE};
+use crate::kv::{KeyValue, Payload};
+use crate::KvsError;
+
+const SNAPSHOT_FILE: &str = "snapshot.bin";
+const LOG_FILE: &str = "wal.log";
+const ORIGIN_FILE: &str = "origin";
+/// Bytes of the length & checksum preceding each log entry
+const ENTRY_HEADER_SIZE: usize = 8;
+
+/// A [KeyValue](struct.KeyValue.html) pair as written to disk
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredPair {
+    pub key: String,
+    pub value: Payload,
+    pub version: Version,
+    pub categories: Vec<u16>,
+    pub expires: Option<u64>,
+    pub ephemeral: bool,
+    pub batch_size: u16,
+    /// Nonce the value is encrypted with on the wire, so routes of the pair can still be withdrawn after a restart
+    pub nonce: [u8; NONCE_SIZE],
+    /// Key of the node that signed the pair, so withdraws of it can be checked after a restart
+    pub signer: Option<PublicKey>,
+    /// Slot of the pair's IPv4 routes, so they can still be withdrawn after a restart
+    pub ipv4_slot: Option<u8>,
+}
+
+impl From<&KeyValue<String, Payload>> for StoredPair {
+    fn from(kv: &KeyValue<String, Payload>) -> Self {
+        Self {
+            key: kv.key().clone(),
+            value: kv.as_ref().clone(),
+            version: kv.version(),
+            categories: kv.categories().to_vec(),
+            expires: kv.expires(),
+            ephemeral: kv.is_ephemeral(),
+            batch_size: kv.batch_size(),
+            nonce: *kv.nonce(),
+            signer: kv.signer().copied(),
+            ipv4_slot: kv.ipv4_slot(),
+        }
+    }
+}
+
+impl From<StoredPair> for KeyValue<String, Payload> {
+    fn from(pair: StoredPair) -> Self {
+        let mut kv = KeyValue::with_version(pair.key, pair.value, pair.version);
+        kv.set_categories(pair.categories);
+        kv.set_expires(pair.expires);
+        kv.set_ephemeral(pair.ephemeral);
+        kv.set_batch_size(pair.batch_size);
+        kv.set_nonce(pair.nonce);
+        kv.set_signer(pair.signer);
+        kv.set_ipv4_slot(pair.ipv4_slot);
+        kv
+    }
+}
+
+/// A change to the [KvStore](struct.KvStore.html), appended to the write-ahead log
+#[derive(Debug, Serialize, Deserialize)]
+pub enum LogEntry {
+    /// A pair was inserted/updated
+    Insert(StoredPair),
+    /// A batch of pairs was inserted/updated (and other keys removed) together, so is restored all or nothing
+    Batch {
+        pairs: Vec<StoredPair>,
+        removed: Vec<String>,
+    },
+    /// A pair was removed by key
+    Remove(String),
+}
+
+/// Write-ahead log & snapshots for a [KvStore](struct.KvStore.html) in a data directory
+#[derive(Debug)]
+pub struct Persistence {
+    dir: PathBuf,
+    log: BufWriter<File>,
+    /// Entries appended to the log since the last snapshot
+    entries: usize,
+    /// Number of log entries to allow before taking a new snapshot
+    snapshot_interval: usize,
+    /// Origin id written by a previous run (if any)
+    origin: Option<u32>,
+}
+
+impl Persistence {
+    /// Open (or create) the data directory
+    ///
+    /// Returns the persistence along with all entries needed to restore the store
+    /// (snapshot pairs as inserts, followed by the log entries)
+    pub fn open<P: AsRef<Path>>(
+        dir: P,
+        snapshot_interval: usize,
+    ) -> Result<(Self, Vec<LogEntry>), KvsError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(|e| storage_error(&dir, e))?;
+
+        let mut entries: Vec<LogEntry> = vec![];
+        let snapshot_path = dir.join(SNAPSHOT_FILE);
+        if snapshot_path.exists() {
+            let bytes = fs::read(&snapshot_path).map_err(|e| storage_error(&snapshot_path, e))?;
+            let pairs: Vec<StoredPair> = bincode::deserialize(&bytes).map_err(|e| {
+                KvsError::StorageError(format!("Corrupt snapshot {:?}: {}", snapshot_path, e))
+            })?;
+            debug!("Loaded {} pairs from {:?}", pairs.len(), snapshot_path);
+            entries.extend(pairs.into_iter().map(LogEntry::Insert));
+        }
+
+        let log_path = dir.join(LOG_FILE);
+        let mut replayed = 0;
+        let mut valid_length = None;
+        if log_path.exists() {
+            let bytes = fs::read(&log_path).map_err(|e| storage_error(&log_path, e))?;
+            let mut remaining = &bytes[..];
+            while !remaining.is_empty() {
+                match read_entry(remaining) {
+                    Ok((entry, rest)) => {
+                        entries.push(entry);
+                        remaining = rest;
+                        replayed += 1;
+                    }
+                    Err(EntryError::Incomplete) | Err(EntryError::Corrupt { last: true }) => {
+                        // Most likely a partially written entry from a crash
+                        warn!(
+                            "Ignoring {} trailing bytes of {:?}",
+                            remaining.len(),
+                            log_path
+                        );
+                        valid_length.replace((bytes.len() - remaining.len()) as u64);
+                        break;
+                    }
+                    Err(EntryError::Corrupt { last: false }) => {
+                        return Err(KvsError::StorageError(format!(
+                            "Corrupt entry {} at byte {} of {:?}, followed by {} more bytes",
+                            replayed + 1,
+                            bytes.len() - remaining.len(),
+                            log_path,
+                            remaining.len()
+                        )));
+                    }
+                }
+            }
+            debug!("Replayed {} entries from {:?}", replayed, log_path);
+        }
+
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .map_err(|e| storage_error(&log_path, e))?;
+        if let Some(length) = valid_length {
+            // Drop the partial entry so new entries aren't appended after it
+            log.set_len(length)
+                .and_then(|_| log.sync_data())
+                .map_err(|e| storage_error(&log_path, e))?;
+        }
+
+        let origin_path = dir.join(ORIGIN_FILE);
+        let origin = if origin_path.exists() {
+            let origin =
+                fs::read_to_string(&origin_path).map_err(|e| storage_error(&origin_path, e))?;
+            Some(u32::from_str_radix(origin.trim(), 16).map_err(|e| {
+                KvsError::StorageError(format!("Corrupt origin {:?}: {}", origin_path, e))
+            })?)
+        } else {
+            None
+        };
+        let persistence = Self {
+            dir,
+            log: BufWriter::new(log),
+            entries: replayed,
+            snapshot_interval,
+            origin,
+        };
+        Ok((persistence, entries))
+    }
+
+    /// Move a store persisted in one directory into another (E.g. to make room for more stores), unless
+    /// the destination already holds a store
+    ///
+    /// Returns whether a store was moved
+    pub fn relocate<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<bool, KvsError> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        let store_files = [SNAPSHOT_FILE, LOG_FILE, ORIGIN_FILE];
+        let files: Vec<&str> = store_files
+            .iter()
+            .copied()
+            .filter(|file| from.join(file).exists())
+            .collect();
+        if files.is_empty() || store_files.iter().any(|file| to.join(file).exists()) {
+            return Ok(false);
+        }
+        fs::create_dir_all(to).map_err(|e| storage_error(to, e))?;
+        for file in files {
+            let path = from.join(file);
+            fs::rename(&path, to.join(file)).map_err(|e| storage_error(&path, e))?;
+        }
+        Ok(true)
+    }
+
+    /// Origin id written by a previous run, if any
+    pub fn origin(&self) -> Option<u32> {
+        self.origin
+    }
+
+    /// Write the origin id of this node, to be restored by the next run
+    pub fn set_origin(&mut self, origin: u32) -> Result<(), KvsError> {
+        if self.origin == Some(origin) {
+            return Ok(());
+        }
+        let origin_path = self.dir.join(ORIGIN_FILE);
+        let tmp_path = self.dir.join(format!("{}.tmp", ORIGIN_FILE));
+        File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(format!("{:x}\n", origin).as_bytes())
+                    .and_then(|_| file.sync_all())
+            })
+            .and_then(|_| fs::rename(&tmp_path, &origin_path))
+            .and_then(|_| sync_dir(&self.dir))
+            .map_err(|e| storage_error(&origin_path, e))?;
+        self.origin = Some(origin);
+        Ok(())
+    }
+
+    /// Append a change to the write-ahead log, returning once it's synced to disk
+    pub fn append(&mut self, entry: &LogEntry) -> Result<(), KvsError> {
+        let bytes = bincode::serialize(entry)
+            .map_err(|e| KvsError::StorageError(format!("Could not encode log entry: {}", e)))?;
+        let length: u32 = bytes.len().try_into().map_err(|_| {
+            KvsError::StorageError(format!("Log entry of {} bytes is too large", bytes.len()))
+        })?;
+        let log_path = self.dir.join(LOG_FILE);
+        self.log
+            .write_all(&length.to_be_bytes())
+            .and_then(|_| self.log.write_all(&crc32fast::hash(&bytes).to_be_bytes()))
+            .and_then(|_| self.log.write_all(&bytes))
+            .and_then(|_| self.log.flush())
+            .and_then(|_| self.log.get_ref().sync_data())
+            .map_err(|e| storage_error(&log_path, e))?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Has the log grown enough to be compacted into a new snapshot?
+    pub fn needs_snapshot(&self) -> bool {
+        self.entries >= self.snapshot_interval
+    }
+
+    /// Write all pairs to a new snapshot and truncate the log
+    ///
+    /// The snapshot is written to a temp file and renamed into place,
+    /// so a crash will leave either the previous or new snapshot intact
+    pub fn snapshot(&mut self, pairs: &[StoredPair]) -> Result<(), KvsError> {
+        let bytes = bincode::serialize(pairs)
+            .map_err(|e| KvsError::StorageError(format!("Could not encode snapshot: {}", e)))?;
+        let snapshot_path = self.dir.join(SNAPSHOT_FILE);
+        let tmp_path = self.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        File::create(&tmp_path)
+            .and_then(|mut file| file.write_all(&bytes).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&tmp_path, &snapshot_path))
+            // The rename must be on disk before the log is truncated
+            .and_then(|_| sync_dir(&self.dir))
+            .map_err(|e| storage_error(&snapshot_path, e))?;
+
+        let log_path = self.dir.join(LOG_FILE);
+        self.log
+            .flush()
+            .and_then(|_| self.log.get_ref().set_len(0))
+            .and_then(|_| self.log.get_ref().sync_data())
+            .map_err(|e| storage_error(&log_path, e))?;
+        self.entries = 0;
+        debug!(
+            "Wrote snapshot of {} pairs to {:?}",
+            pairs.len(),
+            snapshot_path
+        );
+        Ok(())
+    }
+}
+
+/// Why a log entry couldn't be read
+#[derive(Debug, PartialEq)]
+enum EntryError {
+    /// The log ends before the end of the entry
+    Incomplete,
+    /// The entry doesn't match its checksum (or can't be deserialized), and is the `last` in the log or not
+    Corrupt { last: bool },
+}
+
+/// Read one length-prefixed & checksummed entry, returning it and the remaining bytes
+fn read_entry(bytes: &[u8]) -> Result<(LogEntry, &[u8]), EntryError> {
+    if bytes.len() < ENTRY_HEADER_SIZE {
+        return Err(EntryError::Incomplete);
+    }
+    let length = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_be_bytes(bytes[4..ENTRY_HEADER_SIZE].try_into().unwrap());
+    let bytes = &bytes[ENTRY_HEADER_SIZE..];
+    if bytes.len() < length {
+        return Err(EntryError::Incomplete);
+    }
+    let (entry, rest) = bytes.split_at(length);
+    if crc32fast::hash(entry) != checksum {
+        return Err(EntryError::Corrupt {
+            last: rest.is_empty(),
+        });
+    }
+    let entry = bincode::deserialize(entry).map_err(|_| EntryError::Corrupt {
+        last: rest.is_empty(),
+    })?;
+    Ok((entry, rest))
+}
+
+/// Sync a directory, so renames & new files in it are on disk
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+fn storage_error(path: &Path, error: std::io::Error) -> KvsError {
+    KvsError::StorageError(format!("{:?}: {}", path, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kvs-bgp-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn pair(key: &str, value: &str, timestamp: u64) -> StoredPair {
+        StoredPair {
+            key: key.to_owned(),
+            value: value.into(),
+            version: Version::new(timestamp, 1),
+            categories: vec![],
+            expires: None,
+            ephemeral: false,
+            batch_size: 0,
+            nonce: [0; NONCE_SIZE],
+            signer: None,
+            ipv4_slot: None,
+        }
+    }
+
+    #[test]
+    fn replay_log() {
+        let dir = test_dir("replay-log");
+        {
+            let (mut persistence, entries) = Persistence::open(&dir, 100).unwrap();
+            assert!(entries.is_empty());
+            persistence
+                .append(&LogEntry::Insert(pair("Key", "Value", 0)))
+                .unwrap();
+            persistence
+                .append(&LogEntry::Remove("Key".to_owned()))
+                .unwrap();
+        }
+        let (_, entries) = Persistence::open(&dir, 100).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(matches!(&entries[0], LogEntry::Insert(pair) if pair.key == "Key"));
+        assert!(matches!(&entries[1], LogEntry::Remove(key) if key == "Key"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn snapshot_truncates_log() {
+        let dir = test_dir("snapshot");
+        {
+            let (mut persistence, _) = Persistence::open(&dir, 1).unwrap();
+            persistence
+                .append(&LogEntry::Insert(pair("Key", "Value", 0)))
+                .unwrap();
+            assert!(persistence.needs_snapshot());
+            persistence.snapshot(&[pair("Key", "Value", 0)]).unwrap();
+            assert!(!persistence.needs_snapshot());
+            persistence
+                .append(&LogEntry::Insert(pair("Other", "Value", 3)))
+                .unwrap();
+        }
+        let (_, entries) = Persistence::open(&dir, 1).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(matches!(&entries[1], LogEntry::Insert(pair) if pair.version.timestamp == 3));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ignore_partial_entry() {
+        let dir = test_dir("partial");
+        {
+            let (mut persistence, _) = Persistence::open(&dir, 100).unwrap();
+            persistence
+                .append(&LogEntry::Insert(pair("Key", "Value", 0)))
+                .unwrap();
+        }
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.join(LOG_FILE))
+            .unwrap();
+        log.write_all(&[0, 0, 0, 42, 1, 2]).unwrap();
+
+        {
+            let (mut persistence, entries) = Persistence::open(&dir, 100).unwrap();
+            assert_eq!(entries.len(), 1);
+            persistence
+                .append(&LogEntry::Remove("Key".to_owned()))
+                .unwrap();
+        }
+        let (_, entries) = Persistence::open(&dir, 100).unwrap();
+        assert_eq!(entries.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuse_corrupt_entry() {
+        let dir = test_dir("corrupt");
+        {
+            let (mut persistence, _) = Persistence::open(&dir, 100).unwrap();
+            for key in &["Key", "Other"] {
+                persistence
+                    .append(&LogEntry::Insert(pair(key, "Value", 0)))
+                    .unwrap();
+            }
+        }
+        // Flip a byte in the value of the first entry, so it no longer matches its checksum
+        let mut bytes = fs::read(dir.join(LOG_FILE)).unwrap();
+        let length = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+        bytes[ENTRY_HEADER_SIZE + length - 1] ^= 0xff;
+        fs::write(dir.join(LOG_FILE), &bytes).unwrap();
+
+        assert!(matches!(
+            Persistence::open(&dir, 100),
+            Err(KvsError::StorageError(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn persist_origin() {
+        let dir = test_dir("origin");
+        {
+            let (mut persistence, _) = Persistence::open(&dir, 100).unwrap();
+            assert_eq!(persistence.origin(), None);
+            persistence.set_origin(0xbf51).unwrap();
+        }
+        let (persistence, _) = Persistence::open(&dir, 100).unwrap();
+        assert_eq!(persistence.origin(), Some(0xbf51));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn relocate_store() {
+        let dir = test_dir("relocate");
+        {
+            let (mut persistence, _) = Persistence::open(&dir, 100).unwrap();
+            persistence.set_origin(0xbf51).unwrap();
+            persistence
+                .append(&LogEntry::Insert(pair("Key", "Value", 1)))
+                .unwrap();
+        }
+        let moved = dir.join("default");
+        assert!(Persistence::relocate(&dir, &moved).unwrap());
+        assert!(!dir.join(LOG_FILE).exists());
+        let (persistence, entries) = Persistence::open(&moved, 100).unwrap();
+        assert_eq!(persistence.origin(), Some(0xbf51));
+        assert_eq!(entries.len(), 1);
+
+        // Nothing left to move, and an existing store isn't overwritten
+        assert!(!Persistence::relocate(&dir, &moved).unwrap());
+        Persistence::open(&dir, 100).unwrap();
+        assert!(!Persistence::relocate(&dir, &moved).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,889 @@
+//! Reassembly of [Route](struct.Route.html)s received from BGP peers
+//!
+//! A [KeyValue](struct.KeyValue.html) pair is encoded as many routes, which may arrive
+//! across many BGP Update messages, out of order, duplicated (re-advertised), or interleaved with
+//! routes for other versions of the same [Key](struct.Key.html). Routes are buffered per
+//! key hash & version, indexed by sequence number, until every route for that version is present.
+//!
+//! Peers that only send some of the routes for a version would leave partial collections
+//! buffered forever, so pending collections expire after a timeout and are evicted (oldest first)
+//! when the configured [ReassemblyLimits](struct.ReassemblyLimits.html) are exceeded.
+//!
+//! Decoded pairs written in a batch are then held back by a [BatchAssembler](struct.BatchAssembler.html)
+//! until every pair of the batch has been received, so a batch is applied to the store all at once (or not at all).
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::time::{Duration, Instant};
+
+use log::{debug, trace, warn};
+
+use crate::clock::{compare_tags, Version};
+use crate::kv::{KeyValue, Payload, Route, RouteCollection};
+
+/// Key hash, version tag & origin tag of the [KeyValue](struct.KeyValue.html) a route belongs to
+type CollectionId = (u64, u32, u16);
+
+/// Bytes buffered per pending route (IPv6 Prefix & NextHop), not counting its categories
+const ROUTE_BYTES: usize = 32;
+
+/// Limits for buffering partially received [RouteCollection](struct.RouteCollection.html)s
+#[derive(Clone, Copy, Debug)]
+pub struct ReassemblyLimits {
+    /// How long a partial collection is kept without receiving any new routes
+    pub timeout: Duration,
+    /// Max number of partial collections buffered at once
+    pub max_pending: usize,
+    /// Max bytes of routes buffered across all partial collections
+    pub max_bytes: usize,
+}
+
+impl Default for ReassemblyLimits {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(60),
+            max_pending: 1024,
+            max_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Counters for routes & collections handled by a [Reassembler](struct.Reassembler.html)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReassemblyStats {
+    /// Collections fully received
+    pub completed: u64,
+    /// Duplicate routes ignored
+    pub duplicates: u64,
+    /// Partial collections dropped (or routes ignored) for a newer version of the same key
+    pub superseded: u64,
+    /// Partial collections dropped after the timeout
+    pub expired: u64,
+    /// Partial collections dropped to stay within the pending/byte limits
+    pub evicted: u64,
+}
+
+/// Routes received so far for a single [KeyValue](struct.KeyValue.html) version
+#[derive(Debug)]
+struct PendingCollection {
+    /// Number of routes expected (from the [NextHop](struct.NextHop.html) encoding)
+    length: usize,
+    /// Received routes, by sequence number
+    routes: BTreeMap<u16, Route>,
+    /// Bytes of the received routes, including their categories
+    bytes: usize,
+    /// When the last route was received
+    updated: Instant,
+}
+
+impl PendingCollection {
+    fn new(length: usize) -> Self {
+        Self {
+            length,
+            routes: BTreeMap::new(),
+            bytes: 0,
+            updated: Instant::now(),
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn is_complete(&self) -> bool {
+        self.routes.len() == self.length
+    }
+}
+
+/// Buffer for partially received [RouteCollection](struct.RouteCollection.html)s
+///
+/// Only hands out a `RouteCollection` once every sequence for a key hash & version has
+/// been received, ignoring duplicate routes and discarding versions that have been
+/// superseded by a newer version of the same key
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    pending: HashMap<CollectionId, PendingCollection>,
+    /// Newest version tag with pending routes, per key hash, and the origin tags pending for it
+    /// (concurrent writes from different origins can share a version tag)
+    newest: HashMap<u64, (u32, Vec<u16>)>,
+    /// Bytes of routes currently buffered
+    bytes: usize,
+    limits: ReassemblyLimits,
+    stats: ReassemblyStats,
+}
+
+impl Reassembler {
+    /// Create a new, empty `Reassembler` with default limits
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a new, empty `Reassembler` with the given limits
+    pub fn with_limits(limits: ReassemblyLimits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    /// Counters of handled routes & dropped collections
+    pub fn stats(&self) -> ReassemblyStats {
+        self.stats
+    }
+
+    /// Bytes of routes currently buffered in partial collections
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Number of partially received [RouteCollection](struct.RouteCollection.html)s
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Are there no partially received collections?
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Add a received [Route](struct.Route.html)
+    ///
+    /// Returns the complete [RouteCollection](struct.RouteCollection.html) once all
+    /// routes for this route's key hash & version have been received
+    pub fn insert(&mut self, route: Route) -> Option<RouteCollection> {
+        let hash = route.hash();
+        let version = route.version();
+        let origin = route.origin();
+        let length = route.collection_length();
+        let sequence = route.sequence();
+
+        if sequence as usize >= length {
+            trace!("Invalid sequence {} for {} v{}", sequence, hash, version);
+            return None;
+        }
+
+        // Version tags wrap around, so are compared with serial number arithmetic
+        match self
+            .newest
+            .get(&hash)
+            .map(|(newest, origins)| (*newest, origins.clone(), compare_tags(*newest, version)))
+        {
+            Some((newest, _, Ordering::Greater)) => {
+                trace!(
+                    "Ignoring superseded {} v{} (have v{})",
+                    hash,
+                    version,
+                    newest
+                );
+                self.stats.superseded += 1;
+                return None;
+            }
+            Some((newest, origins, Ordering::Less)) => {
+                for other in origins {
+                    if let Some(dropped) = self.remove((hash, newest, other)) {
+                        debug!(
+                            "Dropping partial RouteCollection {} v{}@{:x} [{}/{}], superseded by v{}",
+                            hash,
+                            newest,
+                            other,
+                            dropped.routes.len(),
+                            dropped.length,
+                            version
+                        );
+                        self.stats.superseded += 1;
+                    }
+                }
+            }
+            _ => (),
+        }
+        // Concurrent writes of the same version tag from other origins are kept alongside
+        let (_, origins) = self
+            .newest
+            .entry(hash)
+            .or_insert_with(|| (version, Vec::new()));
+        if !origins.contains(&origin) {
+            origins.push(origin);
+        }
+
+        let id = (hash, version, origin);
+        let pending = self
+            .pending
+            .entry(id)
+            .or_insert_with(|| PendingCollection::new(length));
+        if pending.length != length {
+            trace!(
+                "Ignoring route with mismatched length for {} v{} ({} != {})",
+                hash,
+                version,
+                length,
+                pending.length
+            );
+            return None;
+        }
+        if pending.routes.contains_key(&sequence) {
+            trace!(
+                "Ignoring duplicate seq {} for {} v{}",
+                sequence,
+                hash,
+                version
+            );
+            self.stats.duplicates += 1;
+            return None;
+        }
+        let bytes = route_bytes(&route);
+        pending.routes.insert(sequence, route);
+        pending.bytes += bytes;
+        pending.updated = Instant::now();
+        self.bytes += bytes;
+        trace!(
+            "Reassembling {} v{} [{}/{}]",
+            hash,
+            version,
+            pending.routes.len(),
+            length
+        );
+
+        if pending.is_complete() {
+            let pending = self.remove(id).expect("Id is pending");
+            self.stats.completed += 1;
+            Some(RouteCollection::from_routes(
+                pending.routes.into_values().collect(),
+            ))
+        } else {
+            self.enforce_limits();
+            None
+        }
+    }
+
+    /// Drop any partial collections that haven't received a route within the timeout
+    ///
+    /// Returns the number of collections dropped
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let timeout = self.limits.timeout;
+        let expired: Vec<CollectionId> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| now.saturating_duration_since(pending.updated) >= timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &expired {
+            if let Some(dropped) = self.remove(*id) {
+                warn!(
+                    "Dropping partial RouteCollection {} v{}@{:x} [{}/{}], expired after {:?}",
+                    id.0,
+                    id.1,
+                    id.2,
+                    dropped.routes.len(),
+                    dropped.length,
+                    timeout
+                );
+                self.stats.expired += 1;
+            }
+        }
+        expired.len()
+    }
+
+    /// Evict the oldest partial collections until within the pending/byte limits
+    fn enforce_limits(&mut self) {
+        while self.pending.len() > self.limits.max_pending || self.bytes > self.limits.max_bytes {
+            let oldest = self
+                .pending
+                .iter()
+                .min_by_key(|(_, pending)| pending.updated)
+                .map(|(id, _)| *id);
+            let id = match oldest {
+                Some(id) => id,
+                None => break,
+            };
+            if let Some(dropped) = self.remove(id) {
+                warn!(
+                    "Dropping partial RouteCollection {} v{}@{:x} [{}/{}], over limits ({} pending, {} bytes)",
+                    id.0,
+                    id.1,
+                    id.2,
+                    dropped.routes.len(),
+                    dropped.length,
+                    self.pending.len() + 1,
+                    self.bytes + dropped.bytes(),
+                );
+                self.stats.evicted += 1;
+            }
+        }
+    }
+
+    /// Drop a [Route](struct.Route.html) that was withdrawn before its
+    /// collection was complete
+    pub fn discard(&mut self, route: &Route) {
+        let id = (route.hash(), route.version(), route.origin());
+        if let Some(pending) = self.pending.get_mut(&id) {
+            if let Some(removed) = pending.routes.remove(&route.sequence()) {
+                let bytes = route_bytes(&removed);
+                pending.bytes -= bytes;
+                self.bytes -= bytes;
+            }
+            if pending.routes.is_empty() {
+                self.remove(id);
+            }
+        }
+    }
+
+    /// Drop every partial collection for a key hash (E.g. once the pair has been removed)
+    ///
+    /// Returns the number of collections dropped
+    pub fn remove_key(&mut self, hash: u64) -> usize {
+        let ids: Vec<CollectionId> = self
+            .pending
+            .keys()
+            .filter(|id| id.0 == hash)
+            .copied()
+            .collect();
+        for id in &ids {
+            self.remove(*id);
+        }
+        ids.len()
+    }
+
+    fn remove(&mut self, id: CollectionId) -> Option<PendingCollection> {
+        let removed = self.pending.remove(&id);
+        if let Some(removed) = &removed {
+            self.bytes -= removed.bytes();
+        }
+        if let Some((newest, origins)) = self.newest.get_mut(&id.0) {
+            if *newest == id.1 {
+                origins.retain(|origin| *origin != id.2);
+                if origins.is_empty() {
+                    self.newest.remove(&id.0);
+                }
+            }
+        }
+        removed
+    }
+}
+
+/// Bytes buffered for a pending route, including its categories (as many as the peer announced)
+fn route_bytes(route: &Route) -> usize {
+    ROUTE_BYTES + mem::size_of::<Vec<u16>>() + route.categories.len() * mem::size_of::<u16>()
+}
+
+/// Pairs received so far for a single batch
+#[derive(Debug)]
+struct PendingBatch {
+    /// Number of pairs in the batch
+    size: usize,
+    /// Received pairs, by key
+    pairs: HashMap<String, KeyValue<String, Payload>>,
+    /// Withdraws of older versions of the pairs, held back until the batch is released
+    withdraws: Vec<KeyValue<String, Payload>>,
+    /// When the last pair was received
+    updated: Instant,
+}
+
+/// Buffer for the [KeyValue](struct.KeyValue.html)s of partially received batches
+///
+/// Every pair of a batch shares the same version, so pairs are buffered per version until
+/// the whole batch has been received. Pairs are buffered whatever their categories, so
+/// subscriptions should be applied to the assembled batch. A batch that is never completed
+/// is dropped after the timeout, or when over the pending limit, so peers never apply only
+/// part of a batch.
+///
+/// Withdraws of the older versions of pairs in a pending batch are held back too, since
+/// applying them would remove the old versions before the batch replaces them. They're
+/// released along with the batch, whether it's completed or dropped (see
+/// [take_withdraws](#method.take_withdraws)).
+#[derive(Debug, Default)]
+pub struct BatchAssembler {
+    pending: HashMap<Version, PendingBatch>,
+    /// Held withdraws of batches that have been released
+    released: Vec<KeyValue<String, Payload>>,
+    limits: ReassemblyLimits,
+}
+
+impl BatchAssembler {
+    /// Create a new, empty `BatchAssembler` with default limits
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a new, empty `BatchAssembler` with the given limits
+    pub fn with_limits(limits: ReassemblyLimits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    /// Number of partially received batches
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Are there no partially received batches?
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Add a received [KeyValue](struct.KeyValue.html)
+    ///
+    /// Returns the batches of pairs ready to be stored: the pair on its own if it wasn't
+    /// written in a batch, or its whole batch once every pair has been received.
+    /// The oldest partial batches are dropped to stay within the pending limit
+    pub fn insert(&mut self, kv: KeyValue<String, Payload>) -> Vec<Vec<KeyValue<String, Payload>>> {
+        if kv.batch_size() == 0 {
+            return vec![vec![kv]];
+        }
+        let version = kv.version();
+        let pending = self.pending.entry(version).or_insert_with(|| PendingBatch {
+            size: kv.batch_size() as usize,
+            pairs: HashMap::new(),
+            withdraws: vec![],
+            updated: Instant::now(),
+        });
+        pending.pairs.insert(kv.key().clone(), kv);
+        pending.updated = Instant::now();
+        trace!(
+            "Assembling batch {} [{}/{}]",
+            version,
+            pending.pairs.len(),
+            pending.size
+        );
+
+        let mut ready = vec![];
+        if pending.pairs.len() >= pending.size {
+            ready.push(self.release(version));
+        }
+        while self.pending.len() > self.limits.max_pending {
+            let oldest = self
+                .pending
+                .iter()
+                .min_by_key(|(_, pending)| pending.updated)
+                .map(|(version, _)| *version)
+                .expect("Batches are pending");
+            let dropped = self.release(oldest);
+            warn!(
+                "Dropping partial batch {} ({} pairs), over limits",
+                oldest,
+                dropped.len()
+            );
+        }
+        ready
+    }
+
+    /// Hold back the withdraw of a pair if a newer version of it is in a pending batch
+    ///
+    /// Returns the withdrawn pair if it should be applied now. Held withdraws are released
+    /// once the batch is completed or dropped, and should then be applied after the batch is
+    /// stored: a withdraw superseded by the stored batch has no effect
+    pub fn hold_withdraw(
+        &mut self,
+        kv: KeyValue<String, Payload>,
+    ) -> Option<KeyValue<String, Payload>> {
+        let pending = self.pending.values_mut().find(|pending| {
+            pending.pairs.get(kv.key()).map(KeyValue::version) > Some(kv.version())
+        });
+        match pending {
+            Some(pending) => {
+                trace!("Holding withdraw of {} for a pending batch", kv.key());
+                pending.withdraws.push(kv);
+                None
+            }
+            None => Some(kv),
+        }
+    }
+
+    /// Take the held withdraws of the batches released (completed or dropped) since last called
+    ///
+    /// Withdraws still superseded by another pending batch stay held
+    pub fn take_withdraws(&mut self) -> Vec<KeyValue<String, Payload>> {
+        let released = mem::take(&mut self.released);
+        released
+            .into_iter()
+            .filter_map(|kv| self.hold_withdraw(kv))
+            .collect()
+    }
+
+    /// Drop any partial batches that haven't received a pair within the timeout
+    ///
+    /// Returns the number of batches dropped
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let timeout = self.limits.timeout;
+        let expired: Vec<Version> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| now.saturating_duration_since(pending.updated) >= timeout)
+            .map(|(version, _)| *version)
+            .collect();
+        for version in &expired {
+            let dropped = self.release(*version);
+            warn!(
+                "Dropping partial batch {} ({} pairs), expired after {:?}",
+                version,
+                dropped.len(),
+                timeout
+            );
+        }
+        expired.len()
+    }
+
+    fn release(&mut self, version: Version) -> Vec<KeyValue<String, Payload>> {
+        match self.pending.remove(&version) {
+            Some(pending) => {
+                self.released.extend(pending.withdraws);
+                pending.pairs.into_values().collect()
+            }
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    fn routes(kv: &KeyValue<String, String>) -> Vec<Route> {
+        let collection: RouteCollection = kv.try_into().unwrap();
+        collection.iter().cloned().collect()
+    }
+
+    #[test]
+    fn reassemble_out_of_order() {
+        let kv = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        let mut routes = routes(&kv);
+        routes.reverse();
+        let last = routes.pop().unwrap();
+
+        let mut reassembler = Reassembler::new();
+        for route in routes {
+            assert!(reassembler.insert(route).is_none());
+        }
+        assert_eq!(reassembler.len(), 1);
+        let collection = reassembler.insert(last).unwrap();
+        let kv2: KeyValue<String, String> = (&collection).try_into().unwrap();
+        assert_eq!(kv2.to_string(), kv.to_string());
+        assert!(reassembler.is_empty());
+    }
+
+    #[test]
+    fn reassemble_ignores_duplicates() {
+        let kv = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        let routes = routes(&kv);
+
+        let (last, routes) = routes.split_last().unwrap();
+
+        let mut reassembler = Reassembler::new();
+        for route in routes {
+            assert!(reassembler.insert(route.clone()).is_none());
+            assert!(reassembler.insert(route.clone()).is_none());
+        }
+        let collection = reassembler.insert(last.clone()).unwrap();
+        assert_eq!(collection.iter().count(), routes.len() + 1);
+        assert_eq!(reassembler.stats().duplicates, routes.len() as u64);
+    }
+
+    #[test]
+    fn reassemble_interleaved_versions() {
+        let mut kv = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        let old_routes = routes(&kv);
+        kv.update("Some other Value".to_owned());
+        let new_routes = routes(&kv);
+
+        let mut reassembler = Reassembler::new();
+        assert!(reassembler.insert(old_routes[0].clone()).is_none());
+        assert!(reassembler.insert(new_routes[0].clone()).is_none());
+        // Old version is superseded, remaining routes are ignored
+        for route in &old_routes[1..] {
+            assert!(reassembler.insert(route.clone()).is_none());
+        }
+        assert_eq!(reassembler.len(), 1);
+
+        let mut completed = None;
+        for route in &new_routes[1..] {
+            completed = reassembler.insert(route.clone());
+        }
+        let kv2: KeyValue<String, String> = (&completed.unwrap()).try_into().unwrap();
+        assert_eq!(kv2.version(), kv.version());
+        assert_eq!(kv2.to_string(), kv.to_string());
+        assert!(reassembler.is_empty());
+    }
+
+    #[test]
+    fn reassemble_versions_wraparound() {
+        let old = KeyValue::with_version(
+            "MyKey".to_owned(),
+            "Some Value".to_owned(),
+            Version::new(0xffff_ffff, 1),
+        );
+        let new = KeyValue::with_version(
+            "MyKey".to_owned(),
+            "Some other Value".to_owned(),
+            Version::new(0x1_0000_0001, 1),
+        );
+        let old_routes = routes(&old);
+        let new_routes = routes(&new);
+
+        let mut reassembler = Reassembler::new();
+        assert!(reassembler.insert(new_routes[0].clone()).is_none());
+        // Tag 0xFFFFFFFF is older than tag 0x00000001 after wrapping
+        assert!(reassembler.insert(old_routes[0].clone()).is_none());
+        assert_eq!(reassembler.stats().superseded, 1);
+        assert_eq!(reassembler.len(), 1);
+    }
+
+    #[test]
+    fn reassemble_concurrent_origins() {
+        // Concurrent writes with the same timestamp from different nodes
+        let kv1 = KeyValue::with_version(
+            "MyKey".to_owned(),
+            "Some Value".to_owned(),
+            Version::new(1000, 1),
+        );
+        let kv2 = KeyValue::with_version(
+            "MyKey".to_owned(),
+            "Some other Value".to_owned(),
+            Version::new(1000, 2),
+        );
+        let routes1 = routes(&kv1);
+        let routes2 = routes(&kv2);
+
+        let mut reassembler = Reassembler::new();
+        let (last1, routes1) = routes1.split_last().unwrap();
+        let (last2, routes2) = routes2.split_last().unwrap();
+        for route in routes1.iter().chain(routes2) {
+            assert!(reassembler.insert(route.clone()).is_none());
+        }
+        let completed = [
+            reassembler.insert(last1.clone()).unwrap(),
+            reassembler.insert(last2.clone()).unwrap(),
+        ];
+        assert_eq!(reassembler.stats().superseded, 0);
+        let decoded: Vec<KeyValue<String, String>> = completed
+            .iter()
+            .map(|routes| routes.try_into().unwrap())
+            .collect();
+        assert!(decoded.iter().any(|kv| kv.version() == kv1.version()));
+        assert!(decoded.iter().any(|kv| kv.version() == kv2.version()));
+
+        // A newer version drops both
+        let newer = KeyValue::with_version(
+            "MyKey".to_owned(),
+            "Newer Value".to_owned(),
+            Version::new(1001, 1),
+        );
+        reassembler.insert(routes1[0].clone());
+        reassembler.insert(routes2[0].clone());
+        assert_eq!(reassembler.len(), 2);
+        reassembler.insert(routes(&newer)[0].clone());
+        assert_eq!(reassembler.len(), 1);
+        assert_eq!(reassembler.stats().superseded, 2);
+    }
+
+    #[test]
+    fn reassemble_remove_key() {
+        let kv = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        let other = KeyValue::new("Other".to_owned(), "Some Value".to_owned());
+
+        let mut reassembler = Reassembler::new();
+        reassembler.insert(routes(&kv)[0].clone());
+        reassembler.insert(routes(&other)[0].clone());
+        assert_eq!(reassembler.remove_key(kv.key_hash()), 1);
+        assert_eq!(reassembler.len(), 1);
+        assert_eq!(reassembler.bytes(), route_bytes(&routes(&other)[0]));
+        assert_eq!(reassembler.remove_key(kv.key_hash()), 0);
+    }
+
+    #[test]
+    fn reassemble_discard_withdrawn() {
+        let kv = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        let routes = routes(&kv);
+
+        let mut reassembler = Reassembler::new();
+        reassembler.insert(routes[0].clone());
+        reassembler.discard(&routes[0]);
+        assert!(reassembler.is_empty());
+        assert_eq!(reassembler.bytes(), 0);
+    }
+
+    #[test]
+    fn reassemble_expire() {
+        let kv = KeyValue::new("MyKey".to_owned(), "Some Value".to_owned());
+        let routes = routes(&kv);
+
+        let mut reassembler = Reassembler::with_limits(ReassemblyLimits {
+            timeout: Duration::from_secs(10),
+            ..ReassemblyLimits::default()
+        });
+        reassembler.insert(routes[0].clone());
+        assert_eq!(reassembler.expire(Instant::now()), 0);
+        assert_eq!(reassembler.len(), 1);
+
+        let later = Instant::now() + Duration::from_secs(11);
+        assert_eq!(reassembler.expire(later), 1);
+        assert!(reassembler.is_empty());
+        assert_eq!(reassembler.bytes(), 0);
+        assert_eq!(reassembler.stats().expired, 1);
+    }
+
+    #[test]
+    fn reassemble_evict_over_limits() {
+        let kv1 = KeyValue::new("Key1".to_owned(), "Some Value".to_owned());
+        let kv2 = KeyValue::new("Key2".to_owned(), "Some Value".to_owned());
+
+        let mut reassembler = Reassembler::with_limits(ReassemblyLimits {
+            max_pending: 1,
+            ..ReassemblyLimits::default()
+        });
+        reassembler.insert(routes(&kv1)[0].clone());
+        reassembler.insert(routes(&kv2)[0].clone());
+        assert_eq!(reassembler.len(), 1);
+        assert_eq!(reassembler.stats().evicted, 1);
+
+        let mut reassembler = Reassembler::with_limits(ReassemblyLimits {
+            max_bytes: route_bytes(&routes(&kv1)[0]),
+            ..ReassemblyLimits::default()
+        });
+        reassembler.insert(routes(&kv1)[0].clone());
+        reassembler.insert(routes(&kv1)[1].clone());
+        assert!(reassembler.is_empty());
+        assert_eq!(reassembler.bytes(), 0);
+        assert_eq!(reassembler.stats().evicted, 1);
+
+        // Categories count towards the limit, so many of them can't bypass it
+        let mut reassembler = Reassembler::with_limits(ReassemblyLimits {
+            max_bytes: 4 * route_bytes(&routes(&kv1)[0]),
+            ..ReassemblyLimits::default()
+        });
+        let mut route = routes(&kv1)[0].clone();
+        route.categories = (0..1000).collect();
+        reassembler.insert(route);
+        assert!(reassembler.is_empty());
+        assert_eq!(reassembler.stats().evicted, 1);
+    }
+
+    fn batch_pair(key: &str, version: Version, size: u16) -> KeyValue<String, Payload> {
+        let mut kv = KeyValue::with_version(key.to_owned(), "Value".into(), version);
+        kv.set_batch_size(size);
+        kv
+    }
+
+    #[test]
+    fn assemble_batches() {
+        let mut batches = BatchAssembler::new();
+        let ready = batches.insert(KeyValue::new("Single".to_owned(), "Value".into()));
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].len(), 1);
+
+        let version = Version::new(1000, 1);
+        assert!(batches.insert(batch_pair("Key1", version, 2)).is_empty());
+        // Re-advertised pairs aren't counted twice
+        assert!(batches.insert(batch_pair("Key1", version, 2)).is_empty());
+        assert!(batches
+            .insert(batch_pair("Other", Version::new(1001, 1), 2))
+            .is_empty());
+        assert_eq!(batches.len(), 2);
+
+        let ready = batches.insert(batch_pair("Key2", version, 2));
+        assert_eq!(ready.len(), 1);
+        let mut keys: Vec<_> = ready[0].iter().map(|kv| kv.key().as_str()).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec!["Key1", "Key2"]);
+        assert_eq!(batches.len(), 1);
+    }
+
+    #[test]
+    fn assemble_batches_expire() {
+        let mut batches = BatchAssembler::with_limits(ReassemblyLimits {
+            timeout: Duration::from_secs(10),
+            ..ReassemblyLimits::default()
+        });
+        batches.insert(batch_pair("Key1", Version::new(1000, 1), 2));
+        assert_eq!(batches.expire(Instant::now()), 0);
+
+        // Partial batches are dropped, rather than applied as-is
+        let later = Instant::now() + Duration::from_secs(11);
+        assert_eq!(batches.expire(later), 1);
+        assert!(batches.is_empty());
+    }
+
+    #[test]
+    fn assemble_batches_over_limits() {
+        let mut batches = BatchAssembler::with_limits(ReassemblyLimits {
+            max_pending: 1,
+            ..ReassemblyLimits::default()
+        });
+        assert!(batches
+            .insert(batch_pair("Key1", Version::new(1000, 1), 2))
+            .is_empty());
+        // The oldest partial batch is dropped
+        assert!(batches
+            .insert(batch_pair("Key1", Version::new(1001, 1), 2))
+            .is_empty());
+        assert_eq!(batches.len(), 1);
+        let ready = batches.insert(batch_pair("Key2", Version::new(1001, 1), 2));
+        assert_eq!(ready.len(), 1);
+        assert!(ready[0]
+            .iter()
+            .all(|kv| kv.version() == Version::new(1001, 1)));
+    }
+
+    #[test]
+    fn assemble_batches_hold_withdraws() {
+        let mut batches = BatchAssembler::new();
+        batches.insert(batch_pair("Key1", Version::new(1000, 1), 2));
+
+        // The old version of a pair in a pending batch isn't withdrawn yet
+        let old = KeyValue::with_version("Key1".to_owned(), "Old".into(), Version::new(900, 1));
+        assert!(batches.hold_withdraw(old).is_none());
+        // But other withdraws are applied
+        let other = KeyValue::with_version("Other".to_owned(), "Old".into(), Version::new(900, 1));
+        assert!(batches.hold_withdraw(other).is_some());
+        let newer = KeyValue::with_version("Key1".to_owned(), "New".into(), Version::new(1100, 1));
+        assert!(batches.hold_withdraw(newer).is_some());
+        assert!(batches.take_withdraws().is_empty());
+
+        // Held withdraws are released with the batch
+        assert_eq!(
+            batches
+                .insert(batch_pair("Key2", Version::new(1000, 1), 2))
+                .len(),
+            1
+        );
+        let released = batches.take_withdraws();
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].version(), Version::new(900, 1));
+        assert!(batches.take_withdraws().is_empty());
+    }
+
+    #[test]
+    fn assemble_batches_release_withdraws_when_dropped() {
+        let mut batches = BatchAssembler::with_limits(ReassemblyLimits {
+            timeout: Duration::from_secs(10),
+            ..ReassemblyLimits::default()
+        });
+        let old = || KeyValue::with_version("Key1".to_owned(), "Old".into(), Version::new(900, 1));
+        batches.insert(batch_pair("Key1", Version::new(1000, 1), 2));
+        assert!(batches.hold_withdraw(old()).is_none());
+        let later = Instant::now() + Duration::from_secs(11);
+        assert_eq!(batches.expire(later), 1);
+        assert_eq!(batches.take_withdraws().len(), 1);
+
+        // A withdraw still superseded by another pending batch stays held
+        batches.insert(batch_pair("Key1", Version::new(1000, 1), 2));
+        batches.insert(batch_pair("Key1", Version::new(1100, 1), 2));
+        assert!(batches.hold_withdraw(old()).is_none());
+        assert_eq!(
+            batches
+                .insert(batch_pair("Key2", Version::new(1000, 1), 2))
+                .len(),
+            1
+        );
+        assert!(batches.take_withdraws().is_empty());
+        assert_eq!(
+            batches
+                .insert(batch_pair("Key2", Version::new(1100, 1), 2))
+                .len(),
+            1
+        );
+        assert_eq!(batches.take_withdraws().len(), 1);
+    }
+}
@@ -0,0 +1,704 @@
+//! Redis (RESP2/RESP3) protocol front-end for the [KvStore](struct.KvStore.html)
+//!
+//! Allows existing Redis clients (E.g. `redis-cli`) to get/insert/remove `KeyValue` pairs.
+//! Supported commands:
+//! - `PING`, `ECHO`, `HELLO`, `COMMAND`, `QUIT`
+//! - `GET`, `SET` (with `EX`/`PX` TTLs), `DEL`, `EXISTS`, `TTL`, `PTTL`
+//! - `MGET`, `MSET` (written atomically as one batch)
+//! - `KEYS`, `SCAN`
+//!
+//! Writes trigger the same BGP updates to peers as the HTTP API.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{Buf, BytesMut};
+use log::{debug, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, RwLock};
+use tokio::task;
+
+use crate::blob;
+use crate::clock::wall_clock;
+use crate::store::{InsertOptions, KvStore, Update};
+
+type Store = Arc<RwLock<KvStore>>;
+type UpdateChannel = mpsc::UnboundedSender<Update>;
+/// Arguments of a command (including the command name)
+type Command = Vec<Vec<u8>>;
+
+/// Default number of keys returned for each `SCAN` call
+const SCAN_COUNT: usize = 10;
+/// Max number of arguments of a command (same as Redis)
+const MAX_ARGS: i64 = 1024 * 1024;
+/// Max bytes of a single argument (same as Redis' default `proto-max-bulk-len`)
+const MAX_BULK_LENGTH: i64 = 512 * 1024 * 1024;
+/// Max bytes of a line, E.g. an inline command (same as Redis)
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// A reply to a RESP command, encoded depending on the connection's protocol version
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Reply>),
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl Reply {
+    fn error(message: &str) -> Self {
+        Reply::Error(format!("ERR {}", message))
+    }
+
+    fn wrong_args(command: &str) -> Self {
+        Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            command.to_lowercase()
+        ))
+    }
+
+    fn bulk<T: AsRef<[u8]>>(data: T) -> Self {
+        Reply::Bulk(data.as_ref().to_vec())
+    }
+
+    /// Encode this reply for the given protocol version (2 or 3)
+    fn encode(&self, protocol: u8, buf: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(s) => buf.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Reply::Error(e) => buf.extend_from_slice(format!("-{}\r\n", e).as_bytes()),
+            Reply::Integer(i) => buf.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+            Reply::Bulk(data) => {
+                buf.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+                buf.extend_from_slice(data);
+                buf.extend_from_slice(b"\r\n");
+            }
+            Reply::Null if protocol >= 3 => buf.extend_from_slice(b"_\r\n"),
+            Reply::Null => buf.extend_from_slice(b"$-1\r\n"),
+            Reply::Array(items) => {
+                buf.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(protocol, buf);
+                }
+            }
+            Reply::Map(pairs) => {
+                if protocol >= 3 {
+                    buf.extend_from_slice(format!("%{}\r\n", pairs.len()).as_bytes());
+                } else {
+                    // RESP2 has no map type, send as a flat array of key, value, ...
+                    buf.extend_from_slice(format!("*{}\r\n", pairs.len() * 2).as_bytes());
+                }
+                for (key, value) in pairs {
+                    key.encode(protocol, buf);
+                    value.encode(protocol, buf);
+                }
+            }
+        }
+    }
+}
+
+/// Try to parse a full command from the buffer
+///
+/// Returns `Ok(None)` if more data is needed, otherwise the command arguments
+/// and the number of bytes consumed. Supports both RESP arrays of bulk strings
+/// and inline commands (E.g. from `telnet` or `nc`).
+///
+/// Commands with more than 1M arguments, an argument over 512 MB or a line over 64 KB are a protocol error
+fn parse_command(buf: &[u8]) -> Result<Option<(Command, usize)>, String> {
+    let (line, mut pos) = match read_line(buf, 0)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.to_vec())
+            .collect();
+        return Ok(Some((args, pos)));
+    }
+
+    let count = parse_int(&line[1..])?;
+    if count > MAX_ARGS {
+        return Err("Protocol error: invalid multibulk length".to_owned());
+    }
+    // Grown as arguments are received, rather than trusting the count
+    let mut args = vec![];
+    for _ in 0..count {
+        let (line, next) = match read_line(buf, pos)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if line.first() != Some(&b'$') {
+            return Err("Protocol error: expected '$'".to_owned());
+        }
+        let length = parse_int(&line[1..])?;
+        if !(0..=MAX_BULK_LENGTH).contains(&length) {
+            return Err("Protocol error: invalid bulk length".to_owned());
+        }
+        let end = next + length as usize;
+        if buf.len() < end + 2 {
+            return Ok(None);
+        }
+        args.push(buf[next..end].to_vec());
+        pos = end + 2;
+    }
+    Ok(Some((args, pos)))
+}
+
+/// Read a CRLF (or bare LF) terminated line starting at `start`, returning it (without the line ending)
+/// and the position after the line ending
+///
+/// Lines over 64 KB are a protocol error, so a client that never ends a line can't grow the buffer without bound
+fn read_line(buf: &[u8], start: usize) -> Result<Option<(&[u8], usize)>, String> {
+    let end = match buf[start..].iter().position(|b| *b == b'\n') {
+        Some(end) if end <= MAX_LINE_LENGTH => start + end,
+        None if buf.len() - start <= MAX_LINE_LENGTH => return Ok(None),
+        _ => return Err("Protocol error: too big inline request".to_owned()),
+    };
+    let line = &buf[start..end];
+    let line = match line.split_last() {
+        Some((b'\r', line)) => line,
+        _ => line,
+    };
+    Ok(Some((line, end + 1)))
+}
+
+fn parse_int(data: &[u8]) -> Result<i64, String> {
+    std::str::from_utf8(data)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| "Protocol error: invalid length".to_owned())
+}
+
+/// Match a key against a Redis glob-style pattern (`*`, `?`, `[abc]`, `[^a-z]`, `\x`)
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((b'*', rest)) => (0..=key.len()).any(|i| glob_match(rest, &key[i..])),
+        Some((b'?', rest)) => !key.is_empty() && glob_match(rest, &key[1..]),
+        Some((b'[', rest)) => {
+            let end = match rest.iter().position(|b| *b == b']') {
+                Some(end) => end,
+                None => return !key.is_empty() && key[0] == b'[' && glob_match(rest, &key[1..]),
+            };
+            let (negate, class) = match rest[..end].split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, &rest[..end]),
+            };
+            let c = match key.first() {
+                Some(c) => *c,
+                None => return false,
+            };
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    matched |= class[i] <= c && c <= class[i + 2];
+                    i += 3;
+                } else {
+                    matched |= class[i] == c;
+                    i += 1;
+                }
+            }
+            matched != negate && glob_match(&rest[end + 1..], &key[1..])
+        }
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            !key.is_empty() && key[0] == rest[0] && glob_match(&rest[1..], &key[1..])
+        }
+        Some((c, rest)) => !key.is_empty() && key[0] == *c && glob_match(rest, &key[1..]),
+    }
+}
+
+/// Keys are stored as strings, values are binary-safe
+fn to_key(arg: &[u8]) -> Result<String, Reply> {
+    String::from_utf8(arg.to_vec()).map_err(|_| Reply::error("key is not valid UTF-8"))
+}
+
+/// A key to write or remove, which can't be one reserved for blobs
+fn to_writable_key(arg: &[u8]) -> Result<String, Reply> {
+    let key = to_key(arg)?;
+    blob::check_key(&key).map_err(|e| Reply::error(&e.to_string()))?;
+    Ok(key)
+}
+
+/// State for a single client connection
+struct Connection {
+    /// Negotiated protocol version (2 unless changed by `HELLO 3`)
+    protocol: u8,
+}
+
+impl Connection {
+    fn new() -> Self {
+        Self { protocol: 2 }
+    }
+
+    /// Run a command against the store, returning the reply and any updates for BGP peers
+    fn execute(&mut self, args: &[Vec<u8>], store: &mut KvStore) -> (Reply, Vec<Update>) {
+        let mut updates = vec![];
+        let reply = match self.dispatch(args, store, &mut updates) {
+            Ok(reply) => reply,
+            Err(reply) => reply,
+        };
+        (reply, updates)
+    }
+
+    fn dispatch(
+        &mut self,
+        args: &[Vec<u8>],
+        store: &mut KvStore,
+        updates: &mut Vec<Update>,
+    ) -> Result<Reply, Reply> {
+        let (command, args) = match args.split_first() {
+            Some((command, args)) => (String::from_utf8_lossy(command).to_uppercase(), args),
+            None => return Err(Reply::error("empty command")),
+        };
+        debug!("RESP: {} ({} args)", command, args.len());
+        let reply = match (command.as_str(), args.len()) {
+            ("PING", 0) => Reply::Simple("PONG"),
+            ("PING", 1) | ("ECHO", 1) => Reply::bulk(&args[0]),
+            ("QUIT", _) => Reply::Simple("OK"),
+            ("COMMAND", _) => Reply::Array(vec![]),
+            ("HELLO", n) => {
+                if n > 0 {
+                    match args[0].as_slice() {
+                        b"2" => self.protocol = 2,
+                        b"3" => self.protocol = 3,
+                        _ => {
+                            return Err(Reply::Error(
+                                "NOPROTO unsupported protocol version".to_owned(),
+                            ))
+                        }
+                    }
+                }
+                Reply::Map(vec![
+                    (Reply::bulk("server"), Reply::bulk("kvs-bgp")),
+                    (
+                        Reply::bulk("version"),
+                        Reply::bulk(env!("CARGO_PKG_VERSION")),
+                    ),
+                    (Reply::bulk("proto"), Reply::Integer(self.protocol as i64)),
+                    (Reply::bulk("mode"), Reply::bulk("standalone")),
+                    (Reply::bulk("role"), Reply::bulk("master")),
+                    (Reply::bulk("modules"), Reply::Array(vec![])),
+                ])
+            }
+            ("GET", 1) => store
+                .get(&to_key(&args[0])?)
+                .map(|payload| Reply::Bulk(payload.data))
+                .unwrap_or(Reply::Null),
+            ("SET", n) if n >= 2 => {
+                let options = self.set_options(&args[2..])?;
+                let update = store
+                    .insert_with(to_writable_key(&args[0])?, args[1].clone().into(), options)
+                    .map_err(|e| Reply::error(&e.to_string()))?;
+                updates.push(update);
+                Reply::Simple("OK")
+            }
+            ("DEL", n) if n > 0 => {
+                let mut removed = 0;
+                for key in args {
+                    let result = store
+                        .remove(&to_writable_key(key)?)
+                        .map_err(|e| Reply::error(&e.to_string()))?;
+                    if let Some(update) = result {
+                        updates.push(update);
+                        removed += 1;
+                    }
+                }
+                Reply::Integer(removed)
+            }
+            ("EXISTS", n) if n > 0 => {
+                let mut found = 0;
+                for key in args {
+                    if store.get(&to_key(key)?).is_some() {
+                        found += 1;
+                    }
+                }
+                Reply::Integer(found)
+            }
+            ("TTL", 1) | ("PTTL", 1) => match store.get_pair(&to_key(&args[0])?) {
+                None => Reply::Integer(-2),
+                Some(kv) => match kv.expires() {
+                    None => Reply::Integer(-1),
+                    Some(expires) => {
+                        let remaining = expires.saturating_sub(wall_clock());
+                        if command == "TTL" {
+                            Reply::Integer(((remaining + 500) / 1000) as i64)
+                        } else {
+                            Reply::Integer(remaining as i64)
+                        }
+                    }
+                },
+            },
+            ("MGET", n) if n > 0 => Reply::Array(
+                args.iter()
+                    .map(|key| {
+                        to_key(key)
+                            .ok()
+                            .and_then(|key| store.get(&key))
+                            .map(|payload| Reply::Bulk(payload.data))
+                            .unwrap_or(Reply::Null)
+                    })
+                    .collect(),
+            ),
+            ("MSET", n) if n > 0 && n % 2 == 0 => {
+                // Like Redis, the last value of a repeated key wins
+                let pairs = args
+                    .chunks(2)
+                    .map(|pair| Ok((to_writable_key(&pair[0])?, pair[1].clone().into())))
+                    .collect::<Result<BTreeMap<_, _>, Reply>>()?;
+                let update = store
+                    .insert_batch(pairs.into_iter().collect(), InsertOptions::default())
+                    .map_err(|e| Reply::error(&e.to_string()))?;
+                updates.push(update);
+                Reply::Simple("OK")
+            }
+            ("KEYS", 1) => {
+                let keys = store
+                    .keys()
+                    .filter(|key| glob_match(&args[0], key.as_bytes()))
+                    .map(Reply::bulk)
+                    .collect();
+                Reply::Array(keys)
+            }
+            ("SCAN", n) if n % 2 == 1 => self.scan(args, store)?,
+            ("PING", _)
+            | ("ECHO", _)
+            | ("GET", _)
+            | ("SET", _)
+            | ("DEL", _)
+            | ("EXISTS", _)
+            | ("TTL", _)
+            | ("PTTL", _)
+            | ("MGET", _)
+            | ("MSET", _)
+            | ("KEYS", _)
+            | ("SCAN", _) => Reply::wrong_args(&command),
+            _ => Reply::Error(format!("ERR unknown command '{}'", command)),
+        };
+        Ok(reply)
+    }
+
+    /// Options of `SET key value [EX seconds | PX milliseconds]`
+    fn set_options(&self, args: &[Vec<u8>]) -> Result<InsertOptions, Reply> {
+        let mut options = InsertOptions::default();
+        let mut args = args.iter();
+        while let Some(option) = args.next() {
+            let unit: fn(u64) -> Duration = match option.to_ascii_uppercase().as_slice() {
+                b"EX" => Duration::from_secs,
+                b"PX" => Duration::from_millis,
+                _ => return Err(Reply::error("syntax error")),
+            };
+            let ttl = args
+                .next()
+                .and_then(|ttl| std::str::from_utf8(ttl).ok())
+                .and_then(|ttl| ttl.parse::<u64>().ok())
+                .filter(|ttl| *ttl > 0)
+                .ok_or_else(|| Reply::error("invalid expire time in 'set' command"))?;
+            options.ttl = Some(unit(ttl));
+        }
+        Ok(options)
+    }
+
+    /// `SCAN cursor [MATCH pattern] [COUNT count]`
+    ///
+    /// The cursor is an offset into the sorted keys of the store
+    fn scan(&self, args: &[Vec<u8>], store: &KvStore) -> Result<Reply, Reply> {
+        let cursor: usize = std::str::from_utf8(&args[0])
+            .ok()
+            .and_then(|c| c.parse().ok())
+            .ok_or_else(|| Reply::error("invalid cursor"))?;
+        let mut pattern: &[u8] = b"*";
+        let mut count = SCAN_COUNT;
+        for option in args[1..].chunks(2) {
+            match option[0].to_ascii_uppercase().as_slice() {
+                b"MATCH" => pattern = &option[1],
+                b"COUNT" => {
+                    count = std::str::from_utf8(&option[1])
+                        .ok()
+                        .and_then(|c| c.parse().ok())
+                        .filter(|c| *c > 0)
+                        .ok_or_else(|| Reply::error("value is not an integer or out of range"))?;
+                }
+                _ => return Err(Reply::error("syntax error")),
+            }
+        }
+
+        let keys: Vec<&str> = store.keys().collect();
+        let end = (cursor + count).min(keys.len());
+        let next = if end >= keys.len() { 0 } else { end };
+        let page = keys
+            .get(cursor..end)
+            .unwrap_or(&[])
+            .iter()
+            .filter(|key| glob_match(pattern, key.as_bytes()))
+            .map(Reply::bulk)
+            .collect();
+        Ok(Reply::Array(vec![
+            Reply::bulk(next.to_string()),
+            Reply::Array(page),
+        ]))
+    }
+}
+
+/// Handle commands from a single client until the connection is closed
+async fn handle_connection(
+    mut stream: TcpStream,
+    store: Store,
+    channel: UpdateChannel,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut conn = Connection::new();
+    let mut buf = BytesMut::with_capacity(4096);
+    let mut out: Vec<u8> = Vec::with_capacity(4096);
+    loop {
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Ok(());
+        }
+        loop {
+            let (args, consumed) = match parse_command(&buf) {
+                Ok(Some(command)) => command,
+                Ok(None) => break,
+                Err(e) => {
+                    Reply::Error(format!("ERR {}", e)).encode(conn.protocol, &mut out);
+                    stream.write_all(&out).await?;
+                    return Ok(());
+                }
+            };
+            buf.advance(consumed);
+            if args.is_empty() {
+                continue;
+            }
+            let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+            let (reply, updates) = {
+                let mut store = store.write().await;
+                // Writes block on syncing to disk (if the store is persistent)
+                task::block_in_place(|| conn.execute(&args, &mut store))
+            };
+            for update in updates {
+                channel.send(update)?;
+            }
+            reply.encode(conn.protocol, &mut out);
+            if quit {
+                stream.write_all(&out).await?;
+                return Ok(());
+            }
+        }
+        stream.write_all(&out).await?;
+        out.clear();
+    }
+}
+
+/// Serve the RESP front-end on the given address
+///
+/// Writes will trigger BGP updates to peers (same as the HTTP API)
+pub async fn serve(addr: SocketAddr, store: Store, channel: UpdateChannel) -> std::io::Result<()> {
+    let mut listener = TcpListener::bind(addr).await?;
+    info!("Starting RESP API on {}", addr);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        debug!("RESP client connected: {}", peer);
+        let store = store.clone();
+        let channel = channel.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, store, channel).await {
+                warn!("RESP client {} error: {}", peer, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> Vec<Vec<u8>> {
+        args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+    }
+
+    fn encode(reply: &Reply, protocol: u8) -> String {
+        let mut buf = vec![];
+        reply.encode(protocol, &mut buf);
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn parse_resp() {
+        let buf = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n*1\r\n$4\r\nPING\r\n";
+        let (args, consumed) = parse_command(buf).unwrap().unwrap();
+        assert_eq!(args, command(&["SET", "key", "value"]));
+        let (args, _) = parse_command(&buf[consumed..]).unwrap().unwrap();
+        assert_eq!(args, command(&["PING"]));
+
+        // Partial commands need more data
+        assert!(parse_command(&buf[..20]).unwrap().is_none());
+        assert!(parse_command(b"*1\r\n$4\r\nPI").unwrap().is_none());
+        assert!(parse_command(b"*1\r\n:4\r\n").is_err());
+
+        // Oversized commands are refused before buffering them
+        assert!(parse_command(b"*1048577\r\n").is_err());
+        assert!(parse_command(b"*9223372036854775807\r\n").is_err());
+        assert!(parse_command(b"*1\r\n$536870913\r\n").is_err());
+        assert!(parse_command(b"*1\r\n$536870912\r\n").unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_inline() {
+        let (args, consumed) = parse_command(b"GET  key\r\n").unwrap().unwrap();
+        assert_eq!(args, command(&["GET", "key"]));
+        assert_eq!(consumed, 10);
+
+        // Bare LF line endings (E.g. from `nc`)
+        let (args, consumed) = parse_command(b"SET key value\nGET key\n").unwrap().unwrap();
+        assert_eq!(args, command(&["SET", "key", "value"]));
+        assert_eq!(consumed, 14);
+        assert!(parse_command(b"*1\n$4\nPING\r\n").unwrap().is_some());
+
+        // Lines are capped, whether or not they ever end
+        let long = vec![b'a'; MAX_LINE_LENGTH + 1];
+        assert!(parse_command(&long).is_err());
+        assert!(parse_command(&long[..MAX_LINE_LENGTH]).unwrap().is_none());
+        assert!(parse_command(&[&long[..], b"\r\n"].concat()).is_err());
+    }
+
+    #[test]
+    fn encode_replies() {
+        assert_eq!(encode(&Reply::Null, 2), "$-1\r\n");
+        assert_eq!(encode(&Reply::Null, 3), "_\r\n");
+        let array = Reply::Array(vec![Reply::bulk("a"), Reply::Integer(1)]);
+        assert_eq!(encode(&array, 2), "*2\r\n$1\r\na\r\n:1\r\n");
+        let map = Reply::Map(vec![(Reply::bulk("a"), Reply::Integer(1))]);
+        assert_eq!(encode(&map, 2), "*2\r\n$1\r\na\r\n:1\r\n");
+        assert_eq!(encode(&map, 3), "%1\r\n$1\r\na\r\n:1\r\n");
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match(b"*", b"anything"));
+        assert!(glob_match(b"favorite::*", b"favorite::food"));
+        assert!(!glob_match(b"favorite::*", b"name"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-c]llo", b"hbllo"));
+        assert!(glob_match(b"h\\*llo", b"h*llo"));
+        assert!(!glob_match(b"h\\*llo", b"hello"));
+    }
+
+    #[test]
+    fn execute_commands() {
+        let mut store = KvStore::new();
+        let mut conn = Connection::new();
+
+        let (reply, updates) = conn.execute(&command(&["set", "name", "Mat"]), &mut store);
+        assert_eq!(reply, Reply::Simple("OK"));
+        assert_eq!(updates.len(), 1);
+
+        let (reply, _) = conn.execute(&command(&["GET", "name"]), &mut store);
+        assert_eq!(reply, Reply::bulk("Mat"));
+        let (reply, _) = conn.execute(&command(&["GET", "missing"]), &mut store);
+        assert_eq!(reply, Reply::Null);
+
+        let (_, updates) = conn.execute(
+            &command(&[
+                "MSET",
+                "favorite::food",
+                "Pizza",
+                "favorite::drink",
+                "Scotch",
+            ]),
+            &mut store,
+        );
+        assert_eq!(updates.len(), 1);
+        // Nothing is written if any pair fails
+        let (reply, updates) = conn.execute(
+            &command(&["MSET", "favorite::color", "Blue", "blob::name", "Mat"]),
+            &mut store,
+        );
+        assert!(matches!(reply, Reply::Error(_)));
+        assert!(updates.is_empty());
+        let (reply, _) = conn.execute(&command(&["EXISTS", "favorite::color"]), &mut store);
+        assert_eq!(reply, Reply::Integer(0));
+        let (reply, _) = conn.execute(&command(&["MGET", "name", "missing"]), &mut store);
+        assert_eq!(reply, Reply::Array(vec![Reply::bulk("Mat"), Reply::Null]));
+        let (reply, _) = conn.execute(&command(&["EXISTS", "name", "missing"]), &mut store);
+        assert_eq!(reply, Reply::Integer(1));
+
+        let (reply, _) = conn.execute(&command(&["KEYS", "favorite::*"]), &mut store);
+        assert_eq!(
+            reply,
+            Reply::Array(vec![
+                Reply::bulk("favorite::drink"),
+                Reply::bulk("favorite::food")
+            ])
+        );
+
+        let (reply, updates) = conn.execute(&command(&["DEL", "name", "missing"]), &mut store);
+        assert_eq!(reply, Reply::Integer(1));
+        assert_eq!(updates.len(), 1);
+
+        let (reply, _) = conn.execute(&command(&["SET", "token", "abc", "EX", "30"]), &mut store);
+        assert_eq!(reply, Reply::Simple("OK"));
+        let (reply, _) = conn.execute(&command(&["TTL", "token"]), &mut store);
+        assert_eq!(reply, Reply::Integer(30));
+        let (reply, _) = conn.execute(&command(&["TTL", "favorite::food"]), &mut store);
+        assert_eq!(reply, Reply::Integer(-1));
+        let (reply, _) = conn.execute(&command(&["PTTL", "missing"]), &mut store);
+        assert_eq!(reply, Reply::Integer(-2));
+        let (reply, _) = conn.execute(&command(&["SET", "token", "abc", "EX"]), &mut store);
+        assert!(matches!(reply, Reply::Error(_)));
+
+        // Blob keys can't be written as plain pairs
+        let (reply, updates) = conn.execute(&command(&["SET", "blob::name", "Mat"]), &mut store);
+        assert!(matches!(reply, Reply::Error(_)));
+        assert!(updates.is_empty());
+        let (reply, _) = conn.execute(&command(&["DEL", "blob-chunk::name::0"]), &mut store);
+        assert!(matches!(reply, Reply::Error(_)));
+
+        let (reply, _) = conn.execute(&command(&["GET"]), &mut store);
+        assert!(matches!(reply, Reply::Error(_)));
+        let (reply, _) = conn.execute(&command(&["FLUSHALL"]), &mut store);
+        assert!(matches!(reply, Reply::Error(_)));
+    }
+
+    #[test]
+    fn scan_keys() {
+        let mut store = KvStore::new();
+        let mut conn = Connection::new();
+        for key in &["a", "b", "c"] {
+            store.insert(key.to_string(), "value".into()).unwrap();
+        }
+
+        let (reply, _) = conn.execute(&command(&["SCAN", "0", "COUNT", "2"]), &mut store);
+        assert_eq!(
+            reply,
+            Reply::Array(vec![
+                Reply::bulk("2"),
+                Reply::Array(vec![Reply::bulk("a"), Reply::bulk("b")])
+            ])
+        );
+        let (reply, _) = conn.execute(&command(&["SCAN", "2", "COUNT", "2"]), &mut store);
+        assert_eq!(
+            reply,
+            Reply::Array(vec![Reply::bulk("0"), Reply::Array(vec![Reply::bulk("c")])])
+        );
+    }
+
+    #[test]
+    fn hello_protocol() {
+        let mut store = KvStore::new();
+        let mut conn = Connection::new();
+        let (reply, _) = conn.execute(&command(&["HELLO", "3"]), &mut store);
+        assert!(matches!(reply, Reply::Map(_)));
+        assert_eq!(conn.protocol, 3);
+        let (reply, _) = conn.execute(&command(&["HELLO", "4"]), &mut store);
+        assert!(matches!(reply, Reply::Error(e) if e.starts_with("NOPROTO")));
+        assert_eq!(conn.protocol, 3);
+    }
+}
@@ -1,34 +1,256 @@
-use std::collections::HashMap;
-use std::convert::TryInto;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::kv::{KeyValue, RouteCollection};
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio::{task, time};
+
+use crate::clock::{wall_clock, HybridClock, Version};
+use crate::crypto::{ClusterKey, NodeKey, PublicKey};
+use crate::kv::{AddrPrefix, KeyValue, Payload, RouteCollection};
+use crate::persist::{LogEntry, Persistence, StoredPair};
 use crate::KvsError;
 
+/// Interval between sweeps for expired pairs
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of events buffered for each subscriber before it starts missing events
+const EVENTS_CAPACITY: usize = 1024;
+
+/// Options for inserting a [KeyValue](struct.KeyValue.html) pair
+#[derive(Debug, Default)]
+pub struct InsertOptions {
+    /// Categories to tag the pair with (`None` keeps the existing categories when updating a key)
+    pub categories: Option<Vec<u16>>,
+    /// How long until the pair expires on every node (`None` never expires, even if the existing value would)
+    pub ttl: Option<Duration>,
+    /// Bind the pair to this node's BGP sessions, so peers remove it once all their sessions to this node are down
+    pub ephemeral: bool,
+    /// Only insert if the key is in the expected state (E.g. for compare-and-swap)
+    pub precondition: Option<Precondition>,
+}
+
+/// Expected state of a key for a conditional write
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Precondition {
+    /// The key must not exist (insert-if-absent)
+    Absent,
+    /// The key must exist, with any version
+    Present,
+    /// The key must exist with exactly this version (compare-and-swap)
+    Version(Version),
+}
+
 /// Front-end Key/Value store for [KeyValue](struct.KeyValue.html) pairs that can be encoded/decoded as
 /// BGP Update announcements
 ///
 /// As contained [KeyValue](struct.KeyValue.html)s are added/updated/removed, serialization
 pub struct KvStore {
     /// Internal storage of [Key](struct.Key.html) -> [KeyValue](struct.KeyValue.html) pairs
-    inner: HashMap<String, KeyValue<String, String>>,
+    ///
+    /// Ordered by key for listing/scanning keys by prefix
+    inner: BTreeMap<String, KeyValue<String, Payload>>,
+    /// Key hash -> Key of every stored pair, for detecting keys with colliding hashes
+    hashes: HashMap<u64, String>,
+    /// IPv4 id (origin tag & slot) -> Number of stored pairs with it, for giving local writes IPv4 slots
+    /// whose routes don't clash with another pair's
+    ipv4_ids: HashMap<u16, usize>,
+    /// Clock for versioning local writes, kept ahead of every version seen
+    clock: HybridClock,
+    /// Optional on-disk log & snapshots, for restoring the store after a restart
+    persistence: Option<Persistence>,
+    /// Broadcasts a [StoreEvent](enum.StoreEvent.html) for every change to subscribers
+    events: broadcast::Sender<StoreEvent>,
+    /// Leading bits of the routes this store's pairs are announced (and received) as
+    addr_prefix: AddrPrefix,
+    /// Optional key to encrypt values announced to peers with
+    cluster_key: Option<ClusterKey>,
+    /// Optional key to sign pairs announced to peers with
+    node_key: Option<NodeKey>,
+    /// Keys of the nodes trusted to write pairs, if signatures are enforced
+    trusted_keys: Option<HashSet<PublicKey>>,
+    /// Categories of pairs to accept from peers (all pairs are accepted if empty)
+    subscriptions: Vec<u16>,
+    /// Keys of pairs written by other nodes that were restored from disk, and haven't been
+    /// announced by peers since (so may have been removed while this node was down)
+    provisional: HashSet<String>,
 }
 
 impl KvStore {
     /// Create a new, empty KvStore
     pub fn new() -> Self {
         Self {
-            inner: HashMap::with_capacity(16),
+            inner: BTreeMap::new(),
+            hashes: HashMap::new(),
+            ipv4_ids: HashMap::new(),
+            clock: HybridClock::with_random_origin(),
+            persistence: None,
+            events: broadcast::channel(EVENTS_CAPACITY).0,
+            addr_prefix: AddrPrefix::default(),
+            cluster_key: None,
+            node_key: None,
+            trusted_keys: None,
+            subscriptions: vec![],
+            provisional: HashSet::new(),
+        }
+    }
+
+    /// Open a persistent KvStore in the given data directory
+    ///
+    /// Any existing snapshot & write-ahead log in the directory are replayed to restore
+    /// the store, and every following change is logged. A new snapshot is taken after
+    /// `snapshot_interval` changes have been logged. The origin id of the previous run is
+    /// restored too, so this node keeps withdrawing (and re-announcing) the pairs it wrote.
+    /// Pairs written by other nodes are restored as provisional, until peers announce them again
+    /// (see [remove_provisional](#method.remove_provisional)).
+    ///
+    /// Changes block on syncing the log to disk, so async tasks should make them with
+    /// [block_in_place](https://docs.rs/tokio/0.2/tokio/task/fn.block_in_place.html)
+    pub fn open<P: AsRef<Path>>(dir: P, snapshot_interval: usize) -> Result<Self, KvsError> {
+        let (mut persistence, entries) = Persistence::open(dir, snapshot_interval)?;
+        let mut store = Self::new();
+        match persistence.origin() {
+            Some(origin) => store.clock.set_origin(origin),
+            None => persistence.set_origin(store.origin())?,
+        }
+        for entry in entries {
+            let (pairs, removed) = match entry {
+                LogEntry::Insert(pair) => (vec![pair], vec![]),
+                LogEntry::Batch { pairs, removed } => (pairs, removed),
+                LogEntry::Remove(key) => (vec![], vec![key]),
+            };
+            for key in removed {
+                if let Some(removed) = store.inner.remove(&key) {
+                    store.unindex(&removed);
+                }
+            }
+            for pair in pairs {
+                let kv: KeyValue<String, Payload> = pair.into();
+                if !store.clock.observe(kv.version()) {
+                    warn!(
+                        "Restored {} version {} is ahead of the clock",
+                        kv.key(),
+                        kv.version()
+                    );
+                }
+                store.index(&kv);
+                if let Some(old) = store.inner.insert(kv.key().clone(), kv) {
+                    store.release_ipv4_id(old.ipv4_id());
+                }
+            }
+        }
+        // Ephemeral pairs were bound to BGP sessions of the previous run, so aren't restored
+        let ephemeral: Vec<String> = store
+            .inner
+            .values()
+            .filter(|kv| kv.is_ephemeral())
+            .map(|kv| kv.key().clone())
+            .collect();
+        for key in ephemeral {
+            if let Some(removed) = store.inner.remove(&key) {
+                store.unindex(&removed);
+            }
+        }
+        let origin = store.origin();
+        store.provisional = store
+            .inner
+            .values()
+            .filter(|kv| kv.version().origin != origin)
+            .map(|kv| kv.key().clone())
+            .collect();
+        info!(
+            "Restored {} pairs from disk ({} written by other nodes)",
+            store.len(),
+            store.provisional.len()
+        );
+        store.persistence = Some(persistence);
+        // Compact the replayed log
+        store.snapshot();
+        Ok(store)
+    }
+
+    /// Id of this node, used as the origin of (and to break ties between) versions of local writes
+    pub fn origin(&self) -> u32 {
+        self.clock.origin()
+    }
+
+    /// Set the id of this node (a random id is used by default, or the id of the previous run
+    /// for a persistent store)
+    ///
+    /// Should be unique per node, so concurrent writes to a key are resolved the same way everywhere
+    pub fn set_origin(&mut self, origin: u32) {
+        self.clock.set_origin(origin);
+        if let Some(persistence) = self.persistence.as_mut() {
+            if let Err(err) = persistence.set_origin(origin) {
+                warn!("Could not persist origin: {}", err);
+            }
         }
     }
 
-    /// Number of unique [Key](struct.Key.html)s in this store
+    /// [AddrPrefix](../kv/struct.AddrPrefix.html) of the routes this store's pairs are announced as
+    pub fn addr_prefix(&self) -> AddrPrefix {
+        self.addr_prefix
+    }
+
+    /// Set the [AddrPrefix](../kv/struct.AddrPrefix.html) of the routes this store's pairs are announced as
+    /// (`BF51` by default)
+    ///
+    /// Every node of the cluster needs the same prefix, and a node can take part in several clusters
+    /// with a store per prefix
+    pub fn set_addr_prefix(&mut self, addr_prefix: AddrPrefix) {
+        self.addr_prefix = addr_prefix;
+    }
+
+    /// Key to encrypt values announced to peers with (and decrypt values from peers with), if any
+    pub fn cluster_key(&self) -> Option<&ClusterKey> {
+        self.cluster_key.as_ref()
+    }
+
+    /// Set the key to encrypt values announced to peers with (values are sent in the clear by default)
+    ///
+    /// Every node of the cluster needs the same key to read the values
+    pub fn set_cluster_key(&mut self, cluster_key: Option<ClusterKey>) {
+        self.cluster_key = cluster_key;
+    }
+
+    /// Set the key to sign pairs announced to peers with (pairs are unsigned by default)
+    pub fn set_node_key(&mut self, node_key: Option<NodeKey>) {
+        self.node_key = node_key;
+    }
+
+    /// Set the keys of the nodes trusted to write pairs
+    ///
+    /// When set, pairs from peers that are unsigned or signed by any other key are refused
+    /// (signatures aren't enforced by default)
+    pub fn set_trusted_keys(&mut self, trusted_keys: Option<Vec<PublicKey>>) {
+        self.trusted_keys = trusted_keys.map(|keys| keys.into_iter().collect());
+    }
+
+    /// Categories of pairs to accept from peers (all pairs are accepted if empty)
+    pub fn subscriptions(&self) -> &[u16] {
+        &self.subscriptions
+    }
+
+    /// Set the categories of pairs to accept from peers, so pairs tagged with none of them
+    /// are ignored when received (all pairs are accepted by default)
+    pub fn set_subscriptions(&mut self, subscriptions: Vec<u16>) {
+        self.subscriptions = subscriptions;
+    }
+
+    /// Number of unique [Key](struct.Key.html)s in this store (not counting expired pairs not yet removed)
     pub fn len(&self) -> usize {
-        self.inner.len()
+        let now = wall_clock();
+        self.inner.values().filter(|kv| !kv.is_expired(now)).count()
     }
 
-    /// Is this store empty?
+    /// Is this store empty (or only holding expired pairs not yet removed)?
     pub fn is_empty(&self) -> bool {
-        self.inner.is_empty()
+        let now = wall_clock();
+        self.inner.values().all(|kv| kv.is_expired(now))
     }
 
     /// Insert a new [Key](struct.Key.html) / [Value](struct.Value.html) pair
@@ -36,55 +258,722 @@ impl KvStore {
     /// Will construct a [KeyValue](struct.KeyValue.html) to store and queue for BGP synchronization.
     /// If the key already exists in this KvStore, will updated the existing value and also queue
     /// a BGP withdraw for the old [KeyValue](struct.KeyValue.html)
-    pub fn insert(&mut self, key: String, value: String) -> Result<Update, KvsError> {
-        if let Some(existing) = self.inner.get_mut(&key) {
-            // Temporarily cast away mut for TryFrom<&KeyValue> to match
-            let withdraw: RouteCollection = (&*existing).try_into().map_err(|_| {
-                KvsError::EncodeError(format!("Could not encode: {}", existing.to_string()))
-            })?;
-            existing.update(value);
-            let announce: RouteCollection = (&*existing).try_into().map_err(|_| {
-                KvsError::EncodeError(format!("Could not encode: {}", existing.to_string()))
-            })?;
-            Ok(Update::with_both(announce, withdraw))
-        } else {
-            let kv = KeyValue::new(key.clone(), value);
-            let announce: RouteCollection = (&kv).try_into()?;
-            self.inner.insert(key, kv);
-            Ok(Update::with_announce(announce))
+    pub fn insert(&mut self, key: String, value: Payload) -> Result<Update, KvsError> {
+        self.insert_with(key, value, InsertOptions::default())
+    }
+
+    /// Insert a new [Key](struct.Key.html) / [Value](struct.Value.html) pair with [InsertOptions](struct.InsertOptions.html)
+    ///
+    /// Same as [insert](#method.insert), but can tag the [KeyValue](struct.KeyValue.html) with categories,
+    /// set a TTL (after which the pair is removed from every node) or only insert given a [Precondition](enum.Precondition.html)
+    pub fn insert_with(
+        &mut self,
+        key: String,
+        value: Payload,
+        options: InsertOptions,
+    ) -> Result<Update, KvsError> {
+        let version = self.clock.now();
+        let (kv, update) =
+            self.prepare_insert(key, value, &options, version, 0, &mut HashSet::new())?;
+        self.insert_entry(kv, EventSource::Local)?;
+        Ok(update)
+    }
+
+    /// Insert a batch of [Key](struct.Key.html) / [Value](struct.Value.html) pairs, with the same
+    /// [InsertOptions](struct.InsertOptions.html) for each pair
+    ///
+    /// Every pair is written with the same version and tagged with the size of the batch, so peers
+    /// only apply the pairs once the whole batch has been received. Nothing is written if any pair
+    /// fails its precondition (or collides with another key).
+    /// The returned [Update](struct.Update.html) announces every pair ahead of any withdraws.
+    pub fn insert_batch(
+        &mut self,
+        pairs: Vec<(String, Payload)>,
+        options: InsertOptions,
+    ) -> Result<Update, KvsError> {
+        self.write_batch(pairs, &[], options)
+    }
+
+    /// Insert a batch of pairs like [insert_batch](#method.insert_batch), and remove other keys along with it
+    ///
+    /// The removals are logged with the batch, and their withdraws sent in the same
+    /// [Update](struct.Update.html) (after the batch is announced). Keys that don't exist are ignored
+    pub fn write_batch(
+        &mut self,
+        pairs: Vec<(String, Payload)>,
+        remove: &[String],
+        options: InsertOptions,
+    ) -> Result<Update, KvsError> {
+        if pairs.is_empty() {
+            return Err(KvsError::InvalidBatch("No pairs to insert".to_owned()));
+        }
+        let batch_size: u16 = pairs.len().try_into().map_err(|_| {
+            KvsError::InvalidBatch(format!("{} pairs (max {})", pairs.len(), u16::MAX))
+        })?;
+        let version = self.clock.now();
+        let mut batch_hashes: HashMap<u64, String> = HashMap::new();
+        let mut ipv4_slots = HashSet::new();
+        let mut prepared = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            let (kv, update) =
+                self.prepare_insert(key, value, &options, version, batch_size, &mut ipv4_slots)?;
+            if let Some(other) = batch_hashes.insert(kv.key_hash(), kv.key().clone()) {
+                return Err(KvsError::InvalidBatch(format!(
+                    "{} and {} can't be in the same batch",
+                    other,
+                    kv.key()
+                )));
+            }
+            prepared.push((kv, update));
+        }
+        let mut removed = Vec::with_capacity(remove.len());
+        for key in remove {
+            if prepared.iter().any(|(kv, _)| kv.key() == key) {
+                return Err(KvsError::InvalidBatch(format!(
+                    "{} can't be written & removed in the same batch",
+                    key
+                )));
+            }
+            if let Some(existing) = self.inner.get(key) {
+                removed.push((key.clone(), self.encode(existing)?));
+            }
+        }
+        // The batch is logged as one entry, so it's never partially restored
+        self.persist(LogEntry::Batch {
+            pairs: prepared.iter().map(|(kv, _)| kv.into()).collect(),
+            removed: removed.iter().map(|(key, _)| key.clone()).collect(),
+        })?;
+        let mut announce = vec![];
+        let mut withdraw = vec![];
+        for (kv, update) in prepared {
+            announce.extend(update.announce);
+            withdraw.extend(update.withdraw);
+            self.store_entry(kv, EventSource::Local);
+        }
+        for (key, routes) in removed {
+            self.take_entry(&key, Some(EventSource::Local));
+            withdraw.push(routes);
         }
+        self.snapshot_if_needed();
+        Ok(Update::with_both(
+            RouteCollection::concat(announce),
+            RouteCollection::concat(withdraw),
+        ))
+    }
+
+    /// Build the [KeyValue](struct.KeyValue.html) for a local write and the [Update](struct.Update.html)
+    /// to send peers, without storing it
+    ///
+    /// `ipv4_slots` are the IPv4 slots given to the other pairs of the same write
+    fn prepare_insert(
+        &self,
+        key: String,
+        value: Payload,
+        options: &InsertOptions,
+        version: Version,
+        batch_size: u16,
+        ipv4_slots: &mut HashSet<u8>,
+    ) -> Result<(KeyValue<String, Payload>, Update), KvsError> {
+        if let Some(precondition) = options.precondition {
+            self.check_precondition(&key, precondition)?;
+        }
+        let mut kv = KeyValue::with_version(key.clone(), value, version);
+        let existing = self.inner.get(&key);
+        kv.set_categories(options.categories.clone().unwrap_or_else(|| {
+            existing.map_or_else(Vec::new, |existing| existing.categories().to_vec())
+        }));
+        // The expiry is absolute, so every peer expires the pair at the same time
+        let expires = options
+            .ttl
+            .map(|ttl| {
+                u64::try_from(ttl.as_millis())
+                    .ok()
+                    .and_then(|ttl| version.timestamp.checked_add(ttl))
+                    .ok_or_else(|| KvsError::InvalidTtl(format!("{:?} is too long", ttl)))
+            })
+            .transpose()?;
+        kv.set_expires(expires);
+        kv.set_ephemeral(options.ephemeral);
+        kv.set_batch_size(batch_size);
+        kv.set_ipv4_slot(self.ipv4_slot(&kv, ipv4_slots));
+        if existing.is_none() {
+            self.check_collision(&kv)?;
+        }
+        let withdraw: Option<RouteCollection> = existing
+            .map(|existing| {
+                self.encode(existing).map_err(|_| {
+                    KvsError::EncodeError(format!("Could not encode: {}", existing.to_string()))
+                })
+            })
+            .transpose()?;
+        let announce = self.encode(&kv)?;
+        let update = match withdraw {
+            Some(withdraw) => Update::with_both(announce, withdraw),
+            None => Update::with_announce(announce),
+        };
+        Ok((kv, update))
+    }
+
+    /// Iterate through all [Key](struct.Key.html)s in this store (in sorted order)
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        let now = wall_clock();
+        self.inner
+            .values()
+            .filter(move |kv| !kv.is_expired(now))
+            .map(|kv| kv.key().as_str())
+    }
+
+    /// List a page of [Key](struct.Key.html)s starting with `prefix`, in sorted order
+    ///
+    /// Returns up to `limit` keys following the `after` cursor (the last key of the previous page),
+    /// along with the cursor for the next page if there are more matching keys. Since the cursor is
+    /// a key, pages stay stable while other keys are inserted/removed.
+    pub fn scan(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> (Vec<&str>, Option<String>) {
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix),
+        };
+        let now = wall_clock();
+        let mut keys = self
+            .inner
+            .range::<str, _>((start, Bound::Unbounded))
+            .map(|(key, _)| key.as_str())
+            .take_while(|key| key.starts_with(prefix))
+            .filter(|key| !self.inner[*key].is_expired(now));
+        let page: Vec<&str> = keys.by_ref().take(limit).collect();
+        let cursor = if keys.next().is_some() {
+            page.last().map(|key| (*key).to_owned())
+        } else {
+            None
+        };
+        (page, cursor)
     }
 
     /// Retrieve a [Value](struct.Value.html) by a given &[Key](struct.Key.html)
-    pub fn get(&self, key: &str) -> Option<String> {
-        self.inner.get(key).map(|kv| kv.as_ref().clone())
+    pub fn get(&self, key: &str) -> Option<Payload> {
+        self.get_pair(key).map(|kv| kv.as_ref().clone())
+    }
+
+    /// Retrieve a [KeyValue](struct.KeyValue.html) (E.g. for its version & categories) by a given &[Key](struct.Key.html)
+    ///
+    /// Expired pairs aren't returned, even if they haven't been removed yet
+    pub fn get_pair(&self, key: &str) -> Option<&KeyValue<String, Payload>> {
+        self.inner
+            .get(key)
+            .filter(|kv| !kv.is_expired(wall_clock()))
+    }
+
+    /// Announce every (unexpired) pair written by this node, E.g. to re-announce the pairs
+    /// restored from disk when starting up
+    ///
+    /// Returns `None` if there are no such pairs
+    pub fn announce_own(&self) -> Result<Option<Update>, KvsError> {
+        let now = wall_clock();
+        let announce = self
+            .inner
+            .values()
+            .filter(|kv| kv.version().origin == self.origin() && !kv.is_expired(now))
+            .map(|kv| self.encode(kv))
+            .collect::<Result<Vec<_>, _>>()?;
+        if announce.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Update::with_announce(RouteCollection::concat(
+            announce,
+        ))))
     }
 
     /// Remove a [KeyValue](struct.KeyValue.html) by a given &[Key](struct.Key.html)
     pub fn remove(&mut self, key: &str) -> Result<Option<Update>, KvsError> {
-        if let Some(removed) = self.inner.remove(key) {
-            let withdraw: RouteCollection = (&removed).try_into().map_err(|_| {
-                KvsError::EncodeError(format!("Could not encode: {}", removed.to_string()))
-            })?;
-            Ok(Some(Update::with_withdraw(withdraw)))
-        } else {
-            Ok(None)
+        match self.remove_entry(key, Some(EventSource::Local))? {
+            Some(removed) => {
+                let withdraw = self.encode(&removed).map_err(|_| {
+                    KvsError::EncodeError(format!("Could not encode: {}", removed.to_string()))
+                })?;
+                Ok(Some(Update::with_withdraw(withdraw)))
+            }
+            None => Ok(None),
         }
     }
 
+    /// Remove a [KeyValue](struct.KeyValue.html) by a given &[Key](struct.Key.html), only if it's at the given version
+    ///
+    /// Fails with [PreconditionFailed](../enum.KvsError.html) if the key has been updated (or removed) since
+    pub fn remove_if(&mut self, key: &str, version: Version) -> Result<Option<Update>, KvsError> {
+        self.check_precondition(key, Precondition::Version(version))?;
+        self.remove(key)
+    }
+
+    /// Remove all pairs that have expired by `now` (milliseconds since the UNIX epoch)
+    ///
+    /// Every node removes expired pairs on its own, but only the node that wrote the
+    /// pair queues a BGP withdraw for it
+    pub fn expire(&mut self, now: u64) -> Result<Vec<Update>, KvsError> {
+        let expired: Vec<String> = self
+            .inner
+            .values()
+            .filter(|kv| kv.is_expired(now))
+            .map(|kv| kv.key().clone())
+            .collect();
+        let mut updates = vec![];
+        for key in expired {
+            if let Some(removed) = self.remove_entry(&key, None)? {
+                debug!("Expired {}", key);
+                if removed.version().origin == self.origin() {
+                    let withdraw = self.encode(&removed).map_err(|_| {
+                        KvsError::EncodeError(format!("Could not encode: {}", removed.to_string()))
+                    })?;
+                    updates.push(Update::with_withdraw(withdraw));
+                }
+            }
+        }
+        Ok(updates)
+    }
+
     /// Insert a new/updated `KeyValue` from a BGP Peer
     ///
     /// Checks for the newest version (will not evict a newer internal version)
-    /// and does not trigger outbound updates
-    pub fn insert_from_peer(&mut self, pair: KeyValue<String, String>) {
+    /// and does not trigger outbound updates. Concurrent writes are resolved by
+    /// [Version](struct.Version.html) ordering, so all nodes keep the same write.
+    ///
+    /// Fails with [Untrusted](../enum.KvsError.html) if signatures are enforced and the pair
+    /// isn't signed by a trusted key, or [InvalidVersion](../enum.KvsError.html) if its version
+    /// is too far ahead of the local clock
+    pub fn insert_from_peer(&mut self, pair: KeyValue<String, Payload>) -> Result<(), KvsError> {
+        // Checked before observing the version, so untrusted writes can't push the clock ahead
+        self.check_signer(&pair)?;
         let key = pair.key().clone();
+        if !self.clock.observe(pair.version()) {
+            return Err(KvsError::InvalidVersion(format!(
+                "{} version {} is ahead of the clock",
+                key,
+                pair.version()
+            )));
+        }
         if let Some(existing) = self.inner.get(&key) {
+            if existing.version() == pair.version() {
+                // Already stored, but peers still have it (if it was restored from disk)
+                self.provisional.remove(&key);
+                return Ok(());
+            }
             if existing.version() > pair.version() {
                 // This is an old update, ignore
-                return;
+                return Ok(());
+            }
+        }
+        if pair.is_expired(wall_clock()) {
+            // Already expired on every node, only the older version needs to be removed
+            self.remove_entry(&key, None)?;
+            return Ok(());
+        }
+        self.check_collision(&pair)?;
+        self.insert_entry(pair, EventSource::Peer)
+    }
+
+    /// Remove a `KeyValue` withdrawn by a BGP Peer
+    ///
+    /// Only removes the stored `KeyValue` if the withdraw is of the stored version, so withdraws of
+    /// other versions (E.g. of an old version after its update was already received) are ignored.
+    ///
+    /// A withdraw of one of this node's own writes means another node removed the pair. Only this node
+    /// announces its routes, so the returned [Update](struct.Update.html) withdraws them from every peer.
+    /// Withdraws of other nodes' writes don't trigger outbound updates.
+    ///
+    /// Fails with [Untrusted](../enum.KvsError.html) if signatures are enforced and the withdraw isn't
+    /// signed by a trusted key, or if it isn't signed by the same key as the stored version
+    pub fn remove_from_peer(
+        &mut self,
+        pair: KeyValue<String, Payload>,
+    ) -> Result<Option<Update>, KvsError> {
+        self.check_signer(&pair)?;
+        match self.inner.get(pair.key()) {
+            Some(existing) if existing.version() == pair.version() => {
+                if existing.signer() != pair.signer() {
+                    return Err(KvsError::Untrusted(format!(
+                        "Withdraw of {} version {} isn't signed by the key it was written with",
+                        pair.key(),
+                        pair.version()
+                    )));
+                }
+            }
+            // Another version is stored (or none), ignore
+            _ => return Ok(None),
+        }
+        match self.remove_entry(pair.key(), Some(EventSource::Peer))? {
+            Some(removed) if removed.version().origin == self.origin() => {
+                Ok(Some(Update::with_withdraw(self.encode(&removed)?)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Remove the pairs of other nodes restored from disk that peers haven't announced since,
+    /// E.g. as they were removed while this node was down (and the withdraws were missed)
+    ///
+    /// Should be called once peers have had time to announce every pair they have. Like
+    /// [remove_from_peer](#method.remove_from_peer), this does not trigger outbound updates.
+    /// Returns the keys removed
+    pub fn remove_provisional(&mut self) -> Result<Vec<String>, KvsError> {
+        let provisional: Vec<String> = self.provisional.drain().collect();
+        let mut removed = Vec::with_capacity(provisional.len());
+        for key in provisional {
+            if self.remove_entry(&key, Some(EventSource::Peer))?.is_some() {
+                removed.push(key);
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Remove the ephemeral pairs written by the given origin, once it's no longer reachable from this node
+    ///
+    /// Like [remove_from_peer](#method.remove_from_peer), this does not trigger outbound updates.
+    /// Returns the pairs removed
+    pub fn remove_ephemeral(
+        &mut self,
+        origin: u32,
+    ) -> Result<Vec<KeyValue<String, Payload>>, KvsError> {
+        if origin == self.origin() {
+            // This node's own pairs live as long as it does
+            return Ok(vec![]);
+        }
+        let orphaned: Vec<String> = self
+            .inner
+            .values()
+            .filter(|kv| kv.is_ephemeral() && kv.version().origin == origin)
+            .map(|kv| kv.key().clone())
+            .collect();
+        let mut removed = Vec::with_capacity(orphaned.len());
+        for key in &orphaned {
+            removed.extend(self.remove_entry(key, Some(EventSource::Peer))?);
+        }
+        Ok(removed)
+    }
+
+    /// Subscribe to a [StoreEvent](enum.StoreEvent.html) for every change to this store,
+    /// whether from local writes, peers or expiry
+    ///
+    /// A subscriber that falls more than 1024 events behind misses the oldest events
+    pub fn subscribe(&self) -> broadcast::Receiver<StoreEvent> {
+        self.events.subscribe()
+    }
+
+    /// Store a new/updated pair, logging the insert (if this store is persistent)
+    fn insert_entry(
+        &mut self,
+        pair: KeyValue<String, Payload>,
+        source: EventSource,
+    ) -> Result<(), KvsError> {
+        self.persist(LogEntry::Insert((&pair).into()))?;
+        self.store_entry(pair, source);
+        self.snapshot_if_needed();
+        Ok(())
+    }
+
+    /// Store a new/updated pair that has already been logged
+    fn store_entry(&mut self, pair: KeyValue<String, Payload>, source: EventSource) {
+        let key = pair.key().clone();
+        let version = pair.version();
+        self.provisional.remove(&key);
+        self.index(&pair);
+        let event = match self.inner.insert(key.clone(), pair) {
+            Some(old) => {
+                self.release_ipv4_id(old.ipv4_id());
+                StoreEvent::Updated {
+                    key,
+                    old: old.version(),
+                    new: version,
+                    source,
+                }
+            }
+            None => StoreEvent::Inserted {
+                key,
+                version,
+                source,
+            },
+        };
+        self.notify(event);
+    }
+
+    /// Remove a pair by key, logging the removal (if this store is persistent)
+    ///
+    /// The `source` of the removal is `None` for expired pairs
+    fn remove_entry(
+        &mut self,
+        key: &str,
+        source: Option<EventSource>,
+    ) -> Result<Option<KeyValue<String, Payload>>, KvsError> {
+        if !self.inner.contains_key(key) {
+            return Ok(None);
+        }
+        self.persist(LogEntry::Remove(key.to_owned()))?;
+        let removed = self.take_entry(key, source);
+        self.snapshot_if_needed();
+        Ok(removed)
+    }
+
+    /// Remove a pair by key that has already been logged
+    fn take_entry(
+        &mut self,
+        key: &str,
+        source: Option<EventSource>,
+    ) -> Option<KeyValue<String, Payload>> {
+        let removed = self.inner.remove(key)?;
+        self.provisional.remove(key);
+        self.unindex(&removed);
+        let key = key.to_owned();
+        let version = removed.version();
+        self.notify(match source {
+            Some(source) => StoreEvent::Removed {
+                key,
+                version,
+                source,
+            },
+            None => StoreEvent::Expired { key, version },
+        });
+        Some(removed)
+    }
+
+    /// Send an event to any subscribers
+    fn notify(&self, event: StoreEvent) {
+        // Only fails if nothing is subscribed
+        let _ = self.events.send(event);
+    }
+
+    /// Encode a pair as routes, encrypting its value if a cluster key is set
+    /// and signing it if a node key is set
+    fn encode(&self, pair: &KeyValue<String, Payload>) -> Result<RouteCollection, KvsError> {
+        RouteCollection::encode(
+            pair,
+            self.addr_prefix,
+            self.cluster_key.as_ref(),
+            self.node_key.as_ref(),
+        )
+    }
+
+    /// Make sure a pair from a peer is signed by a trusted key (or this node's own key),
+    /// if signatures are enforced
+    fn check_signer(&self, pair: &KeyValue<String, Payload>) -> Result<(), KvsError> {
+        let trusted_keys = match &self.trusted_keys {
+            Some(trusted_keys) => trusted_keys,
+            None => return Ok(()),
+        };
+        match pair.signer() {
+            Some(signer) if trusted_keys.contains(signer) => Ok(()),
+            Some(signer) if self.node_key.as_ref().map(|key| key.public_key()) == Some(*signer) => {
+                Ok(())
+            }
+            Some(signer) => Err(KvsError::Untrusted(format!(
+                "{} is signed by untrusted key {}",
+                pair.key(),
+                signer
+            ))),
+            None => Err(KvsError::Untrusted(format!("{} isn't signed", pair.key()))),
+        }
+    }
+
+    /// Make sure a key is in the state expected by a conditional write
+    ///
+    /// Expired pairs that haven't been removed yet are treated as absent
+    fn check_precondition(&self, key: &str, precondition: Precondition) -> Result<(), KvsError> {
+        let current = self.get_pair(key).map(|kv| kv.version());
+        let matches = match precondition {
+            Precondition::Absent => current.is_none(),
+            Precondition::Present => current.is_some(),
+            Precondition::Version(version) => current == Some(version),
+        };
+        if matches {
+            return Ok(());
+        }
+        Err(KvsError::PreconditionFailed(match current {
+            Some(version) => format!("{} is at version {}", key, version),
+            None => format!("{} does not exist", key),
+        }))
+    }
+
+    /// Make sure no other stored key has the same key hash as this pair
+    ///
+    /// Peers reassemble & withdraw routes by key hash, so two keys with the same hash can't be synchronized
+    fn check_collision(&self, pair: &KeyValue<String, Payload>) -> Result<(), KvsError> {
+        match self.hashes.get(&pair.key_hash()) {
+            Some(existing) if existing != pair.key() => Err(KvsError::HashCollision(format!(
+                "{} and {} both hash to {:x}",
+                existing,
+                pair.key(),
+                pair.key_hash()
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// IPv4 slot for a pair being written locally, if this store's pairs are also encoded as IPv4 routes
+    ///
+    /// The slot follows the origin tag in the IPv4 prefixes of the pair, so it's only taken by pairs written
+    /// with the same origin tag. It's probed for from a hash of the key & version, skipping the slots of stored
+    /// pairs (including older versions of the same key) & `taken` by the other pairs of the same write.
+    /// Pairs too large for IPv4 routes, or written when every slot is taken, are only announced as IPv6 routes
+    fn ipv4_slot(&self, pair: &KeyValue<String, Payload>, taken: &mut HashSet<u8>) -> Option<u8> {
+        self.addr_prefix.ipv4()?;
+        if !pair.fits_ipv4_routes(self.cluster_key.as_ref(), self.node_key.as_ref()) {
+            return None;
+        }
+        let origin_tag = pair.version().origin as u8;
+        let start = (pair.key_hash() ^ pair.version().timestamp) as u8;
+        let slot = (0..=u8::MAX).map(|i| start.wrapping_add(i)).find(|slot| {
+            !taken.contains(slot)
+                && !self
+                    .ipv4_ids
+                    .contains_key(&u16::from_be_bytes([origin_tag, *slot]))
+        });
+        match slot {
+            Some(slot) => {
+                taken.insert(slot);
+            }
+            None => warn!(
+                "No free IPv4 slot for {}, only announcing it as IPv6 routes",
+                pair.key()
+            ),
+        }
+        slot
+    }
+
+    /// Add a stored pair to the key hash & IPv4 id indexes
+    fn index(&mut self, pair: &KeyValue<String, Payload>) {
+        self.hashes.insert(pair.key_hash(), pair.key().clone());
+        if let Some(ipv4_id) = pair.ipv4_id() {
+            *self.ipv4_ids.entry(ipv4_id).or_default() += 1;
+        }
+    }
+
+    /// Remove a pair that's no longer stored from the key hash & IPv4 id indexes
+    fn unindex(&mut self, pair: &KeyValue<String, Payload>) {
+        self.hashes.remove(&pair.key_hash());
+        self.release_ipv4_id(pair.ipv4_id());
+    }
+
+    /// Release the IPv4 id of a pair that's no longer stored (E.g. an older version)
+    fn release_ipv4_id(&mut self, ipv4_id: Option<u16>) {
+        let ipv4_id = match ipv4_id {
+            Some(ipv4_id) => ipv4_id,
+            None => return,
+        };
+        if let Some(count) = self.ipv4_ids.get_mut(&ipv4_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.ipv4_ids.remove(&ipv4_id);
+            }
+        }
+    }
+
+    /// Append a change to the write-ahead log (if this store is persistent)
+    fn persist(&mut self, entry: LogEntry) -> Result<(), KvsError> {
+        if let Some(persistence) = self.persistence.as_mut() {
+            persistence.append(&entry)?;
+        }
+        Ok(())
+    }
+
+    fn snapshot_if_needed(&mut self) {
+        if let Some(true) = self.persistence.as_ref().map(Persistence::needs_snapshot) {
+            self.snapshot();
+        }
+    }
+
+    /// Write all pairs to a new snapshot (if this store is persistent)
+    ///
+    /// Failures are only logged, since all changes are still in the write-ahead log
+    fn snapshot(&mut self) {
+        if let Some(persistence) = self.persistence.as_mut() {
+            let pairs: Vec<StoredPair> = self.inner.values().map(StoredPair::from).collect();
+            if let Err(err) = persistence.snapshot(&pairs) {
+                warn!("Could not write snapshot: {}", err);
+            }
+        }
+    }
+}
+
+/// Periodically remove expired pairs from the store
+///
+/// Withdraws for the expired pairs written by this node are sent on the given channel
+pub async fn expire_pairs(store: Arc<RwLock<KvStore>>, channel: mpsc::UnboundedSender<Update>) {
+    let mut interval = time::interval(EXPIRY_INTERVAL);
+    loop {
+        interval.tick().await;
+        let mut store = store.write().await;
+        match task::block_in_place(|| store.expire(wall_clock())) {
+            Ok(updates) => {
+                for update in updates {
+                    if channel.send(update).is_err() {
+                        return;
+                    }
+                }
             }
+            Err(err) => error!("Could not expire pairs: {}", err),
+        }
+    }
+}
+
+/// Where a change to the [KvStore](struct.KvStore.html) came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventSource {
+    /// A write to this node (E.g. from the HTTP or Redis API)
+    Local,
+    /// A BGP update from a peer (or the origin of an ephemeral pair becoming unreachable)
+    Peer,
+}
+
+/// A change to a [KeyValue](struct.KeyValue.html) in the [KvStore](struct.KvStore.html),
+/// sent to [subscribers](struct.KvStore.html#method.subscribe)
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum StoreEvent {
+    /// A new key was stored
+    Inserted {
+        key: String,
+        version: Version,
+        source: EventSource,
+    },
+    /// A stored key was replaced by a newer version
+    Updated {
+        key: String,
+        old: Version,
+        new: Version,
+        source: EventSource,
+    },
+    /// A key was removed (E.g. deleted locally or withdrawn by a peer)
+    Removed {
+        key: String,
+        version: Version,
+        source: EventSource,
+    },
+    /// A key was removed once its TTL passed (on every node)
+    Expired { key: String, version: Version },
+}
+
+impl StoreEvent {
+    /// The key that changed
+    pub fn key(&self) -> &str {
+        match self {
+            StoreEvent::Inserted { key, .. }
+            | StoreEvent::Updated { key, .. }
+            | StoreEvent::Removed { key, .. }
+            | StoreEvent::Expired { key, .. } => key,
+        }
+    }
+
+    /// Name of this kind of event (E.g. for SSE event names)
+    pub fn name(&self) -> &'static str {
+        match self {
+            StoreEvent::Inserted { .. } => "inserted",
+            StoreEvent::Updated { .. } => "updated",
+            StoreEvent::Removed { .. } => "removed",
+            StoreEvent::Expired { .. } => "expired",
         }
-        self.inner.insert(key, pair);
     }
 }
 
@@ -92,6 +981,7 @@ impl KvStore {
 ///
 /// - A new [KeyValue](struct.KeyValue.html) will only have an announcement
 /// - An updated [KeyValue](struct.KeyValue.html) will announce the new value (and version), will withdraw the old value
+///   (except routes whose prefix is announced again, which the announcement replaces)
 /// - A removed [KeyValue](struct.KeyValue.html) will only have a withdraw
 #[derive(Debug)]
 pub struct Update {
@@ -114,10 +1004,13 @@ impl Update {
         }
     }
 
+    /// Announce & withdraw together, leaving prefixes that are announced out of the withdraw
+    /// (their routes are replaced by the announcement)
     fn with_both(announce: RouteCollection, withdraw: RouteCollection) -> Self {
+        let withdraw = withdraw.without_prefixes_of(&announce);
         Self {
             announce: Some(announce),
-            withdraw: Some(withdraw),
+            withdraw: Some(withdraw).filter(|withdraw| !withdraw.is_empty()),
         }
     }
 }
@@ -125,43 +1018,572 @@ impl Update {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::Ipv6Addr;
 
     #[test]
     fn store_new_insert() {
         let mut store = KvStore::new();
         assert!(store.is_empty());
 
-        store.insert("Key".to_owned(), "Value".to_owned()).unwrap();
+        store.insert("Key".to_owned(), "Value".into()).unwrap();
         assert_eq!(store.len(), 1);
-        assert_eq!(store.get("Key"), Some("Value".to_owned()));
+        assert_eq!(store.get("Key"), Some("Value".into()));
     }
 
     #[test]
     fn store_insert_and_update() {
         let mut store = KvStore::new();
 
-        let update = store.insert("Key".to_owned(), "Value".to_owned()).unwrap();
+        let update = store.insert("Key".to_owned(), "Value".into()).unwrap();
         assert!(update.announce.is_some());
         assert!(update.withdraw.is_none());
 
         let routes: Vec<_> = update.announce.unwrap().iter().cloned().collect();
-        assert_eq!(routes[0].next_hop.version(), 0);
+        let version = store.get_pair("Key").unwrap().version();
+        assert_eq!(routes[0].next_hop.version(), version.tag());
+        assert_eq!(version.origin, store.origin());
 
-        let update = store.insert("Key".to_owned(), "42".to_owned()).unwrap();
+        let update = store.insert("Key".to_owned(), "42".into()).unwrap();
         assert!(update.announce.is_some());
         assert!(update.withdraw.is_some());
 
+        let updated = store.get_pair("Key").unwrap().version();
+        assert!(updated > version);
         let a_routes: Vec<_> = update.announce.unwrap().iter().cloned().collect();
-        assert_eq!(a_routes[0].next_hop.version(), 1);
+        assert_eq!(a_routes[0].next_hop.version(), updated.tag());
 
         let w_routes: Vec<_> = update.withdraw.unwrap().iter().cloned().collect();
-        assert_eq!(w_routes[0].next_hop.version(), 0);
+        assert_eq!(w_routes[0].next_hop.version(), version.tag());
+
+        // Prefixes shared by both versions (E.g. the origin & expiry chunk) are only announced,
+        // so the withdraw doesn't cancel the new version's routes
+        let announced: Vec<Ipv6Addr> = a_routes
+            .iter()
+            .map(|route| *route.prefix.as_ref())
+            .collect();
+        assert!(w_routes
+            .iter()
+            .all(|route| !announced.contains(route.prefix.as_ref())));
+        assert!(w_routes.len() < a_routes.len());
+    }
+
+    #[test]
+    fn store_encrypted_routes() {
+        let cluster_key = ClusterKey::from_secret(b"Cluster secret");
+        let mut store = KvStore::new();
+        store.set_cluster_key(Some(cluster_key.clone()));
+        let announce = store
+            .insert("Key".to_owned(), "Value".into())
+            .unwrap()
+            .announce
+            .unwrap();
+        let kv: KeyValue<String, Payload> =
+            KeyValue::decode(&announce, AddrPrefix::default(), Some(&cluster_key)).unwrap();
+        assert_eq!(kv.as_ref(), &"Value".into());
+        assert!(
+            KeyValue::<String, Payload>::decode(&announce, AddrPrefix::default(), None).is_err()
+        );
+
+        // Withdraws the same routes that were announced
+        let withdraw = store.remove("Key").unwrap().unwrap().withdraw.unwrap();
+        assert!(announce
+            .iter()
+            .zip(withdraw.iter())
+            .all(|(a, w)| a.prefix.as_ref() == w.prefix.as_ref()));
+
+        // Also after a restart, as the nonce of each pair is stored
+        let dir = std::env::temp_dir().join(format!("kvs-bgp-nonce-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let announce = {
+            let mut store = KvStore::open(&dir, 2).unwrap();
+            store.set_cluster_key(Some(cluster_key.clone()));
+            let update = store.insert("Key".to_owned(), "Value".into()).unwrap();
+            update.announce.unwrap()
+        };
+        let mut store = KvStore::open(&dir, 2).unwrap();
+        store.set_cluster_key(Some(cluster_key));
+        let withdraw = store.remove("Key").unwrap().unwrap().withdraw.unwrap();
+        assert!(announce
+            .iter()
+            .zip(withdraw.iter())
+            .all(|(a, w)| a.prefix.as_ref() == w.prefix.as_ref()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn store_addr_prefix() {
+        let addr_prefix: AddrPrefix = "fd42".parse().unwrap();
+        let mut store = KvStore::new();
+        store.set_addr_prefix(addr_prefix);
+        let update = store.insert("Key".to_owned(), "Value".into()).unwrap();
+        let announce = update.announce.unwrap();
+        assert!(announce
+            .iter()
+            .all(|route| route.has_valid_prefix(addr_prefix)));
+        let kv: KeyValue<String, Payload> = KeyValue::decode(&announce, addr_prefix, None).unwrap();
+        assert_eq!(kv.as_ref(), &"Value".into());
+
+        let withdraw = store.remove("Key").unwrap().unwrap().withdraw.unwrap();
+        assert!(withdraw
+            .iter()
+            .all(|route| route.has_valid_prefix(addr_prefix)));
+    }
+
+    #[test]
+    fn store_ipv4_routes() {
+        let addr_prefix: AddrPrefix = "fd42,241".parse().unwrap();
+        let mut store = KvStore::new();
+        store.set_addr_prefix(addr_prefix);
+        let update = store.insert("Key".to_owned(), "Value".into()).unwrap();
+        let announce = update.announce.unwrap();
+        assert!(announce.iter().any(|route| route.is_ipv4()));
+        assert!(announce
+            .iter()
+            .all(|route| route.has_valid_prefix(addr_prefix)));
+
+        // Updates are announced with new IPv4 prefixes, so withdrawing the old version leaves them be
+        let update = store
+            .insert("Key".to_owned(), "Other Value".into())
+            .unwrap();
+        let ipv4_prefixes = |routes: &RouteCollection| -> Vec<Ipv6Addr> {
+            routes
+                .iter()
+                .filter(|route| route.is_ipv4())
+                .map(|route| *route.prefix.as_ref())
+                .collect()
+        };
+        let announce = update.announce.unwrap();
+        let announced = ipv4_prefixes(&announce);
+        let withdrawn = ipv4_prefixes(&update.withdraw.unwrap());
+        assert!(announced.iter().all(|prefix| !withdrawn.contains(prefix)));
+
+        // Every pair of this node gets its own IPv4 slot, until they run out
+        let ipv4_ids = |update: Update| -> HashSet<u64> {
+            update
+                .announce
+                .unwrap()
+                .iter()
+                .filter(|route| route.is_ipv4())
+                .map(|route| route.hash())
+                .collect()
+        };
+        let mut ids = ipv4_ids(Update::with_announce(announce));
+        for i in 1..=u8::MAX {
+            let update = store.insert(i.to_string(), "Value".into()).unwrap();
+            let id = ipv4_ids(update);
+            assert_eq!(id.len(), 1);
+            assert!(ids.insert(*id.iter().next().unwrap()));
+        }
+        // Then pairs are written, but only announced as IPv6 routes
+        let update = store.insert("Full".to_owned(), "Value".into()).unwrap();
+        assert!(ipv4_ids(update).is_empty());
+        store.remove("1").unwrap();
+        let update = store.insert("Freed".to_owned(), "Value".into()).unwrap();
+        assert_eq!(ipv4_ids(update).len(), 1);
+
+        // Concurrent writes of another node use another origin tag
+        let mut other = KvStore::new();
+        other.set_addr_prefix(addr_prefix);
+        other.set_origin(store.origin().wrapping_add(1));
+        let update = other.insert("Key".to_owned(), "Value".into()).unwrap();
+        let id = ipv4_ids(update);
+        assert_eq!(id.len(), 1);
+        assert!(!ids.contains(id.iter().next().unwrap()));
+
+        // Pairs too large for IPv4 routes are only announced as IPv6 routes
+        let update = other
+            .insert(
+                "Large".to_owned(),
+                (0..1024)
+                    .map(|_| rand::random::<u8>())
+                    .collect::<Vec<u8>>()
+                    .into(),
+            )
+            .unwrap();
+        assert!(ipv4_ids(update).is_empty());
+        assert!(other.get("Large").is_some());
+    }
+
+    #[test]
+    fn store_trusted_keys() {
+        let trusted = NodeKey::from_hex(&"42".repeat(32)).unwrap();
+        let untrusted = NodeKey::from_hex(&"07".repeat(32)).unwrap();
+        let mut peer = KvStore::new();
+        let mut store = KvStore::new();
+        store.set_trusted_keys(Some(vec![trusted.public_key()]));
+
+        let pair = |peer: &mut KvStore, key: &str| -> KeyValue<String, Payload> {
+            let announce = peer
+                .insert(key.to_owned(), "Value".into())
+                .unwrap()
+                .announce
+                .unwrap();
+            (&announce).try_into().unwrap()
+        };
+        let unsigned = pair(&mut peer, "Unsigned");
+        assert!(matches!(
+            store.insert_from_peer(unsigned),
+            Err(KvsError::Untrusted(_))
+        ));
+        peer.set_node_key(Some(untrusted));
+        let untrusted = pair(&mut peer, "Untrusted");
+        assert!(matches!(
+            store.insert_from_peer(untrusted),
+            Err(KvsError::Untrusted(_))
+        ));
+        peer.set_node_key(Some(trusted));
+        let signed = pair(&mut peer, "Signed");
+        assert_eq!(
+            signed.signer(),
+            Some(&peer.node_key.as_ref().unwrap().public_key())
+        );
+        store.insert_from_peer(signed).unwrap();
+        assert_eq!(store.get("Signed"), Some("Value".into()));
+        assert!(store.get("Unsigned").is_none());
+        assert!(store.get("Untrusted").is_none());
+    }
+
+    #[test]
+    fn store_insert_with_categories() {
+        let mut store = KvStore::new();
+        let update = store
+            .insert_with(
+                "Key".to_owned(),
+                "Value".into(),
+                InsertOptions {
+                    categories: Some(vec![2, 1]),
+                    ..InsertOptions::default()
+                },
+            )
+            .unwrap();
+        for route in update.announce.unwrap().iter() {
+            assert_eq!(route.categories, vec![1, 2]);
+        }
+
+        // Updating without categories keeps the existing categories
+        store.insert("Key".to_owned(), "42".into()).unwrap();
+        assert_eq!(store.get_pair("Key").unwrap().categories(), &[1, 2]);
+
+        store
+            .insert_with(
+                "Key".to_owned(),
+                "Value".into(),
+                InsertOptions {
+                    categories: Some(vec![]),
+                    ..InsertOptions::default()
+                },
+            )
+            .unwrap();
+        assert!(store.get_pair("Key").unwrap().categories().is_empty());
+    }
+
+    #[test]
+    fn store_concurrent_writes_converge() {
+        // The same key written on two nodes at the same time
+        let first = KeyValue::with_version("Key".to_owned(), "First".into(), Version::new(1000, 1));
+        let second =
+            KeyValue::with_version("Key".to_owned(), "Second".into(), Version::new(1000, 2));
+
+        let mut store1 = KvStore::new();
+        store1.insert_from_peer(first).unwrap();
+        store1.insert_from_peer(second).unwrap();
+
+        let first = KeyValue::with_version("Key".to_owned(), "First".into(), Version::new(1000, 1));
+        let second =
+            KeyValue::with_version("Key".to_owned(), "Second".into(), Version::new(1000, 2));
+        let mut store2 = KvStore::new();
+        store2.insert_from_peer(second).unwrap();
+        store2.insert_from_peer(first).unwrap();
+
+        // Both resolve to the write from the higher origin id
+        assert_eq!(store1.get("Key"), Some("Second".into()));
+        assert_eq!(store2.get("Key"), Some("Second".into()));
+
+        // Local writes are newer than anything seen from peers
+        store1.insert("Key".to_owned(), "Local".into()).unwrap();
+        assert!(store1.get_pair("Key").unwrap().version() > Version::new(1000, 2));
+
+        // But versions far ahead of the clock are refused
+        let future = Version::new(wall_clock() + 24 * 60 * 60 * 1000, 2);
+        let kv = KeyValue::with_version("Key".to_owned(), "Future".into(), future);
+        assert!(matches!(
+            store1.insert_from_peer(kv),
+            Err(KvsError::InvalidVersion(_))
+        ));
+        assert_eq!(store1.get("Key"), Some("Local".into()));
+    }
+
+    #[test]
+    fn store_expire() {
+        let mut store = KvStore::new();
+        let ttl = InsertOptions {
+            ttl: Some(Duration::from_secs(60)),
+            ..InsertOptions::default()
+        };
+        store
+            .insert_with("Key".to_owned(), "Value".into(), ttl)
+            .unwrap();
+        let expires = store.get_pair("Key").unwrap().expires().unwrap();
+        assert!(expires >= wall_clock() + 59_000);
+
+        // Expired pairs from a peer (written by another node) are removed without a withdraw
+        let mut kv = KeyValue::with_version("Peer".to_owned(), "Value".into(), Version::new(1, 1));
+        kv.set_expires(Some(expires));
+        store.insert_from_peer(kv).unwrap();
+        store.insert("Forever".to_owned(), "Value".into()).unwrap();
+
+        assert!(store.expire(expires - 1).unwrap().is_empty());
+        assert_eq!(store.len(), 3);
+        let updates = store.expire(expires).unwrap();
+        assert_eq!(updates.len(), 1);
+        assert!(updates[0].withdraw.is_some());
+        assert_eq!(store.keys().collect::<Vec<_>>(), vec!["Forever"]);
+
+        // Updating without a TTL removes the expiry
+        store
+            .insert_with(
+                "Forever".to_owned(),
+                "Value".into(),
+                InsertOptions {
+                    ttl: Some(Duration::from_secs(60)),
+                    ..InsertOptions::default()
+                },
+            )
+            .unwrap();
+        store.insert("Forever".to_owned(), "Value".into()).unwrap();
+        assert_eq!(store.get_pair("Forever").unwrap().expires(), None);
+
+        // TTLs past the end of time are refused
+        let ttl = InsertOptions {
+            ttl: Some(Duration::from_secs(u64::MAX)),
+            ..InsertOptions::default()
+        };
+        assert!(matches!(
+            store.insert_with("Key".to_owned(), "Value".into(), ttl),
+            Err(KvsError::InvalidTtl(_))
+        ));
+    }
+
+    #[test]
+    fn store_ignores_expired_from_peer() {
+        let mut store = KvStore::new();
+        store
+            .insert_from_peer(KeyValue::new("Key".to_owned(), "Value".into()))
+            .unwrap();
+        let mut kv = KeyValue::with_version("Key".to_owned(), "Value".into(), Version::new(1, 1));
+        kv.set_expires(Some(1));
+        store.insert_from_peer(kv).unwrap();
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn store_remove_ephemeral() {
+        let mut store = KvStore::new();
+        store.set_origin(1);
+        store
+            .insert_with(
+                "Local".to_owned(),
+                "Value".into(),
+                InsertOptions {
+                    ephemeral: true,
+                    ..InsertOptions::default()
+                },
+            )
+            .unwrap();
+        let mut kv = KeyValue::with_version("Peer".to_owned(), "Value".into(), Version::new(1, 2));
+        kv.set_ephemeral(true);
+        store.insert_from_peer(kv).unwrap();
+        let kv = KeyValue::with_version("Durable".to_owned(), "Value".into(), Version::new(1, 2));
+        store.insert_from_peer(kv).unwrap();
+
+        assert!(store.remove_ephemeral(1).unwrap().is_empty());
+        assert!(store.remove_ephemeral(3).unwrap().is_empty());
+        let removed = store.remove_ephemeral(2).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].key(), "Peer");
+        assert_eq!(store.keys().collect::<Vec<_>>(), vec!["Durable", "Local"]);
+    }
+
+    #[test]
+    fn store_subscribe() {
+        let mut store = KvStore::new();
+        store.set_origin(1);
+        let mut events = store.subscribe();
+        store.insert("Key".to_owned(), "Value".into()).unwrap();
+        let inserted = match events.try_recv().unwrap() {
+            StoreEvent::Inserted {
+                key,
+                version,
+                source,
+            } => {
+                assert_eq!(key, "Key");
+                assert_eq!(source, EventSource::Local);
+                version
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        };
+        assert_eq!(inserted.origin, 1);
+
+        let updated = Version::new(inserted.timestamp + 1, 2);
+        let kv = KeyValue::with_version("Key".to_owned(), "Peer".into(), updated);
+        store.insert_from_peer(kv).unwrap();
+        assert_eq!(
+            events.try_recv().unwrap(),
+            StoreEvent::Updated {
+                key: "Key".to_owned(),
+                old: inserted,
+                new: updated,
+                source: EventSource::Peer,
+            }
+        );
+
+        store.remove("Key").unwrap();
+        assert_eq!(
+            events.try_recv().unwrap(),
+            StoreEvent::Removed {
+                key: "Key".to_owned(),
+                version: updated,
+                source: EventSource::Local,
+            }
+        );
+
+        let ttl = InsertOptions {
+            ttl: Some(Duration::from_secs(1)),
+            ..InsertOptions::default()
+        };
+        store
+            .insert_with("Token".to_owned(), "Value".into(), ttl)
+            .unwrap();
+        let _ = events.try_recv().unwrap();
+        store.expire(u64::MAX).unwrap();
+        let expired = events.try_recv().unwrap();
+        assert!(matches!(expired, StoreEvent::Expired { .. }));
+        assert_eq!(expired.key(), "Token");
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn store_conditional_writes() {
+        let mut store = KvStore::new();
+        let if_absent = || InsertOptions {
+            precondition: Some(Precondition::Absent),
+            ..InsertOptions::default()
+        };
+        store
+            .insert_with("Key".to_owned(), "Value".into(), if_absent())
+            .unwrap();
+        let err = store.insert_with("Key".to_owned(), "Other".into(), if_absent());
+        assert!(matches!(err, Err(KvsError::PreconditionFailed(_))));
+        assert_eq!(store.get("Key"), Some("Value".into()));
+
+        // Compare-and-swap only succeeds against the current version
+        let version = store.get_pair("Key").unwrap().version();
+        let swap = |version| InsertOptions {
+            precondition: Some(Precondition::Version(version)),
+            ..InsertOptions::default()
+        };
+        store
+            .insert_with("Key".to_owned(), "Swapped".into(), swap(version))
+            .unwrap();
+        let err = store.insert_with("Key".to_owned(), "Stale".into(), swap(version));
+        assert!(matches!(err, Err(KvsError::PreconditionFailed(_))));
+        assert_eq!(store.get("Key"), Some("Swapped".into()));
+
+        let err = store.remove_if("Key", version);
+        assert!(matches!(err, Err(KvsError::PreconditionFailed(_))));
+        let current = store.get_pair("Key").unwrap().version();
+        assert!(store.remove_if("Key", current).unwrap().is_some());
+        assert!(store.is_empty());
+
+        let if_present = InsertOptions {
+            precondition: Some(Precondition::Present),
+            ..InsertOptions::default()
+        };
+        let err = store.insert_with("Key".to_owned(), "Value".into(), if_present);
+        assert!(matches!(err, Err(KvsError::PreconditionFailed(_))));
+    }
+
+    #[test]
+    fn store_insert_batch() {
+        let mut store = KvStore::new();
+        store.insert("Key1".to_owned(), "Old".into()).unwrap();
+        let update = store
+            .insert_batch(
+                vec![
+                    ("Key1".to_owned(), "New".into()),
+                    ("Key2".to_owned(), "New".into()),
+                ],
+                InsertOptions::default(),
+            )
+            .unwrap();
+        let announced = update.announce.unwrap().iter().count();
+        let key1 = store.get_pair("Key1").unwrap();
+        let key2 = store.get_pair("Key2").unwrap();
+        assert_eq!(
+            announced,
+            key1.number_of_routes(None, None) + key2.number_of_routes(None, None)
+        );
+        assert!(update.withdraw.is_some());
+        assert_eq!(key1.version(), key2.version());
+        assert_eq!(key1.batch_size(), 2);
+        assert_eq!(key2.as_ref(), &Payload::from("New"));
+
+        // Nothing is written if any pair fails its precondition
+        let if_absent = InsertOptions {
+            precondition: Some(Precondition::Absent),
+            ..InsertOptions::default()
+        };
+        let err = store.insert_batch(
+            vec![
+                ("Key3".to_owned(), "New".into()),
+                ("Key1".to_owned(), "Newer".into()),
+            ],
+            if_absent,
+        );
+        assert!(matches!(err, Err(KvsError::PreconditionFailed(_))));
+        assert_eq!(store.len(), 2);
+
+        let err = store.insert_batch(
+            vec![
+                ("Key3".to_owned(), "New".into()),
+                ("Key3".to_owned(), "Newer".into()),
+            ],
+            InsertOptions::default(),
+        );
+        assert!(matches!(err, Err(KvsError::InvalidBatch(_))));
+        assert!(store.get("Key3").is_none());
+        let err = store.insert_batch(vec![], InsertOptions::default());
+        assert!(matches!(err, Err(KvsError::InvalidBatch(_))));
+    }
+
+    #[test]
+    fn store_hash_collision() {
+        let kv = KeyValue::new("Key".to_owned(), "Value".into());
+        let mut store = KvStore::new();
+        // Fake another key already owning this key's hash
+        store.hashes.insert(kv.key_hash(), "Other".to_owned());
+        assert!(matches!(
+            store.insert("Key".to_owned(), "Value".into()),
+            Err(KvsError::HashCollision(_))
+        ));
+        assert!(matches!(
+            store.insert_from_peer(kv),
+            Err(KvsError::HashCollision(_))
+        ));
+        assert!(store.is_empty());
+
+        // Hashes are released when keys are removed
+        let mut store = KvStore::new();
+        store.insert("Key".to_owned(), "Value".into()).unwrap();
+        assert_eq!(store.hashes.len(), 1);
+        store.remove("Key").unwrap();
+        assert!(store.hashes.is_empty());
     }
 
     #[test]
     fn store_remove() {
         let mut store = KvStore::new();
-        store.insert("Key".to_owned(), "Value".to_owned()).unwrap();
+        store.insert("Key".to_owned(), "Value".into()).unwrap();
 
         let update = store.remove("Key").unwrap();
         assert_eq!(store.get("Key"), None);
@@ -170,4 +1592,244 @@ mod tests {
         assert!(update.announce.is_none());
         assert!(update.withdraw.is_some());
     }
+
+    #[test]
+    fn store_scan() {
+        let mut store = KvStore::new();
+        for key in &[
+            "name",
+            "favorite::food",
+            "favorite::drink",
+            "favorite::protocol",
+        ] {
+            store.insert(key.to_string(), "Value".into()).unwrap();
+        }
+
+        let (keys, cursor) = store.scan("favorite::", None, 2);
+        assert_eq!(keys, vec!["favorite::drink", "favorite::food"]);
+        assert_eq!(cursor, Some("favorite::food".to_owned()));
+
+        // Pagination is stable while keys are inserted before the cursor
+        store
+            .insert("favorite::bgp".to_owned(), "Value".into())
+            .unwrap();
+        let (keys, cursor) = store.scan("favorite::", cursor.as_deref(), 2);
+        assert_eq!(keys, vec!["favorite::protocol"]);
+        assert_eq!(cursor, None);
+
+        let (keys, _) = store.scan("", None, 10);
+        assert_eq!(keys.len(), 5);
+        let (keys, _) = store.scan("missing", None, 10);
+        assert!(keys.is_empty());
+    }
+
+    #[test]
+    fn store_remove_from_peer() {
+        let mut store = KvStore::new();
+        let mut kv = KeyValue::new("Key".to_owned(), "Value".into());
+        kv.update("Updated".into());
+        store.insert_from_peer(kv).unwrap();
+
+        // Withdraw of an older version shouldn't remove the newer value
+        store
+            .remove_from_peer(KeyValue::new("Key".to_owned(), "Value".into()))
+            .unwrap();
+        assert_eq!(store.get("Key"), Some("Updated".into()));
+
+        // Nor a withdraw of a version that was never received
+        let mut kv = KeyValue::new("Key".to_owned(), "Value".into());
+        kv.update("Updated".into());
+        kv.update("Newer".into());
+        store.remove_from_peer(kv).unwrap();
+        assert_eq!(store.get("Key"), Some("Updated".into()));
+
+        let mut kv = KeyValue::new("Key".to_owned(), "Value".into());
+        kv.update("Updated".into());
+        store.remove_from_peer(kv).unwrap();
+        assert!(store.is_empty());
+
+        // Other nodes' writes are only withdrawn by the node that wrote them
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn store_remove_across_nodes() {
+        let mut writer = KvStore::new();
+        let mut other = KvStore::new();
+        let mut peer = KvStore::new();
+        let announce = writer
+            .insert("Key".to_owned(), "Value".into())
+            .unwrap()
+            .announce
+            .unwrap();
+        other
+            .insert_from_peer((&announce).try_into().unwrap())
+            .unwrap();
+        peer.insert_from_peer((&announce).try_into().unwrap())
+            .unwrap();
+
+        // Another node removes the pair, withdrawing the writer's version
+        let withdraw = other.remove("Key").unwrap().unwrap().withdraw.unwrap();
+        assert!(other.get("Key").is_none());
+
+        // So the writer removes it too, and withdraws the routes it announced
+        let update = writer
+            .remove_from_peer((&withdraw).try_into().unwrap())
+            .unwrap()
+            .unwrap();
+        assert!(writer.get("Key").is_none());
+        assert!(update.announce.is_none());
+        let withdraw = update.withdraw.unwrap();
+        let prefixes: Vec<_> = announce
+            .iter()
+            .map(|route| *route.prefix.as_ref())
+            .collect();
+        assert!(withdraw
+            .iter()
+            .all(|route| prefixes.contains(route.prefix.as_ref())));
+
+        // Which removes it from every other peer
+        assert!(peer
+            .remove_from_peer((&withdraw).try_into().unwrap())
+            .unwrap()
+            .is_none());
+        assert!(peer.get("Key").is_none());
+    }
+
+    #[test]
+    fn store_remove_from_peer_signed() {
+        let writer = NodeKey::from_hex(&"42".repeat(32)).unwrap();
+        let other = NodeKey::from_hex(&"07".repeat(32)).unwrap();
+        let mut store = KvStore::new();
+        let mut peer = KvStore::new();
+        peer.set_node_key(Some(writer));
+        let update = peer.insert("Key".to_owned(), "Value".into()).unwrap();
+        let announce = update.announce.unwrap();
+        store
+            .insert_from_peer((&announce).try_into().unwrap())
+            .unwrap();
+
+        // The same version signed by another key can't withdraw it
+        let pair = peer.get_pair("Key").unwrap();
+        let forged =
+            KeyValue::with_version("Key".to_owned(), Payload::from("Value"), pair.version());
+        let forged =
+            RouteCollection::encode(&forged, AddrPrefix::default(), None, Some(&other)).unwrap();
+        assert!(matches!(
+            store.remove_from_peer((&forged).try_into().unwrap()),
+            Err(KvsError::Untrusted(_))
+        ));
+        // Nor can an unsigned withdraw
+        let unsigned = KeyValue::with_version("Key".to_owned(), "Value".into(), pair.version());
+        assert!(matches!(
+            store.remove_from_peer(unsigned),
+            Err(KvsError::Untrusted(_))
+        ));
+        assert!(store.get("Key").is_some());
+
+        store
+            .remove_from_peer((&announce).try_into().unwrap())
+            .unwrap();
+        assert!(store.get("Key").is_none());
+    }
+
+    #[test]
+    fn store_persist_and_restore() {
+        let dir = std::env::temp_dir().join(format!("kvs-bgp-store-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let origin;
+        {
+            let mut store = KvStore::open(&dir, 2).unwrap();
+            origin = store.origin();
+            store.insert("Key".to_owned(), "Value".into()).unwrap();
+            store.insert("Key".to_owned(), "Updated".into()).unwrap();
+            store.insert("Other".to_owned(), "Value".into()).unwrap();
+            store.remove("Other").unwrap();
+            store
+                .insert_from_peer(KeyValue::new("Peer".to_owned(), "Value".into()))
+                .unwrap();
+            store
+                .insert_from_peer(KeyValue::new("Gone".to_owned(), "Value".into()))
+                .unwrap();
+            let session = InsertOptions {
+                ephemeral: true,
+                ..InsertOptions::default()
+            };
+            store
+                .insert_with("Session".to_owned(), "Value".into(), session)
+                .unwrap();
+            store
+                .insert_batch(
+                    vec![
+                        ("Batch1".to_owned(), "Value".into()),
+                        ("Batch2".to_owned(), "Value".into()),
+                    ],
+                    InsertOptions::default(),
+                )
+                .unwrap();
+        }
+        let mut store = KvStore::open(&dir, 2).unwrap();
+        assert_eq!(store.len(), 5);
+        assert_eq!(store.get("Batch1"), Some("Value".into()));
+        assert_eq!(store.get("Batch2"), Some("Value".into()));
+        assert_eq!(store.get("Session"), None);
+        assert_eq!(store.get("Key"), Some("Updated".into()));
+        assert_eq!(store.get("Peer"), Some("Value".into()));
+        assert_eq!(store.origin(), origin);
+
+        // Only the pairs written by this node are re-announced
+        let announced = store.announce_own().unwrap().unwrap().announce.unwrap();
+        let expected: usize = ["Key", "Batch1", "Batch2"]
+            .iter()
+            .map(|key| {
+                store
+                    .encode(store.get_pair(key).unwrap())
+                    .unwrap()
+                    .iter()
+                    .count()
+            })
+            .sum();
+        assert_eq!(announced.iter().count(), expected);
+
+        // Pairs of other nodes are dropped unless peers announce them again (E.g. removed while down)
+        store
+            .insert_from_peer(KeyValue::new("Peer".to_owned(), "Value".into()))
+            .unwrap();
+        assert_eq!(store.remove_provisional().unwrap(), vec!["Gone".to_owned()]);
+        assert_eq!(store.get("Peer"), Some("Value".into()));
+        assert_eq!(store.get("Gone"), None);
+        assert_eq!(store.len(), 4);
+        assert!(store.remove_provisional().unwrap().is_empty());
+
+        // Restored versions continue from where they left off
+        let restored = store.get_pair("Key").unwrap().version();
+        store.insert("Key".to_owned(), "42".into()).unwrap();
+        assert!(store.get_pair("Key").unwrap().version() > restored);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn store_expire_after_restore() {
+        let dir = std::env::temp_dir().join(format!("kvs-bgp-expire-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let expires;
+        {
+            let mut store = KvStore::open(&dir, 2).unwrap();
+            let ttl = InsertOptions {
+                ttl: Some(Duration::from_secs(60)),
+                ..InsertOptions::default()
+            };
+            store
+                .insert_with("Key".to_owned(), "Value".into(), ttl)
+                .unwrap();
+            expires = store.get_pair("Key").unwrap().expires().unwrap();
+        }
+        // Pairs written before a restart are still withdrawn when they expire
+        let mut store = KvStore::open(&dir, 2).unwrap();
+        let updates = store.expire(expires).unwrap();
+        assert_eq!(updates.len(), 1);
+        assert!(updates[0].withdraw.is_some());
+        assert!(store.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }